@@ -0,0 +1,187 @@
+use crate::fence::Fence;
+use crate::format::BlockExtractor;
+
+/// Finds the source blocks of AsciiDoc documents
+pub struct AsciidocExtractor;
+
+impl BlockExtractor for AsciidocExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_source_blocks(text)
+    }
+}
+
+/// Language and other attributes of a `[source,rust,linenums]` line, as an info string,
+/// and whether the `source` style is spelled out
+///
+/// The style may carry options (`[source%linenums,rust]`), or be left out before a
+/// language (`[,rust]`); other attribute lists (`[%header,cols=2]`) are not source.
+fn source_attributes(line: &str) -> Option<(String, bool)> {
+    let list = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (style, rest) = list.split_once(',').unwrap_or((list, ""));
+    let rest = rest.trim();
+    if style.trim().is_empty() {
+        let lang = rest.split(',').next().unwrap_or_default().trim();
+        return (!lang.is_empty() && !lang.contains(['=', '%', '#', '.'])).then(|| (rest.to_string(), false));
+    }
+    (style.split('%').next().unwrap_or_default().trim() == "source").then(|| (rest.to_string(), true))
+}
+
+/// Fence character and length of a listing (`----`) or literal (`....`) delimiter line
+fn verbatim_delimiter(line: &str) -> Option<(char, usize)> {
+    ['-', '.'].into_iter().find_map(|ch| delimiter(line, ch, 4).map(|len| (ch, len)))
+}
+
+/// Length of a delimiter line made only of `min` or more `ch`
+fn delimiter(line: &str, ch: char, min: usize) -> Option<usize> {
+    let line = line.trim_end();
+    let len = line.chars().take_while(|&c| c == ch).count();
+    (len >= min && len == line.len()).then_some(len)
+}
+
+/// A verbatim block of the lines `body`
+fn block(fence_char: char, fence_len: usize, info: String, start_line: usize, end_line: usize, body: &[&str]) -> Fence {
+    Fence {
+        fence_char,
+        fence_len,
+        indent: 0,
+        info,
+        start_line,
+        end_line,
+        lines: body.iter().map(|line| line.trim_end_matches('\r').to_string()).collect(),
+        prefixes: vec![0; body.len()],
+        sources: Vec::new(),
+        scope_session: None,
+        options: Vec::new(),
+    }
+}
+
+/// Find the source blocks of an AsciiDoc document
+///
+/// A `[source,lang]` line applies to the `----` listing or `....` literal block after it
+/// (block titles may come in between), or to the paragraph after it when there is no
+/// delimiter. Fenced ```` ```lang ```` blocks work as in Asciidoctor. Blocks are verbatim:
+/// lines keep their indentation. Anything in a `////` comment block is skipped.
+pub fn scan_source_blocks(text: &str) -> Vec<Fence> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut line = 0;
+    while line < lines.len() {
+        let current = lines[line];
+
+        if let Some(len) = delimiter(current, '/', 4) {
+            line = (line + 1..lines.len())
+                .find(|&end| delimiter(lines[end], '/', 4) == Some(len))
+                .map_or(lines.len(), |end| end + 1);
+            continue;
+        }
+
+        if let Some(rest) = current.strip_prefix("```") {
+            let end = (line + 1..lines.len())
+                .find(|&end| lines[end].trim_end() == "```")
+                .unwrap_or(lines.len());
+            blocks.push(block('`', 3, rest.trim().to_string(), line, end, &lines[line + 1..end]));
+            line = end + 1;
+            continue;
+        }
+
+        let (info, explicit) = match source_attributes(current) {
+            Some(attributes) => attributes,
+            None => {
+                line += 1;
+                continue;
+            }
+        };
+        let mut open = line + 1;
+        while open < lines.len() && lines[open].starts_with('.') && !lines[open].starts_with("..") {
+            open += 1;
+        }
+        match lines.get(open).and_then(|opening| verbatim_delimiter(opening)) {
+            Some((ch, len)) => {
+                let end = (open + 1..lines.len())
+                    .find(|&end| delimiter(lines[end], ch, 4) == Some(len))
+                    .unwrap_or(lines.len());
+                blocks.push(block(ch, len, info, open, end, &lines[open + 1..end]));
+                line = end + 1;
+            }
+            // `[,rust]` is only a source block on a delimited block
+            None if !explicit => line += 1,
+            None => {
+                // A paragraph has no delimiters: it ends at a blank line
+                let end = (open..lines.len())
+                    .find(|&end| lines[end].trim().is_empty())
+                    .unwrap_or(lines.len());
+                blocks.push(block('[', 1, info, open - 1, end, &lines[open..end]));
+                line = end;
+            }
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source_blocks() {
+        let text = "\
+= Guide
+
+.Entry point
+[source,rust,linenums]
+----
+fn main() {
+    run();
+}
+----
+
+[source%nowrap,python]
+print(1)
+
+[,sh]
+------
+ls ----
+------
+
+```go
+fmt.Println(1)
+```
+";
+        let blocks = scan_source_blocks(text);
+        assert_eq!(blocks.len(), 4);
+
+        assert_eq!(blocks[0].lang(), "rust");
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (4, 8));
+        assert_eq!(blocks[0].lines, vec!["fn main() {", "    run();", "}"]);
+        assert_eq!(blocks[0].content_start(), 5);
+
+        assert_eq!(blocks[1].lang(), "python");
+        assert_eq!(blocks[1].lines, vec!["print(1)"]);
+        assert_eq!(blocks[1].content_start(), 11);
+
+        assert_eq!(blocks[2].lang(), "sh");
+        assert_eq!(blocks[2].lines, vec!["ls ----"]);
+
+        assert_eq!(blocks[3].lang(), "go");
+        assert_eq!(blocks[3].content_line(0), 19);
+    }
+
+    #[test]
+    fn test_other_blocks_are_not_source() {
+        let text = "[quote]\n----\nnot code\n----\n\n////\n[source,rust]\n----\nfn a() {}\n----\n////\n\n----\nplain listing\n----\n";
+        assert!(scan_source_blocks(text).is_empty());
+
+        let text = "[%header,cols=2]\n|===\n|a |b\n|===\n\n[%autowidth]\n----\nx\n----\n\n[,rust]\nnot a listing\n";
+        assert!(scan_source_blocks(text).is_empty());
+    }
+
+    #[test]
+    fn test_literal_blocks() {
+        let text = "[source,python]\n....\nprint(1)\n....\n";
+        let blocks = scan_source_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].lang(), "python");
+        assert_eq!(blocks[0].lines, vec!["print(1)"]);
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (1, 3));
+    }
+}
@@ -0,0 +1,257 @@
+/// Attributes of a code block, parsed from the info string after the language
+///
+/// Supports braced attributes (```` ```rust {ignore file=src/lib.rs} ````), pandoc-style
+/// classes (```` ```{.rust .ignore} ````), rustdoc-style flags (```` ```rust,ignore ````)
+/// and Quarto / R Markdown chunk headers (```` ```{r echo=FALSE} ````). Org header
+/// arguments (`#+begin_src rust :tangle src/lib.rs`) are read by the Org extractor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockAttributes {
+    /// Intentionally broken example: kept out of the virtual document and its diagnostics
+    pub ignore: bool,
+    /// Hidden from the rendered document, but still part of the code
+    pub hidden: bool,
+    /// Not sent to any language server
+    pub no_lsp: bool,
+    /// Virtual document file the block belongs to
+    pub file: Option<String>,
+    /// Named session the block belongs to
+    pub session: Option<String>,
+}
+
+impl BlockAttributes {
+    /// Whether the block is left out of virtual documents
+    pub fn excluded(&self) -> bool {
+        self.ignore || self.no_lsp
+    }
+
+    /// Apply one attribute token: `.class`, `#id`, `key=value` or a bare flag
+    fn apply(&mut self, token: &str) {
+        let token = token.strip_prefix('.').unwrap_or(token);
+        if token.starts_with('#') {
+            return;
+        }
+
+        match token.split_once('=') {
+            Some((key, value)) => self.apply_option(key, value.trim_matches(|c| c == '"' || c == '\'')),
+            None => match token {
+                "ignore" => self.ignore = true,
+                "hidden" => self.hidden = true,
+                "no-lsp" | "no_lsp" => self.no_lsp = true,
+                _ => {}
+            },
+        }
+    }
+
+    /// Apply a `key=value` attribute, or a chunk option such as `#| eval: false`
+    ///
+    /// Org header arguments come in as options too, keyed with their colon.
+    pub(crate) fn apply_option(&mut self, key: &str, value: &str) {
+        if let Some(key) = key.strip_prefix(':') {
+            self.apply_header(key, (!value.is_empty()).then_some(value));
+            return;
+        }
+        match key {
+            "file" => self.file = Some(value.to_string()),
+            "session" => self.session = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    /// Apply an Org header argument such as `:tangle src/lib.rs`
+    fn apply_header(&mut self, key: &str, value: Option<&str>) {
+        let value = value.map(|value| value.trim_matches(|c| c == '"' || c == '\''));
+        match (key, value) {
+            // `:tangle yes` writes to a file named after the document, like the default session
+            ("tangle", Some(file)) if file != "yes" && file != "no" => self.file = Some(file.to_string()),
+            ("session", Some(session)) if session != "none" => self.session = Some(session.to_string()),
+            ("eval", Some("no" | "never")) => self.no_lsp = true,
+            _ => {}
+        }
+    }
+}
+
+/// Split attribute text on whitespace and commas, keeping quoted values together
+fn tokens(text: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quote = None;
+
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => {
+                quote = Some(c);
+                start.get_or_insert(i);
+            }
+            None if c.is_whitespace() || c == ',' || c == '{' || c == '}' => {
+                if let Some(s) = start.take() {
+                    tokens.push(&text[s..i]);
+                }
+            }
+            None => {
+                start.get_or_insert(i);
+            }
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&text[s..]);
+    }
+    tokens
+}
+
+/// Engines of knitr and Quarto, which name the language of an executable chunk
+const CHUNK_ENGINES: &[&str] = &[
+    "asy", "awk", "bash", "c", "cc", "coffee", "cpp", "css", "dot", "fortran", "fortran95", "go", "groovy",
+    "haskell", "js", "julia", "mermaid", "node", "octave", "ojs", "perl", "php", "powershell", "python", "r",
+    "rcpp", "rscript", "ruby", "sas", "sass", "scala", "scss", "sed", "sh", "sql", "stan", "stata", "tikz", "zsh",
+];
+
+/// Whether a token of a braced info string names the language of an executable chunk
+fn is_engine(token: &str) -> bool {
+    CHUNK_ENGINES.iter().any(|engine| engine.eq_ignore_ascii_case(token))
+}
+
+/// Whether an info string is a Quarto / R Markdown executable chunk header: `{python}`,
+/// `{r label, echo=FALSE}`
+pub fn is_executable_chunk(info: &str) -> bool {
+    let info = info.trim();
+    info.starts_with('{') && tokens(info).first().is_some_and(|token| is_engine(token))
+}
+
+/// The `key=value` options of an executable chunk header: `{r setup, eval=FALSE}`
+pub(crate) fn chunk_header_options(info: &str) -> Vec<(String, String)> {
+    tokens(info)
+        .into_iter()
+        .skip(1)
+        .filter_map(|token| {
+            let (key, value) = token.split_once('=')?;
+            Some((key.to_string(), value.trim_matches(|c| c == '"' || c == '\'').to_string()))
+        })
+        .collect()
+}
+
+/// Parse a fence info string into the block language and its attributes
+pub fn parse_info(info: &str) -> (&str, BlockAttributes) {
+    let info = info.trim();
+    let mut attributes = BlockAttributes::default();
+
+    // Pandoc style: the first class is the language. Executable chunks name it first, bare.
+    if info.starts_with('{') {
+        let mut lang = "";
+        for (index, token) in tokens(info).into_iter().enumerate() {
+            match token.strip_prefix('.') {
+                Some(class) if lang.is_empty() => lang = class,
+                None if index == 0 && is_engine(token) => lang = token,
+                _ => attributes.apply(token),
+            }
+        }
+        return (lang, attributes);
+    }
+
+    let lang_end = info
+        .find(|c: char| c.is_whitespace() || c == ',' || c == '{')
+        .unwrap_or(info.len());
+    for token in tokens(&info[lang_end..]) {
+        attributes.apply(token);
+    }
+    (&info[..lang_end], attributes)
+}
+
+/// Parse the Org header arguments after the language of a `#+begin_src` line, as options
+/// keyed with their colon: `:tangle src/lib.rs` is `(":tangle", "src/lib.rs")`
+pub(crate) fn header_arguments(info: &str) -> Vec<(String, String)> {
+    let mut arguments = Vec::new();
+    let mut tokens = tokens(info).into_iter().skip(1).peekable();
+    while let Some(token) = tokens.next() {
+        if token.starts_with(':') {
+            let value = tokens.next_if(|value| !value.starts_with(':')).unwrap_or("");
+            arguments.push((token.to_string(), value.to_string()));
+        }
+    }
+    arguments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_braced_attributes() {
+        let (lang, attrs) = parse_info("rust {ignore}");
+        assert_eq!(lang, "rust");
+        assert!(attrs.ignore && attrs.excluded());
+
+        let (lang, attrs) = parse_info("rust {file=src/lib.rs session=\"the server\"}");
+        assert_eq!(lang, "rust");
+        assert_eq!(attrs.file.as_deref(), Some("src/lib.rs"));
+        assert_eq!(attrs.session.as_deref(), Some("the server"));
+        assert!(!attrs.excluded());
+
+        let (_, attrs) = parse_info("python {hidden, no-lsp}");
+        assert!(attrs.hidden && attrs.no_lsp);
+    }
+
+    #[test]
+    fn test_pandoc_and_rustdoc_styles() {
+        let (lang, attrs) = parse_info("{.rust .ignore #example session=wrong}");
+        assert_eq!(lang, "rust");
+        assert!(attrs.ignore);
+        assert_eq!(attrs.session.as_deref(), Some("wrong"));
+
+        let (lang, attrs) = parse_info("rust,ignore");
+        assert_eq!(lang, "rust");
+        assert!(attrs.ignore);
+    }
+
+    #[test]
+    fn test_org_header_arguments() {
+        let header = |info: &str| {
+            let mut attrs = BlockAttributes::default();
+            for (key, value) in header_arguments(info) {
+                attrs.apply_option(&key, &value);
+            }
+            attrs
+        };
+        let attrs = header("rust :tangle src/main.rs :session \"the server\" :results silent :noweb");
+        assert_eq!(attrs.file.as_deref(), Some("src/main.rs"));
+        assert_eq!(attrs.session.as_deref(), Some("the server"));
+
+        let attrs = header("python :tangle no :session none :eval never");
+        assert_eq!((attrs.file.as_deref(), attrs.session.as_deref()), (None, None));
+        assert!(attrs.excluded());
+
+        // Only Org blocks have header arguments
+        assert_eq!(parse_info("rust :tangle src/main.rs"), ("rust", BlockAttributes::default()));
+    }
+
+    #[test]
+    fn test_executable_chunk_headers() {
+        assert_eq!(parse_info("{python}").0, "python");
+        assert!(is_executable_chunk("{python}"));
+
+        let (lang, attrs) = parse_info("{r setup, echo=FALSE, eval=FALSE}");
+        assert_eq!(lang, "r");
+        // Chunks that are not run are still edited, so they keep their language server
+        assert!(!attrs.excluded());
+        assert_eq!(
+            chunk_header_options("{r setup, echo=FALSE, eval=FALSE}"),
+            vec![("echo".to_string(), "FALSE".to_string()), ("eval".to_string(), "FALSE".to_string())]
+        );
+
+        // Only known engines are languages
+        assert!(is_executable_chunk("{R}"));
+        let (lang, attrs) = parse_info("{ignore}");
+        assert_eq!(lang, "");
+        assert!(attrs.ignore);
+        assert!(!is_executable_chunk("{ignore}"));
+        assert!(!is_executable_chunk("{.rust .ignore}"));
+        assert!(!is_executable_chunk("python"));
+    }
+
+    #[test]
+    fn test_plain_info_string() {
+        assert_eq!(parse_info("forth"), ("forth", BlockAttributes::default()));
+        assert_eq!(parse_info(""), ("", BlockAttributes::default()));
+    }
+}
@@ -0,0 +1,556 @@
+use crate::child_lsp::ChildLspManager;
+use crate::child_lsp_init::{ChildLspInitParams, ChildLspInitializer};
+use crate::chunks::chunk_diagnostics;
+use crate::config::Config;
+use crate::diagnostics::map_diagnostics;
+use crate::disk_vdoc::{document_filename, session_filename, DiskVirtualDoc};
+use crate::document_store::Document;
+use crate::features::Feature;
+use crate::format::get_document_language;
+use crate::position::{PositionEncoding, PositionMapper};
+use crate::tangle::{collect_sources, display_path};
+use crate::utils::constants;
+use crate::virtual_doc::{collect_languages, collect_sessions, VirtualDocument};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, NumberOrString, PublishDiagnosticsParams, Url};
+
+/// How `literate-lsp check` prints its findings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// `file:line:col: severity: message`, one per line
+    Text,
+    Json,
+    /// SARIF 2.1.0, for code scanning dashboards
+    Sarif,
+}
+
+/// Command line options of `literate-lsp check`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckOptions {
+    pub inputs: Vec<String>,
+    pub format: OutputFormat,
+    /// Limit on waiting for the language servers' diagnostics
+    pub timeout: Duration,
+    /// Count a document its server published nothing for as clean instead of unchecked
+    pub assume_clean: bool,
+}
+
+impl CheckOptions {
+    /// Parse `[--format text|json|sarif] [--timeout SECS] [--assume-clean] [paths or globs...]`
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = CheckOptions {
+            inputs: Vec::new(),
+            format: OutputFormat::Text,
+            timeout: Duration::from_secs(constants::CHECK_TIMEOUT_SECS),
+            assume_clean: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--format" => {
+                    options.format = match args.next().map(String::as_str) {
+                        Some("text") => OutputFormat::Text,
+                        Some("json") => OutputFormat::Json,
+                        Some("sarif") => OutputFormat::Sarif,
+                        other => return Err(format!("Unknown --format {:?}, expected text, json or sarif", other.unwrap_or(""))),
+                    };
+                }
+                "--timeout" => {
+                    let secs = args
+                        .next()
+                        .and_then(|secs| secs.parse::<u64>().ok())
+                        .ok_or("--timeout expects a number of seconds")?;
+                    options.timeout = Duration::from_secs(secs);
+                }
+                "--assume-clean" => options.assume_clean = true,
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+                input => options.inputs.push(input.to_string()),
+            }
+        }
+        Ok(options)
+    }
+}
+
+/// A diagnostic on a literate document, with columns in Unicode code points
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub diagnostic: Diagnostic,
+}
+
+impl Finding {
+    /// Severity name; diagnostics without one are treated as errors
+    pub fn severity(&self) -> &'static str {
+        match self.diagnostic.severity {
+            Some(DiagnosticSeverity::WARNING) => "warning",
+            Some(DiagnosticSeverity::INFORMATION) => "info",
+            Some(DiagnosticSeverity::HINT) => "hint",
+            _ => "error",
+        }
+    }
+
+    fn code(&self) -> Option<String> {
+        match self.diagnostic.code.as_ref()? {
+            NumberOrString::Number(code) => Some(code.to_string()),
+            NumberOrString::String(code) => Some(code.clone()),
+        }
+    }
+}
+
+/// Outcome of a check run
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub findings: Vec<Finding>,
+    /// Documents that could not be checked: unreadable inputs or servers that failed to start
+    pub errors: Vec<String>,
+    /// Documents their server published no diagnostics for before the timeout
+    pub unchecked: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn count(&self, severity: &str) -> usize {
+        self.findings.iter().filter(|finding| finding.severity() == severity).count()
+    }
+
+    /// 1 when a document has errors, 2 when something could not be checked at all
+    pub fn exit_code(&self) -> i32 {
+        if !self.errors.is_empty() || !self.unchecked.is_empty() {
+            2
+        } else if self.count("error") > 0 {
+            1
+        } else {
+            0
+        }
+    }
+
+    /// One-line summary for stderr
+    pub fn summary(&self) -> String {
+        format!(
+            "{} error(s), {} warning(s), {} document(s) could not be checked, {} unchecked",
+            self.count("error"),
+            self.count("warning"),
+            self.errors.len(),
+            self.unchecked.len()
+        )
+    }
+
+    /// Render the findings in the requested format
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Text => self
+                .findings
+                .iter()
+                .map(|finding| {
+                    let start = finding.diagnostic.range.start;
+                    format!(
+                        "{}:{}:{}: {}: {}\n",
+                        display_path(&finding.path),
+                        start.line + 1,
+                        start.character + 1,
+                        finding.severity(),
+                        finding.diagnostic.message
+                    )
+                })
+                .collect(),
+            OutputFormat::Json => {
+                let findings: Vec<Value> = self.findings.iter().map(Self::json_finding).collect();
+                serde_json::to_string_pretty(&findings).unwrap_or_default()
+            }
+            OutputFormat::Sarif => serde_json::to_string_pretty(&self.sarif()).unwrap_or_default(),
+        }
+    }
+
+    fn json_finding(finding: &Finding) -> Value {
+        let range = finding.diagnostic.range;
+        json!({
+            "file": display_path(&finding.path),
+            "line": range.start.line + 1,
+            "column": range.start.character + 1,
+            "endLine": range.end.line + 1,
+            "endColumn": range.end.character + 1,
+            "severity": finding.severity(),
+            "message": finding.diagnostic.message,
+            "source": finding.diagnostic.source,
+            "code": finding.code(),
+        })
+    }
+
+    fn sarif(&self) -> Value {
+        let results: Vec<Value> = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let range = finding.diagnostic.range;
+                let level = match finding.severity() {
+                    "error" => "error",
+                    "warning" => "warning",
+                    _ => "note",
+                };
+                let mut result = json!({
+                    "level": level,
+                    "message": { "text": finding.diagnostic.message },
+                    "locations": [{
+                        "physicalLocation": {
+                            "artifactLocation": { "uri": display_path(&finding.path) },
+                            "region": {
+                                "startLine": range.start.line + 1,
+                                "startColumn": range.start.character + 1,
+                                "endLine": range.end.line + 1,
+                                "endColumn": range.end.character + 1
+                            }
+                        }
+                    }]
+                });
+                if let Some(rule) = finding.code().or_else(|| finding.diagnostic.source.clone()) {
+                    result["ruleId"] = json!(rule);
+                }
+                result
+            })
+            .collect();
+
+        json!({
+            "version": "2.1.0",
+            "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+            "runs": [{
+                "tool": { "driver": { "name": "literate-lsp", "version": env!("CARGO_PKG_VERSION") } },
+                "columnKind": "unicodeCodePoints",
+                "results": results
+            }]
+        })
+    }
+}
+
+/// A virtual document opened in a child LSP, waiting for its diagnostics
+struct OpenedDoc {
+    path: PathBuf,
+    markdown_uri: Url,
+    virtual_uri: Url,
+    /// Language server the document is open in
+    server: String,
+    vdoc: VirtualDocument,
+    child_encoding: PositionEncoding,
+}
+
+/// Check the literate documents named by `options.inputs` with their language servers
+///
+/// Every virtual document is written and opened in the child LSPs of its language that
+/// serve diagnostics, one per project, language and server as in the editor. Diagnostics
+/// are collected until every document has received some and then none arrived for
+/// `CHECK_SETTLE_MS`, or until the timeout; a document that got none is unchecked. With
+/// `assume_clean`, collection stops at the first quiet period and silence means clean.
+pub async fn check(config: &Config, options: &CheckOptions) -> CheckReport {
+    let mut report = CheckReport::default();
+    let sources = collect_sources(&options.inputs, &mut report.errors);
+
+    let (tx, mut rx) = mpsc::unbounded_channel::<(String, PublishDiagnosticsParams)>();
+    let mut children: HashMap<(PathBuf, String, String), Arc<ChildLspManager>> = HashMap::new();
+    let mut failed: HashSet<(PathBuf, String, String)> = HashSet::new();
+    let mut opened: Vec<OpenedDoc> = Vec::new();
+    let aliases = Arc::new(config.language_aliases());
+
+    for path in sources {
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                report.errors.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let markdown_uri = match Url::from_file_path(&path) {
+            Ok(uri) => uri,
+            Err(_) => continue,
+        };
+        let doc = Document::new(&markdown_uri, text, 0, &aliases);
+        let project_root = doc.project_root.clone().unwrap_or_else(|| PathBuf::from("."));
+        let filename = document_filename(&project_root, &path);
+        let doc_lang = get_document_language(&markdown_uri);
+        let fences = doc.fences();
+
+        report.findings.extend(
+            chunk_diagnostics(&fences, &doc.aliases, PositionEncoding::Utf32)
+                .into_iter()
+                .map(|diagnostic| Finding { path: path.clone(), diagnostic }),
+        );
+
+        for lang in collect_languages(&fences, &doc.aliases) {
+            if config.skips_language(doc_lang.as_deref(), &lang) {
+                continue;
+            }
+            let servers: Vec<_> = config
+                .get_language_servers(&lang)
+                .into_iter()
+                .filter(|server| server.serves(Feature::Diagnostics))
+                .collect();
+            if servers.is_empty() {
+                continue;
+            }
+            let extension = config.get_extension_for_language(&lang).unwrap_or_else(|| lang.clone());
+
+            for session in collect_sessions(&fences, &doc.aliases, &lang) {
+                let vdoc = doc.virtual_document(&fences, &lang, session.as_deref());
+                if vdoc.blocks.is_empty() {
+                    continue;
+                }
+                let markdown_filename = session_filename(&filename, session.as_deref());
+                let disk_doc = match DiskVirtualDoc::write_to_disk(
+                    &project_root,
+                    &doc.output_dir,
+                    &markdown_filename,
+                    &lang,
+                    &extension,
+                    vdoc.content.clone(),
+                ) {
+                    Ok(disk_doc) => disk_doc,
+                    Err(e) => {
+                        report.errors.push(format!("{}: failed to write virtual document: {}", path.display(), e));
+                        continue;
+                    }
+                };
+                let file_uri = disk_doc.to_uri();
+                let virtual_uri = match Url::parse(&file_uri) {
+                    Ok(uri) => uri,
+                    Err(_) => continue,
+                };
+
+                for server in &servers {
+                    let child_key = (project_root.clone(), lang.clone(), server.name.clone());
+                    if failed.contains(&child_key) {
+                        continue;
+                    }
+                    let child = match children.get(&child_key) {
+                        Some(child) => {
+                            if let Err(e) = child.did_open(file_uri.clone(), lang.clone(), vdoc.content.clone()).await {
+                                report.errors.push(format!(
+                                    "{}: failed to open '{}' document in '{}': {}",
+                                    path.display(),
+                                    lang,
+                                    server.name,
+                                    e
+                                ));
+                                continue;
+                            }
+                            Arc::clone(child)
+                        }
+                        None => {
+                            let root = disk_doc.file_path.parent().map(|dir| dir.to_path_buf()).unwrap_or_default();
+                            let params = ChildLspInitParams {
+                                lang: lang.clone(),
+                                binary_name: server.command.clone(),
+                                args: server.args.clone(),
+                                root_uri: format!("file://{}", root.display()),
+                                file_uri: file_uri.clone(),
+                                file_content: vdoc.content.clone(),
+                                init_options: config.get_init_options(&server.name),
+                            };
+                            let child = match ChildLspInitializer::initialize_child_lsp(params).await {
+                                Ok(result) => Arc::new(result.lsp),
+                                Err(e) => {
+                                    report.errors.push(format!("{}: {}", path.display(), e));
+                                    failed.insert(child_key.clone());
+                                    continue;
+                                }
+                            };
+                            if let Some(mut diagnostics) = child.take_diagnostics().await {
+                                let (tx, server) = (tx.clone(), server.name.clone());
+                                tokio::spawn(async move {
+                                    while let Some(params) = diagnostics.recv().await {
+                                        if tx.send((server.clone(), params)).is_err() {
+                                            break;
+                                        }
+                                    }
+                                });
+                            }
+                            children.insert(child_key.clone(), Arc::clone(&child));
+                            child
+                        }
+                    };
+
+                    opened.push(OpenedDoc {
+                        path: path.clone(),
+                        markdown_uri: markdown_uri.clone(),
+                        virtual_uri: virtual_uri.clone(),
+                        server: server.name.clone(),
+                        vdoc: vdoc.clone(),
+                        child_encoding: child.position_encoding(),
+                    });
+                }
+            }
+        }
+    }
+    drop(tx);
+
+    let expected: Vec<_> = opened.iter().map(|doc| (doc.virtual_uri.clone(), doc.server.clone())).collect();
+    let mut latest = collect_diagnostics(&mut rx, &expected, options.timeout, options.assume_clean).await;
+
+    for doc in &opened {
+        let diagnostics = match latest.remove(&(doc.virtual_uri.clone(), doc.server.clone())) {
+            Some(diagnostics) => diagnostics,
+            // Pull-only servers and servers with nothing to say for a clean document never publish
+            None if options.assume_clean => continue,
+            None => {
+                report.unchecked.push(format!(
+                    "{}: no diagnostics from the '{}' language server within {}s",
+                    doc.path.display(),
+                    doc.server,
+                    options.timeout.as_secs()
+                ));
+                continue;
+            }
+        };
+        let mapper = PositionMapper::new(&doc.vdoc.blocks).with_encodings(PositionEncoding::Utf32, doc.child_encoding);
+        report.findings.extend(
+            map_diagnostics(diagnostics, &mapper, &doc.virtual_uri, &doc.markdown_uri)
+                .into_iter()
+                .map(|diagnostic| Finding { path: doc.path.clone(), diagnostic }),
+        );
+    }
+
+    let mut shutdowns = tokio::task::JoinSet::new();
+    for (_, child) in children {
+        shutdowns.spawn(async move { child.shutdown().await });
+    }
+    while shutdowns.join_next().await.is_some() {}
+
+    report.findings.sort_by(|a, b| {
+        let (a_start, b_start) = (a.diagnostic.range.start, b.diagnostic.range.start);
+        (&a.path, a_start.line, a_start.character).cmp(&(&b.path, b_start.line, b_start.character))
+    });
+    report
+}
+
+/// Latest diagnostics published for each (virtual document, server)
+///
+/// Waits up to `timeout` for every `expected` document to get its first diagnostics, then
+/// for the stream to go quiet for `CHECK_SETTLE_MS`. With `assume_clean`, the first quiet
+/// period ends the wait even if some documents never reported.
+async fn collect_diagnostics(
+    rx: &mut mpsc::UnboundedReceiver<(String, PublishDiagnosticsParams)>,
+    expected: &[(Url, String)],
+    timeout: Duration,
+    assume_clean: bool,
+) -> HashMap<(Url, String), Vec<Diagnostic>> {
+    let mut latest: HashMap<(Url, String), Vec<Diagnostic>> = HashMap::new();
+    let deadline = Instant::now() + timeout;
+    while !expected.is_empty() {
+        let all_reported = expected.iter().all(|key| latest.contains_key(key));
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let wait = if all_reported || assume_clean {
+            remaining.min(Duration::from_millis(constants::CHECK_SETTLE_MS))
+        } else {
+            remaining
+        };
+        match tokio::time::timeout(wait, rx.recv()).await {
+            Ok(Some((server, params))) => {
+                latest.insert((params.uri, server), params.diagnostics);
+            }
+            Ok(None) | Err(_) => break,
+        }
+    }
+    latest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::{Position, Range};
+
+    fn finding(line: u32, severity: DiagnosticSeverity, message: &str) -> Finding {
+        Finding {
+            path: PathBuf::from("/docs/book.md"),
+            diagnostic: Diagnostic {
+                range: Range {
+                    start: Position { line, character: 4 },
+                    end: Position { line, character: 9 },
+                },
+                severity: Some(severity),
+                code: Some(NumberOrString::String("E0425".to_string())),
+                source: Some("rustc".to_string()),
+                message: message.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let args: Vec<String> = ["--format", "sarif", "docs/", "--timeout", "5"].iter().map(|s| s.to_string()).collect();
+        let options = CheckOptions::parse(&args).unwrap();
+        assert_eq!(options.format, OutputFormat::Sarif);
+        assert_eq!(options.timeout, Duration::from_secs(5));
+        assert_eq!(options.inputs, vec!["docs/"]);
+        assert!(!options.assume_clean);
+        assert!(CheckOptions::parse(&["--assume-clean".to_string()]).unwrap().assume_clean);
+
+        assert!(CheckOptions::parse(&["--format".to_string(), "xml".to_string()]).is_err());
+        assert!(CheckOptions::parse(&["--verbose".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_render_and_exit_code() {
+        let mut report = CheckReport::default();
+        assert_eq!(report.exit_code(), 0);
+
+        report.findings.push(finding(2, DiagnosticSeverity::WARNING, "unused variable"));
+        assert_eq!(report.exit_code(), 0);
+        report.findings.push(finding(7, DiagnosticSeverity::ERROR, "cannot find value `x`"));
+        assert_eq!(report.exit_code(), 1);
+
+        let text = report.render(OutputFormat::Text);
+        assert_eq!(
+            text,
+            "/docs/book.md:3:5: warning: unused variable\n/docs/book.md:8:5: error: cannot find value `x`\n"
+        );
+
+        let json: Value = serde_json::from_str(&report.render(OutputFormat::Json)).unwrap();
+        assert_eq!(json[1]["line"], 8);
+        assert_eq!(json[1]["severity"], "error");
+        assert_eq!(json[1]["code"], "E0425");
+
+        let sarif: Value = serde_json::from_str(&report.render(OutputFormat::Sarif)).unwrap();
+        let result = &sarif["runs"][0]["results"][1];
+        assert_eq!(result["level"], "error");
+        assert_eq!(result["ruleId"], "E0425");
+        assert_eq!(result["locations"][0]["physicalLocation"]["region"]["startColumn"], 5);
+
+        report.unchecked.push("no diagnostics".to_string());
+        assert_eq!(report.exit_code(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_diagnostics_published_after_the_settle_window() {
+        let late = (Url::parse("file:///project/.literate/book.rs").unwrap(), "rust-analyzer".to_string());
+        let silent = (Url::parse("file:///project/.literate/notes.rs").unwrap(), "rust-analyzer".to_string());
+        let expected = vec![late.clone(), silent.clone()];
+        let publish_after = Duration::from_millis(constants::CHECK_SETTLE_MS + 500);
+        let timeout = publish_after + Duration::from_millis(500);
+
+        let publish = |tx: mpsc::UnboundedSender<(String, PublishDiagnosticsParams)>| {
+            let (uri, server) = late.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(publish_after).await;
+                let params = PublishDiagnosticsParams { uri, diagnostics: Vec::new(), version: None };
+                let _ = tx.send((server, params));
+                // Keep the stream open like a running server
+                tokio::time::sleep(timeout).await;
+            })
+        };
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        publish(tx);
+        let latest = collect_diagnostics(&mut rx, &expected, timeout, false).await;
+        assert!(latest.contains_key(&late));
+        assert!(!latest.contains_key(&silent));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        publish(tx);
+        let latest = collect_diagnostics(&mut rx, &expected, timeout, true).await;
+        assert!(latest.is_empty());
+    }
+}
@@ -1,35 +1,302 @@
 use anyhow::{anyhow, Result};
+use crate::position::PositionEncoding;
+use crate::utils::constants;
 use tower_lsp::lsp_types::*;
 use serde_json::{json, Value};
-use std::io::{BufRead, BufReader, Write};
-use std::process::{Child, Command, Stdio, ChildStdin, ChildStdout};
-use tokio::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use std::sync::Arc;
 use tracing::debug;
 
+/// Requests awaiting a response from the child, keyed by request id
+type PendingRequests = Arc<std::sync::Mutex<HashMap<i64, oneshot::Sender<Value>>>>;
+
+/// How a child LSP process ended
+#[derive(Debug, Clone)]
+pub struct ChildExit {
+    /// Exit status, or `None` if it could not be collected
+    pub status: Option<ExitStatus>,
+    /// The last lines the child wrote to stderr
+    pub stderr_tail: Vec<String>,
+    /// How long the process ran
+    pub uptime: Duration,
+}
+
+impl fmt::Display for ChildExit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "{}", status),
+            None => write!(f, "unknown exit status"),
+        }
+    }
+}
+
+/// Last lines of a child's stderr, kept for crash reports
+type StderrTail = Arc<std::sync::Mutex<VecDeque<String>>>;
+
 pub struct ChildLspManager {
-    _process: Arc<Mutex<Option<Child>>>,
+    exit: watch::Receiver<Option<ChildExit>>,
+    /// Tells the monitor task to kill the process; dropping it has the same effect
+    kill: std::sync::Mutex<Option<oneshot::Sender<()>>>,
     stdin: Arc<Mutex<ChildStdin>>,
-    stdout: Arc<Mutex<BufReader<ChildStdout>>>,
+    pending: PendingRequests,
+    diagnostics: Arc<Mutex<Option<mpsc::UnboundedReceiver<PublishDiagnosticsParams>>>>,
+    settings: Arc<std::sync::Mutex<Value>>,
     next_id: Arc<Mutex<i32>>,
     capabilities: Arc<Mutex<Option<Value>>>,
+    position_encoding: std::sync::OnceLock<PositionEncoding>,
+}
+
+/// Read a single JSON-RPC message from an LSP stdio stream
+///
+/// Returns `Ok(None)` when the stream is closed cleanly between messages.
+async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R) -> Result<Option<Value>> {
+    let mut headers = HashMap::new();
+    let mut line = String::new();
+
+    // Read headers
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line).await?;
+        if n == 0 {
+            if headers.is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("Unexpected EOF while reading LSP headers"));
+        }
+
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            break;
+        }
+
+        if let Some((key, value)) = line.split_once(':') {
+            headers.insert(key.trim().to_string(), value.trim().to_string());
+        }
+    }
+
+    let content_length: usize = headers
+        .get("Content-Length")
+        .ok_or_else(|| anyhow!("Missing Content-Length header"))?
+        .parse()?;
+
+    let mut content = vec![0u8; content_length];
+    reader.read_exact(&mut content).await?;
+
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+/// Write a single JSON-RPC message with its Content-Length header
+async fn write_message<W: AsyncWrite + Unpin>(writer: &mut W, msg: &Value) -> Result<()> {
+    let msg_str = msg.to_string();
+    let message = format!("Content-Length: {}\r\n\r\n{}", msg_str.len(), msg_str);
+    writer.write_all(message.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Build a request (with an `id`) or a notification to the child
+///
+/// Null `params` are left out: `shutdown` and `exit` take none, and some servers
+/// reject an explicit `"params": null`.
+fn outgoing(id: Option<i64>, method: &str, params: Value) -> Value {
+    let mut message = json!({ "jsonrpc": "2.0", "method": method });
+    if let Some(id) = id {
+        message["id"] = json!(id);
+    }
+    if !params.is_null() {
+        message["params"] = params;
+    }
+    message
+}
+
+/// Hand a response to the request waiting for it
+fn route_response(pending: &PendingRequests, msg: Value) {
+    let id = msg.get("id").and_then(|id| id.as_i64());
+    let waiter = id.and_then(|id| pending.lock().unwrap().remove(&id));
+    match waiter {
+        Some(tx) => {
+            let _ = tx.send(msg);
+        }
+        // A late response to a request that already timed out
+        None => debug!("[ChildLSP] Dropping response with no pending request: id={:?}", id),
+    }
+}
+
+/// Dispatch a notification sent by the child
+fn handle_notification(
+    method: &str,
+    params: Option<&Value>,
+    diagnostics: &mpsc::UnboundedSender<PublishDiagnosticsParams>,
+) {
+    match method {
+        "textDocument/publishDiagnostics" => {
+            match params.cloned().map(serde_json::from_value) {
+                Some(Ok(params)) => {
+                    let _ = diagnostics.send(params);
+                }
+                _ => debug!("[ChildLSP] Ignoring malformed publishDiagnostics"),
+            }
+        }
+        "window/logMessage" | "window/showMessage" => {
+            let message = params
+                .and_then(|p| p.get("message"))
+                .and_then(|m| m.as_str())
+                .unwrap_or("");
+            debug!("[ChildLSP] {}: {}", method, message);
+        }
+        _ => debug!("[ChildLSP] Skipping notification: method={}", method),
+    }
+}
+
+/// Answer a request sent by the child (server-to-client request)
+///
+/// Returns the result on success, or a JSON-RPC error code and message.
+fn handle_server_request(
+    method: &str,
+    params: Option<&Value>,
+    settings: &Value,
+) -> std::result::Result<Value, (i64, String)> {
+    match method {
+        "workspace/configuration" => {
+            let items = params
+                .and_then(|p| p.get("items"))
+                .and_then(|i| i.as_array())
+                .cloned()
+                .unwrap_or_default();
+            let results: Vec<Value> = items
+                .iter()
+                .map(|item| match item.get("section").and_then(|s| s.as_str()) {
+                    Some(section) => section
+                        .split('.')
+                        .try_fold(settings, |value, key| value.get(key))
+                        .cloned()
+                        .unwrap_or(Value::Null),
+                    None => settings.clone(),
+                })
+                .collect();
+            Ok(Value::Array(results))
+        }
+        "window/workDoneProgress/create"
+        | "client/registerCapability"
+        | "client/unregisterCapability"
+        | "window/showMessageRequest" => Ok(Value::Null),
+        "workspace/workspaceFolders" => Ok(Value::Null),
+        "workspace/applyEdit" => Ok(json!({ "applied": false })),
+        _ => Err((-32601, format!("Method not found: {}", method))),
+    }
+}
+
+/// Background task that drains the child's stdout
+///
+/// Responses are routed to the pending request with the same id, notifications
+/// are dispatched to `handle_notification` and requests from the child are
+/// answered on the spot so it never blocks waiting on us.
+async fn reader_loop(
+    mut reader: BufReader<ChildStdout>,
+    stdin: Arc<Mutex<ChildStdin>>,
+    pending: PendingRequests,
+    diagnostics: mpsc::UnboundedSender<PublishDiagnosticsParams>,
+    settings: Arc<std::sync::Mutex<Value>>,
+) {
+    loop {
+        let msg = match read_message(&mut reader).await {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                debug!("[ChildLSP] stdout closed, stopping reader");
+                break;
+            }
+            Err(e) => {
+                debug!("[ChildLSP] Failed to read message, stopping reader: {}", e);
+                break;
+            }
+        };
+
+        let method = msg.get("method").and_then(|m| m.as_str());
+        match (method, msg.get("id")) {
+            (Some(method), Some(id)) => {
+                let reply = {
+                    let settings = settings.lock().unwrap();
+                    handle_server_request(method, msg.get("params"), &settings)
+                };
+                let response = match reply {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message }
+                    }),
+                };
+                let mut stdin = stdin.lock().await;
+                if let Err(e) = write_message(&mut *stdin, &response).await {
+                    debug!("[ChildLSP] Failed to answer '{}': {}", method, e);
+                }
+            }
+            (Some(method), None) => handle_notification(method, msg.get("params"), &diagnostics),
+            (None, _) => route_response(&pending, msg),
+        }
+    }
+
+    // Fail every outstanding request instead of letting them run into the timeout
+    pending.lock().unwrap().clear();
+}
+
+/// Background task that waits for the child process to exit and publishes how it ended
+///
+/// The process is killed and reaped when the kill switch fires or its sender is
+/// dropped along with the `ChildLspManager`.
+async fn monitor_process(
+    mut child: Child,
+    stderr_task: Option<tokio::task::JoinHandle<()>>,
+    stderr_tail: StderrTail,
+    exit: watch::Sender<Option<ChildExit>>,
+    kill: oneshot::Receiver<()>,
+) {
+    let started = Instant::now();
+    let status = tokio::select! {
+        status = child.wait() => status,
+        _ = kill => {
+            debug!("[ChildLSP] Killing process");
+            if let Err(e) = child.start_kill() {
+                debug!("[ChildLSP] Failed to kill process: {}", e);
+            }
+            child.wait().await
+        }
+    };
+    let status = match status {
+        Ok(status) => Some(status),
+        Err(e) => {
+            debug!("[ChildLSP] Failed to wait for process: {}", e);
+            None
+        }
+    };
+
+    // Let the last stderr lines arrive before taking the report
+    if let Some(task) = stderr_task {
+        let _ = tokio::time::timeout(Duration::from_secs(1), task).await;
+    }
+    let stderr_tail = stderr_tail.lock().unwrap().iter().cloned().collect();
+
+    let report = ChildExit { status, stderr_tail, uptime: started.elapsed() };
+    debug!("[ChildLSP] Process exited: {}", report);
+    let _ = exit.send(Some(report));
 }
 
 impl ChildLspManager {
     pub async fn spawn(binary: &str, args: Vec<String>) -> Result<Self> {
-        let binary = binary.to_string();
         debug!("[ChildLSP] Spawning: {} {:?}", binary, args);
-        let mut child = tokio::task::spawn_blocking(move || {
-            let mut cmd = Command::new(&binary);
-            cmd.stdin(Stdio::piped())
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped());
-            for arg in args {
-                cmd.arg(arg);
-            }
-            cmd.spawn()
-        })
-        .await??;
+        let mut child = Command::new(binary)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
 
         let stdin = child
             .stdin
@@ -40,111 +307,146 @@ impl ChildLspManager {
             .take()
             .ok_or_else(|| anyhow!("Failed to get stdout"))?;
 
-        let reader = BufReader::new(stdout);
+        // Drain stderr so a chatty child never blocks on a full pipe, keeping
+        // the last lines for crash reports
+        let stderr_tail: StderrTail = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+        let stderr_task = child.stderr.take().map(|stderr| {
+            let tail = Arc::clone(&stderr_tail);
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    debug!("[ChildLSP stderr] {}", line);
+                    let mut tail = tail.lock().unwrap();
+                    if tail.len() == constants::CHILD_LSP_STDERR_TAIL_LINES {
+                        tail.pop_front();
+                    }
+                    tail.push_back(line);
+                }
+            })
+        });
+        let (exit_tx, exit_rx) = watch::channel(None);
+        let (kill_tx, kill_rx) = oneshot::channel();
+        tokio::spawn(monitor_process(child, stderr_task, stderr_tail, exit_tx, kill_rx));
+
+        let stdin = Arc::new(Mutex::new(stdin));
+        let pending: PendingRequests = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let settings = Arc::new(std::sync::Mutex::new(Value::Null));
+        let (diagnostics_tx, diagnostics_rx) = mpsc::unbounded_channel();
+        tokio::spawn(reader_loop(
+            BufReader::new(stdout),
+            Arc::clone(&stdin),
+            Arc::clone(&pending),
+            diagnostics_tx,
+            Arc::clone(&settings),
+        ));
 
         Ok(ChildLspManager {
-            _process: Arc::new(Mutex::new(Some(child))),
-            stdin: Arc::new(Mutex::new(stdin)),
-            stdout: Arc::new(Mutex::new(reader)),
+            exit: exit_rx,
+            kill: std::sync::Mutex::new(Some(kill_tx)),
+            stdin,
+            pending,
+            diagnostics: Arc::new(Mutex::new(Some(diagnostics_rx))),
+            settings,
             next_id: Arc::new(Mutex::new(1)),
             capabilities: Arc::new(Mutex::new(None)),
+            position_encoding: std::sync::OnceLock::new(),
         })
     }
 
+    /// Send a request and wait for its response
+    ///
+    /// Any number of requests may be in flight at once; the reader task matches
+    /// each response to its caller by id.
     pub async fn send_request_raw(&self, method: &str, params: Value) -> Result<Value> {
+        if let Some(exit) = self.exit_status() {
+            return Err(anyhow!("Child LSP is not running ({})", exit));
+        }
+
         let id = {
             let mut next_id = self.next_id.lock().await;
             let current_id = *next_id;
             *next_id += 1;
-            current_id
+            current_id as i64
         };
 
-        let request = json!({
-            "jsonrpc": "2.0",
-            "id": id,
-            "method": method,
-            "params": params,
-        });
+        let request = outgoing(Some(id), method, params);
 
-        let request_str = request.to_string();
-        let message = format!("Content-Length: {}\r\n\r\n{}", request_str.len(), request_str);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
 
-        let mut stdin = self.stdin.lock().await;
-        stdin.write_all(message.as_bytes())?;
-        stdin.flush()?;
-        drop(stdin); // Release lock before reading
+        if let Err(e) = self.write(&request).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
 
-        self.read_response(id).await
+        let timeout = std::time::Duration::from_secs(constants::LSP_RESPONSE_TIMEOUT_SECS);
+        match tokio::time::timeout(timeout, rx).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(_)) => Err(anyhow!("Unexpected EOF while reading LSP response")),
+            Err(_) => {
+                self.pending.lock().unwrap().remove(&id);
+                let _ = self.send_notification("$/cancelRequest", json!({ "id": id })).await;
+                Err(anyhow!("Timeout waiting for LSP response from child process"))
+            }
+        }
     }
 
-    async fn read_response(&self, expected_id: i32) -> Result<Value> {
-        let stdout_arc = Arc::clone(&self.stdout);
-        let task = tokio::task::spawn_blocking(move || {
-            let mut stdout = stdout_arc.blocking_lock();
-
-            loop {
-                let mut headers = std::collections::HashMap::new();
-                let mut line = String::new();
-
-                // Read headers
-                loop {
-                    line.clear();
-                    let n = stdout.read_line(&mut line)?;
-                    if n == 0 {
-                        return Err(anyhow!("Unexpected EOF while reading LSP response"));
-                    }
-
-                    let trimmed = line.trim();
-                    if trimmed.is_empty() {
-                        break;
-                    }
-
-                    if let Some((key, value)) = line.split_once(':') {
-                        headers.insert(key.trim().to_string(), value.trim().to_string());
-                    }
-                }
-
-                let content_length: usize = headers
-                    .get("Content-Length")
-                    .ok_or_else(|| anyhow!("Missing Content-Length header"))?
-                    .parse()?;
-
-                let mut content = vec![0u8; content_length];
-                use std::io::Read;
-                stdout.read_exact(&mut content)?;
+    /// How the process ended, or `None` while it is still running
+    pub fn exit_status(&self) -> Option<ChildExit> {
+        self.exit.borrow().clone()
+    }
 
-                let msg: Value = serde_json::from_slice(&content)?;
+    /// Subscribe to the exit report without holding on to the manager
+    pub fn exit_watch(&self) -> watch::Receiver<Option<ChildExit>> {
+        self.exit.clone()
+    }
 
-                // If this is a response with matching ID, return it
-                if let Some(id) = msg.get("id") {
-                    if id.as_i64() == Some(expected_id as i64) && (msg.get("result").is_some() || msg.get("error").is_some()) {
-                        return Ok(msg);
-                    }
-                }
+    /// Wait until the process exits
+    pub async fn wait_for_exit(&self) -> ChildExit {
+        let mut exit = self.exit.clone();
+        let report = exit.wait_for(|exit| exit.is_some()).await.ok().and_then(|exit| exit.clone());
+        // The monitor task is gone without a report; treat the child as dead
+        report.unwrap_or(ChildExit { status: None, stderr_tail: Vec::new(), uptime: Duration::ZERO })
+    }
 
-                // Otherwise, it's a notification or a response we don't want - skip it and continue
-                debug!("[ChildLSP] Skipping message (not response to ID {}): method={}",
-                    expected_id,
-                    msg.get("method").and_then(|m| m.as_str()).unwrap_or("<no method>"));
-            }
-        });
+    async fn write(&self, msg: &Value) -> Result<()> {
+        let mut stdin = self.stdin.lock().await;
+        write_message(&mut *stdin, msg).await
+    }
 
-        match tokio::time::timeout(std::time::Duration::from_secs(5), task).await {
-            Ok(Ok(value_result)) => value_result,  // Flatten nested Results
-            Ok(Err(join_err)) => Err(anyhow!("Task error: {}", join_err)),
-            Err(_) => Err(anyhow!("Timeout waiting for LSP response from child process")),
-        }
+    /// Take the stream of `textDocument/publishDiagnostics` notifications from this child
+    ///
+    /// Can only be taken once; subsequent calls return `None`.
+    pub async fn take_diagnostics(&self) -> Option<mpsc::UnboundedReceiver<PublishDiagnosticsParams>> {
+        self.diagnostics.lock().await.take()
     }
 
     pub async fn initialize(&self, root_uri: String, init_options: Option<serde_json::Value>) -> Result<()> {
         let init_opts = init_options.unwrap_or_else(|| json!({}));
+        // Answer workspace/configuration from the same settings
+        *self.settings.lock().unwrap() = init_opts.clone();
         let params = json!({
             "processId": std::process::id(),
             "rootUri": root_uri,
             "capabilities": {
+                "general": {
+                    "positionEncodings": PositionEncoding::SUPPORTED.map(PositionEncoding::kind)
+                },
+                "workspace": {
+                    "configuration": true,
+                    "workspaceEdit": {
+                        "documentChanges": true
+                    }
+                },
                 "textDocument": {
                     "synchronization": {
                         "didSave": true
+                    },
+                    "rename": {
+                        "prepareSupport": true
+                    },
+                    "publishDiagnostics": {
+                        "relatedInformation": true
                     }
                 }
             },
@@ -154,6 +456,13 @@ impl ChildLspManager {
         let response = self.send_request_raw("initialize", params).await?;
         // Extract and store server capabilities from the response
         if let Some(capabilities) = response.get("result").and_then(|r| r.get("capabilities")) {
+            // Servers that ignore the offer use UTF-16, the LSP default
+            let encoding = capabilities
+                .get("positionEncoding")
+                .and_then(|kind| serde_json::from_value(kind.clone()).ok())
+                .and_then(|kind| PositionEncoding::from_kind(&kind))
+                .unwrap_or_default();
+            let _ = self.position_encoding.set(encoding);
             let mut caps = self.capabilities.lock().await;
             *caps = Some(capabilities.clone());
         }
@@ -162,6 +471,11 @@ impl ChildLspManager {
         Ok(())
     }
 
+    /// Position encoding negotiated with the child during `initialize`
+    pub fn position_encoding(&self) -> PositionEncoding {
+        self.position_encoding.get().copied().unwrap_or_default()
+    }
+
     pub async fn get_capabilities(&self) -> Option<Value> {
         let caps = self.capabilities.lock().await;
         caps.as_ref().cloned()
@@ -186,20 +500,22 @@ impl ChildLspManager {
         None
     }
 
-    async fn send_notification(&self, method: &str, params: Value) -> Result<()> {
-        let notification = json!({
-            "jsonrpc": "2.0",
-            "method": method,
-            "params": params,
-        });
-
-        let notification_str = notification.to_string();
-        let message = format!("Content-Length: {}\r\n\r\n{}", notification_str.len(), notification_str);
+    /// Whether the child accepts incremental `textDocument/didChange` edits
+    pub async fn supports_incremental_sync(&self) -> bool {
+        let sync = match self.get_capabilities().await {
+            Some(caps) => caps.get("textDocumentSync").cloned(),
+            None => None,
+        };
+        let kind = match sync {
+            Some(Value::Number(kind)) => kind.as_i64(),
+            Some(Value::Object(options)) => options.get("change").and_then(|c| c.as_i64()),
+            _ => None,
+        };
+        kind == Some(2)
+    }
 
-        let mut stdin = self.stdin.lock().await;
-        stdin.write_all(message.as_bytes())?;
-        stdin.flush()?;
-        Ok(())
+    async fn send_notification(&self, method: &str, params: Value) -> Result<()> {
+        self.write(&outgoing(None, method, params)).await
     }
 
     pub async fn did_open(&self, uri: String, language_id: String, content: String) -> Result<()> {
@@ -220,22 +536,43 @@ impl ChildLspManager {
         debug!("[ChildLSP] Changing document: uri={}, version={}, content_len={}",
             uri, version, content.len());
 
+        self.send_did_change(uri, version, json!([{ "text": content }])).await
+    }
+
+    /// Send range edits instead of the full document text
+    ///
+    /// Only valid when `supports_incremental_sync` is true.
+    pub async fn did_change_incremental(&self, uri: String, version: i32, changes: Vec<Value>) -> Result<()> {
+        debug!("[ChildLSP] Changing document incrementally: uri={}, version={}, changes={}",
+            uri, version, changes.len());
+
+        self.send_did_change(uri, version, Value::Array(changes)).await
+    }
+
+    async fn send_did_change(&self, uri: String, version: i32, content_changes: Value) -> Result<()> {
         let params = json!({
             "textDocument": {
                 "uri": uri,
                 "version": version
             },
-            "contentChanges": [
-                {
-                    "text": content
-                }
-            ]
+            "contentChanges": content_changes
         });
 
         self.send_notification("textDocument/didChange", params).await?;
         Ok(())
     }
 
+    pub async fn did_close(&self, uri: String) -> Result<()> {
+        let params = json!({
+            "textDocument": {
+                "uri": uri
+            }
+        });
+
+        self.send_notification("textDocument/didClose", params).await?;
+        Ok(())
+    }
+
     pub async fn goto_definition(
         &self,
         uri: String,
@@ -283,17 +620,154 @@ impl ChildLspManager {
         }
     }
 
+    /// Kill the process and wait until it has been reaped
+    pub async fn kill(&self) -> ChildExit {
+        if let Some(kill) = self.kill.lock().unwrap().take() {
+            let _ = kill.send(());
+        }
+        self.wait_for_exit().await
+    }
+
+    /// Shut the child down following the LSP exit sequence
+    ///
+    /// Sends `shutdown` and `exit`, giving the child `CHILD_LSP_SHUTDOWN_TIMEOUT_SECS`
+    /// for each step, then kills and reaps the process if it is still running.
     pub async fn shutdown(&self) -> Result<()> {
-        // Send exit notification only - don't wait for responses
-        let _ = self.send_notification("exit", json!({})).await;
+        self.shutdown_within(Duration::from_secs(constants::CHILD_LSP_SHUTDOWN_TIMEOUT_SECS))
+            .await
+    }
+
+    async fn shutdown_within(&self, timeout: Duration) -> Result<()> {
+        if self.exit_status().is_some() {
+            return Ok(());
+        }
+
+        match tokio::time::timeout(timeout, self.send_request_raw("shutdown", Value::Null)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => debug!("[ChildLSP] Shutdown request failed: {}", e),
+            Err(_) => debug!("[ChildLSP] Shutdown request timed out"),
+        }
+        // Even without a shutdown response, `exit` lets the child quit on its own
+        let _ = self.send_notification("exit", Value::Null).await;
+
+        let exit = match tokio::time::timeout(timeout, self.wait_for_exit()).await {
+            Ok(exit) => exit,
+            Err(_) => {
+                debug!("[ChildLSP] Process did not exit, killing it");
+                self.kill().await
+            }
+        };
+        debug!("[ChildLSP] Shut down: {}", exit);
         Ok(())
     }
 }
 
 impl Drop for ChildLspManager {
     fn drop(&mut self) {
-        // Can't use blocking_lock() in Drop when inside async runtime
-        // Let the OS clean up the process - it will be killed when the parent exits
-        debug!("[ChildLSP] Dropped, process will be cleaned up by OS");
+        // The monitor task outlives the manager; it kills and reaps the process
+        let kill = self.kill.get_mut().ok().and_then(Option::take);
+        if let Some(kill) = kill {
+            debug!("[ChildLSP] Dropped, killing process if still running");
+            let _ = kill.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_message_roundtrip() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = BufReader::new(server);
+
+        write_message(&mut client, &json!({"jsonrpc": "2.0", "id": 1, "result": "ünïcode"})).await.unwrap();
+        write_message(&mut client, &json!({"jsonrpc": "2.0", "method": "initialized"})).await.unwrap();
+        drop(client);
+
+        let first = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(first["result"], "ünïcode");
+        let second = read_message(&mut reader).await.unwrap().unwrap();
+        assert_eq!(second["method"], "initialized");
+        assert!(read_message(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_responses_routed_out_of_order() {
+        let pending: PendingRequests = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let (tx1, rx1) = oneshot::channel();
+        let (tx2, rx2) = oneshot::channel();
+        pending.lock().unwrap().insert(1, tx1);
+        pending.lock().unwrap().insert(2, tx2);
+
+        route_response(&pending, json!({"jsonrpc": "2.0", "id": 2, "result": "second"}));
+        route_response(&pending, json!({"jsonrpc": "2.0", "id": 1, "result": "first"}));
+
+        assert_eq!(rx1.await.unwrap()["result"], "first");
+        assert_eq!(rx2.await.unwrap()["result"], "second");
+        assert!(pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_methods_without_params() {
+        let shutdown = outgoing(Some(7), "shutdown", Value::Null);
+        assert_eq!(shutdown, json!({"jsonrpc": "2.0", "id": 7, "method": "shutdown"}));
+        let exit = outgoing(None, "exit", Value::Null);
+        assert_eq!(exit, json!({"jsonrpc": "2.0", "method": "exit"}));
+
+        let hover = outgoing(Some(8), "textDocument/hover", json!({"position": {"line": 0, "character": 0}}));
+        assert_eq!(hover["params"]["position"]["line"], 0);
+    }
+
+    #[test]
+    fn test_workspace_configuration_request() {
+        let settings = json!({"gopls": {"hints": {"assignVariableTypes": true}}});
+        let params = json!({"items": [{"section": "gopls.hints"}, {"section": "missing"}, {}]});
+
+        let result = handle_server_request("workspace/configuration", Some(&params), &settings).unwrap();
+        assert_eq!(result[0], json!({"assignVariableTypes": true}));
+        assert_eq!(result[1], Value::Null);
+        assert_eq!(result[2], settings);
+
+        assert!(handle_server_request("unknown/method", None, &settings).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exit_report_and_dead_child() {
+        let script = "echo starting >&2; echo 'panic: malformed snippet' >&2; exit 3";
+        let lsp = ChildLspManager::spawn("sh", vec!["-c".to_string(), script.to_string()])
+            .await
+            .unwrap();
+
+        let exit = lsp.wait_for_exit().await;
+        assert_eq!(exit.status.and_then(|s| s.code()), Some(3));
+        assert_eq!(exit.stderr_tail, vec!["starting", "panic: malformed snippet"]);
+
+        // Requests to a dead child fail immediately instead of waiting for a response
+        let err = lsp.send_request_raw("textDocument/hover", json!({})).await.unwrap_err();
+        assert!(err.to_string().contains("not running"));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_kills_unresponsive_child() {
+        // Reads stdin forever but never answers
+        let lsp = ChildLspManager::spawn("sh", vec!["-c".to_string(), "cat > /dev/null".to_string()])
+            .await
+            .unwrap();
+
+        lsp.shutdown_within(Duration::from_millis(200)).await.unwrap();
+        let exit = lsp.exit_status().expect("child should be reaped");
+        assert!(exit.status.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_drop_kills_child() {
+        let lsp = ChildLspManager::spawn("sleep", vec!["30".to_string()]).await.unwrap();
+        let mut exit = lsp.exit_watch();
+        drop(lsp);
+
+        let reaped = tokio::time::timeout(Duration::from_secs(5), exit.wait_for(|exit| exit.is_some())).await;
+        assert!(matches!(reaped, Ok(Ok(_))));
     }
 }
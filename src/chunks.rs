@@ -0,0 +1,227 @@
+use crate::config::LanguageAliases;
+use crate::fence::Fence;
+use crate::position::PositionEncoding;
+use crate::virtual_doc::{code_lines, collect_languages, collect_sessions, group_fences};
+use std::collections::{HashMap, HashSet};
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+
+/// Name of the chunk a block defines, from a `<<name>>=` first line
+pub fn definition_name(line: &str) -> Option<&str> {
+    let name = line.trim().strip_prefix("<<")?.strip_suffix(">>=")?.trim();
+    (!name.is_empty() && !name.contains("<<") && !name.contains(">>")).then_some(name)
+}
+
+/// A `<<name>>` reference taking up a whole line: the width of its indentation and the chunk name
+pub fn reference(line: &str) -> Option<(usize, &str)> {
+    let code = line.trim_start_matches([' ', '\t']);
+    let name = code.trim_end().strip_prefix("<<")?.strip_suffix(">>")?.trim();
+    if name.is_empty() || name.contains("<<") || name.contains(">>") {
+        return None;
+    }
+    Some((line.len() - code.len(), name))
+}
+
+/// A broken chunk reference, by block and line within the block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkProblem {
+    pub block: usize,
+    pub line: usize,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+/// Chunk definitions and references among the blocks of one virtual document
+#[derive(Debug, Default)]
+pub struct ChunkGraph {
+    /// Chunk each block defines, if any
+    names: Vec<Option<String>>,
+    /// Blocks defining each chunk, in document order
+    definitions: HashMap<String, Vec<usize>>,
+    /// References in each block: (line, chunk name)
+    references: Vec<Vec<(usize, String)>>,
+    /// Every chunk referenced somewhere
+    referenced: HashSet<String>,
+}
+
+impl ChunkGraph {
+    /// Build the graph from the code lines of each block
+    pub fn new<'a>(blocks: impl IntoIterator<Item = &'a [String]>) -> Self {
+        let mut graph = ChunkGraph::default();
+        for (index, lines) in blocks.into_iter().enumerate() {
+            let name = lines.first().and_then(|line| definition_name(line)).map(str::to_string);
+            if let Some(name) = &name {
+                graph.definitions.entry(name.clone()).or_default().push(index);
+            }
+            graph.names.push(name);
+
+            let references: Vec<(usize, String)> = lines
+                .iter()
+                .enumerate()
+                .filter_map(|(line, text)| reference(text).map(|(_, name)| (line, name.to_string())))
+                .collect();
+            graph.referenced.extend(references.iter().map(|(_, name)| name.clone()));
+            graph.references.push(references);
+        }
+        graph
+    }
+
+    /// Name of the chunk a block defines
+    pub fn defines(&self, block: usize) -> Option<&str> {
+        self.names.get(block)?.as_deref()
+    }
+
+    /// Blocks defining a chunk, in the order their code is concatenated
+    pub fn definitions(&self, name: &str) -> &[usize] {
+        self.definitions.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Whether a block is emitted at the top level of the virtual document
+    ///
+    /// Plain blocks are, and so are chunks nothing references (such as `<<*>>=`), so
+    /// their code still reaches the language server.
+    pub fn is_root(&self, block: usize) -> bool {
+        match self.defines(block) {
+            Some(name) => !self.referenced.contains(name),
+            None => true,
+        }
+    }
+
+    /// References to undefined chunks, and references that close a cycle
+    pub fn problems(&self) -> Vec<ChunkProblem> {
+        let mut problems = Vec::new();
+        for (block, references) in self.references.iter().enumerate() {
+            for (line, name) in references {
+                if !self.definitions.contains_key(name) {
+                    problems.push(ChunkProblem {
+                        block,
+                        line: *line,
+                        severity: DiagnosticSeverity::WARNING,
+                        message: format!("Undefined chunk '{}'", name),
+                    });
+                }
+            }
+        }
+
+        let mut finished = HashSet::new();
+        for name in self.names.iter().flatten() {
+            let mut path = Vec::new();
+            self.find_cycles(name, &mut path, &mut finished, &mut problems);
+        }
+        problems.sort_by_key(|problem| (problem.block, problem.line));
+        problems
+    }
+
+    /// Depth-first search reporting every reference back into the current path
+    fn find_cycles<'a>(
+        &'a self,
+        name: &'a str,
+        path: &mut Vec<&'a str>,
+        finished: &mut HashSet<&'a str>,
+        problems: &mut Vec<ChunkProblem>,
+    ) {
+        if finished.contains(name) {
+            return;
+        }
+        path.push(name);
+        for &block in self.definitions(name) {
+            for (line, target) in &self.references[block] {
+                if let Some(start) = path.iter().position(|open| open == target) {
+                    let cycle: Vec<&str> = path[start..].iter().copied().chain([target.as_str()]).collect();
+                    problems.push(ChunkProblem {
+                        block,
+                        line: *line,
+                        severity: DiagnosticSeverity::ERROR,
+                        message: format!("Chunk reference cycle: {}", cycle.join(" -> ")),
+                    });
+                } else if self.definitions.contains_key(target) {
+                    self.find_cycles(target, path, finished, problems);
+                }
+            }
+        }
+        path.pop();
+        finished.insert(name);
+    }
+}
+
+/// Diagnostics for broken chunk references in every virtual document of a document,
+/// from all of its blocks
+pub fn chunk_diagnostics(fences: &[Fence], aliases: &LanguageAliases, encoding: PositionEncoding) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for lang in collect_languages(fences, aliases) {
+        for session in collect_sessions(fences, aliases, &lang) {
+            let fences: Vec<_> = group_fences(fences, aliases, &lang, session.as_deref())
+                .iter()
+                .map(|fence| code_lines(fence, &lang))
+                .collect();
+            let graph = ChunkGraph::new(fences.iter().map(|fence| fence.lines.as_slice()));
+
+            for problem in graph.problems() {
+                let fence = &fences[problem.block];
+                let code = &fence.lines[problem.line];
+                let indent = code.len() - code.trim_start_matches([' ', '\t']).len();
+                let line = fence.content_line(problem.line) as u32;
+                diagnostics.push(Diagnostic {
+                    range: Range {
+                        start: Position { line, character: fence.column(problem.line, indent, encoding) },
+                        end: Position { line, character: fence.column(problem.line, code.trim_end().len(), encoding) },
+                    },
+                    severity: Some(problem.severity),
+                    source: Some("literate-lsp".to_string()),
+                    message: problem.message,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::DocumentFormat;
+
+    fn blocks(blocks: &[&str]) -> Vec<Vec<String>> {
+        blocks.iter().map(|block| block.lines().map(str::to_string).collect()).collect()
+    }
+
+    #[test]
+    fn test_definitions_and_references() {
+        assert_eq!(definition_name("<<parse args>>="), Some("parse args"));
+        assert_eq!(definition_name("<<parse args>>"), None);
+        assert_eq!(reference("    <<parse args>>"), Some((4, "parse args")));
+        assert_eq!(reference("x <<= 2;"), None);
+        assert_eq!(reference("<<a>> + <<b>>"), None);
+
+        let blocks = blocks(&["fn main() {\n    <<body>>\n}", "<<body>>=\nprintln!();", "<<*>>=\nfn unused() {}"]);
+        let graph = ChunkGraph::new(blocks.iter().map(Vec::as_slice));
+        assert_eq!(graph.defines(1), Some("body"));
+        assert_eq!(graph.definitions("body"), &[1]);
+        assert!(graph.is_root(0) && !graph.is_root(1) && graph.is_root(2));
+        assert!(graph.problems().is_empty());
+    }
+
+    #[test]
+    fn test_cycles_and_undefined_chunks() {
+        let blocks = blocks(&["<<a>>", "<<a>>=\n<<b>>", "<<b>>=\n<<missing>>\n<<a>>"]);
+        let graph = ChunkGraph::new(blocks.iter().map(Vec::as_slice));
+        let problems = graph.problems();
+        assert_eq!(problems.len(), 2);
+        assert_eq!((problems[0].block, problems[0].line), (2, 1));
+        assert_eq!(problems[0].message, "Undefined chunk 'missing'");
+        assert_eq!((problems[1].block, problems[1].line), (2, 2));
+        assert_eq!(problems[1].message, "Chunk reference cycle: a -> b -> a");
+        assert_eq!(problems[1].severity, DiagnosticSeverity::ERROR);
+    }
+
+    #[test]
+    fn test_chunk_diagnostics_on_markdown() {
+        let markdown = "# Loop\n\n```rust\n<<a>>=\n  <<a>>\n```\n\n```rust\n<<a>>\n```\n";
+        let fences = DocumentFormat::Markdown.scan(markdown);
+        let diagnostics = chunk_diagnostics(&fences, &LanguageAliases::default(), PositionEncoding::Utf16);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].range.start, Position { line: 4, character: 2 });
+        assert_eq!(diagnostics[0].range.end, Position { line: 4, character: 7 });
+        assert_eq!(diagnostics[0].message, "Chunk reference cycle: a -> a");
+    }
+}
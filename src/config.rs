@@ -1,3 +1,4 @@
+use crate::features::Feature;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -23,21 +24,47 @@ pub const FORBIDDEN_FORMATS: &[&str] = &[
 ];
 
 /// Configuration for virtual document output
-#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct VirtualDocConfig {
     #[serde(default = "default_output_dir")]
     pub output_dir: String,
 }
 
-fn default_output_dir() -> String {
+impl Default for VirtualDocConfig {
+    fn default() -> Self {
+        VirtualDocConfig {
+            output_dir: default_output_dir(),
+        }
+    }
+}
+
+pub(crate) fn default_output_dir() -> String {
     "./src".to_string()
 }
 
+/// Code injected around a language's virtual documents
+///
+/// Lets fragments type-check without boilerplate in every block, e.g. a Go
+/// `package main` prelude, or `fn main() {` / `}` around Rust statements.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Wrapper {
+    #[serde(default)]
+    pub prelude: String,
+    #[serde(default)]
+    pub epilogue: String,
+}
+
 /// Top-level literate-lsp configuration
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
 pub struct LiterateConfig {
     #[serde(default)]
     pub literate: VirtualDocConfig,
+    /// Prelude and epilogue per code block language
+    #[serde(default)]
+    pub wrappers: HashMap<String, Wrapper>,
+    /// Extra names for languages, e.g. `golang = "go"`
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -53,10 +80,37 @@ pub struct LspConfig {
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 pub struct LanguageServerRef {
     pub name: String,
+    /// Features the server is limited to; empty for all of them
     #[serde(default)]
+    #[serde(rename = "only-features")]
+    pub only_features: Vec<String>,
+    /// Features the server is not used for
+    #[serde(default)]
+    #[serde(rename = "except-features")]
     pub except_features: Vec<String>,
 }
 
+/// A language server to run for a language, resolved to its command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LanguageServerCommand {
+    pub name: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Features the server is limited to; empty for all of them
+    pub only_features: Vec<String>,
+    /// Features the server is not used for
+    pub except_features: Vec<String>,
+}
+
+impl LanguageServerCommand {
+    /// Whether the language's config lets this server serve a feature
+    pub fn serves(&self, feature: Feature) -> bool {
+        let name = feature.name();
+        (self.only_features.is_empty() || self.only_features.iter().any(|only| only == name))
+            && !self.except_features.iter().any(|except| except == name)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum LanguageServerEntry {
@@ -64,15 +118,27 @@ pub enum LanguageServerEntry {
     Object(LanguageServerRef),
 }
 
+/// A `file-types` entry: a file extension, or a glob matching whole file names
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum FileType {
+    Extension(String),
+    Glob { glob: String },
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct LanguageConfig {
     pub name: String,
+    /// Name of the language in the LSP protocol, when it differs from `name`
+    #[serde(default)]
+    #[serde(rename = "language-id")]
+    pub language_id: Option<String>,
     #[serde(default)]
     #[serde(rename = "language-servers")]
     pub language_servers: Vec<LanguageServerEntry>,
     #[serde(default)]
     #[serde(rename = "file-types")]
-    pub file_types: Vec<String>,
+    pub file_types: Vec<FileType>,
 }
 
 impl LanguageConfig {
@@ -83,6 +149,51 @@ impl LanguageConfig {
             .map(|entry| entry.to_server_name())
             .collect()
     }
+
+    /// File extensions of the language, leaving out globs
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.file_types.iter().filter_map(|file_type| match file_type {
+            FileType::Extension(extension) => Some(extension.as_str()),
+            FileType::Glob { .. } => None,
+        })
+    }
+}
+
+/// Fence names that are common in documents but not a file type or language id
+const COMMON_ALIASES: &[(&str, &str)] = &[
+    ("golang", "go"),
+    ("shell", "bash"),
+    ("shellscript", "bash"),
+];
+
+/// Maps the names code blocks give a language to its name in the config
+///
+/// `rs` and `rust` blocks are the same language: they share a virtual document and
+/// a child LSP. Lookups ignore case; unknown names resolve to themselves.
+#[derive(Debug, Clone, Default)]
+pub struct LanguageAliases {
+    names: HashMap<String, String>,
+}
+
+impl LanguageAliases {
+    /// Name of the language `lang` stands for
+    pub fn resolve<'a>(&'a self, lang: &'a str) -> &'a str {
+        match self.names.get(lang) {
+            Some(name) => name,
+            None => self.names.get(&lang.to_lowercase()).map_or(lang, String::as_str),
+        }
+    }
+
+    /// Add aliases that take precedence over the existing ones
+    ///
+    /// A target may itself be an alias, e.g. `py3 = "py"`.
+    pub fn extend(&mut self, aliases: &HashMap<String, String>) {
+        let resolved: Vec<(String, String)> = aliases
+            .iter()
+            .map(|(alias, lang)| (alias.to_lowercase(), self.resolve(lang).to_string()))
+            .collect();
+        self.names.extend(resolved);
+    }
 }
 
 /// Extension trait for extracting server names from LanguageServerEntry
@@ -212,6 +323,34 @@ impl Config {
         });
     }
 
+    /// Alias table of the configured languages
+    ///
+    /// Language names come first, then Helix `language-id`s, a few common fence names,
+    /// and file extensions. Within each kind the first language listed wins.
+    pub fn language_aliases(&self) -> LanguageAliases {
+        let mut names = HashMap::new();
+        for language in &self.language {
+            names.insert(language.name.to_lowercase(), language.name.clone());
+        }
+        let mut add = |alias: &str, name: &str| {
+            names.entry(alias.to_lowercase()).or_insert_with(|| name.to_string());
+        };
+        for language in &self.language {
+            if let Some(id) = &language.language_id {
+                add(id, &language.name);
+            }
+        }
+        for (alias, name) in COMMON_ALIASES {
+            add(alias, name);
+        }
+        for language in &self.language {
+            for extension in language.extensions() {
+                add(extension, &language.name);
+            }
+        }
+        LanguageAliases { names }
+    }
+
     /// Get LSP command for a language
     /// Walks the language-servers list and returns the first one that exists and has a command
     /// First checks if lang is a direct LSP name, then checks if it's a language with LSPs
@@ -270,15 +409,32 @@ impl Config {
     }
 
     /// Get both command and args for a language
-    /// Returns (command, args) by walking the language-servers list
+    /// Returns (command, args) of the first of its language servers that can run
     pub fn get_command_and_args(&self, lang: &str) -> Option<(String, Vec<String>)> {
+        self.get_language_servers(lang)
+            .into_iter()
+            .next()
+            .map(|server| (server.command, server.args))
+    }
+
+    /// Get every language server that can run for a language, in the order they are listed
+    /// A language server named like the language is used on its own
+    /// Otherwise walks the language-servers list, keeping servers whose binary is in PATH
+    pub fn get_language_servers(&self, lang: &str) -> Vec<LanguageServerCommand> {
         // First try direct LSP lookup
         if let Some(lsp) = self.language_server.get(lang) {
             if !lsp.command.is_empty() {
-                return Some((lsp.command.clone(), lsp.args.clone()));
+                return vec![LanguageServerCommand {
+                    name: lang.to_string(),
+                    command: lsp.command.clone(),
+                    args: lsp.args.clone(),
+                    only_features: Vec::new(),
+                    except_features: Vec::new(),
+                }];
             }
         }
 
+        let mut servers = Vec::new();
         // If not found, try to find the language and walk its LSPs
         if let Some(language) = self.language.iter().find(|l| l.name == lang) {
             info!("[Config] Found language '{}' with {} servers", lang, language.language_servers.len());
@@ -300,7 +456,19 @@ impl Config {
                         // Check if the binary exists in PATH
                         if Self::command_exists(&lsp_cfg.command) {
                             info!("[Config] Server '{}' command found in PATH", server_name);
-                            return Some((lsp_cfg.command.clone(), lsp_cfg.args.clone()));
+                            let (only_features, except_features) = match server_entry {
+                                LanguageServerEntry::String(_) => (Vec::new(), Vec::new()),
+                                LanguageServerEntry::Object(server) => {
+                                    (server.only_features.clone(), server.except_features.clone())
+                                }
+                            };
+                            servers.push(LanguageServerCommand {
+                                name: server_name,
+                                command: lsp_cfg.command.clone(),
+                                args: lsp_cfg.args.clone(),
+                                only_features,
+                                except_features,
+                            });
                         } else {
                             info!("[Config] Server '{}' command NOT found in PATH", server_name);
                         }
@@ -313,7 +481,7 @@ impl Config {
             info!("[Config] Language '{}' not found in config", lang);
         }
 
-        None
+        servers
     }
 
     /// Get initialization options for an LSP
@@ -367,6 +535,22 @@ impl Config {
             .any(|lsp| lsp.to_lowercase() == lower)
     }
 
+    /// Whether blocks of `block_lang` in a document of `doc_lang` are left out
+    ///
+    /// The one rule for the language server, `tangle` and `check`: documentation formats
+    /// are never child languages, and blocks in the document's own language would have
+    /// literate-lsp act on itself (markdown blocks in a markdown file).
+    pub fn skips_language(&self, doc_lang: Option<&str>, block_lang: &str) -> bool {
+        if self.is_format_forbidden(block_lang) {
+            return true;
+        }
+        match doc_lang {
+            Some("latex") => matches!(block_lang, "latex" | "tex"),
+            Some(doc_lang) => doc_lang == block_lang,
+            None => false,
+        }
+    }
+
     /// Static method for backward compatibility (checks only format names)
     pub fn is_format_forbidden_static(lang: &str) -> bool {
         let lower = lang.to_lowercase();
@@ -381,10 +565,10 @@ impl Config {
         if let Some(language) = self.language.iter().find(|l| l.name == lang) {
             // Return the first file-type if available
             // file-types are typically in the format ["rs"], ["py", "pyw"], etc.
-            // We just need the first one
-            if !language.file_types.is_empty() {
-                debug!("Extension for '{}': using file-type '{}'", lang, language.file_types[0]);
-                return Some(language.file_types[0].clone());
+            // We just need the first extension; globs name whole files
+            if let Some(extension) = language.extensions().next() {
+                debug!("Extension for '{}': using file-type '{}'", lang, extension);
+                return Some(extension.to_string());
             } else {
                 debug!("Extension for '{}': no file-types in config, falling back to language name", lang);
             }
@@ -427,6 +611,18 @@ impl Default for Config {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_skipped_languages() {
+        let config = Config::default();
+        assert!(config.skips_language(Some("markdown"), "markdown"));
+        assert!(config.skips_language(Some("markdown"), "typst"));
+        assert!(config.skips_language(Some("latex"), "tex"));
+        assert!(config.skips_language(Some("jupyter"), "jupyter"));
+        assert!(config.skips_language(Some("go"), "go"));
+        assert!(!config.skips_language(Some("markdown"), "go"));
+        assert!(!config.skips_language(None, "rust"));
+    }
+
     #[test]
     fn test_forbidden_formats_are_detected() {
         let config: Config = toml::from_str("").unwrap_or_default();
@@ -538,6 +734,78 @@ extra_config = { nested = "value" }
         assert_eq!(pyright.args, vec!["--option1"]);
     }
 
+    #[test]
+    fn test_every_language_server_with_features() {
+        let config: Config = toml::from_str(
+            r#"
+[[language]]
+name = "python"
+language-servers = [
+  "pyright",
+  { name = "ruff", only-features = ["format", "diagnostics"] },
+  { name = "pylsp", except-features = ["hover"] },
+  "missing",
+]
+
+[language-server.pyright]
+command = "sh"
+
+[language-server.ruff]
+command = "cat"
+
+[language-server.pylsp]
+command = "sh"
+
+[language-server.missing]
+command = "no-such-language-server"
+"#,
+        )
+        .unwrap();
+
+        let servers = config.get_language_servers("python");
+        let names: Vec<&str> = servers.iter().map(|server| server.name.as_str()).collect();
+        assert_eq!(names, vec!["pyright", "ruff", "pylsp"]);
+        assert_eq!(config.get_command_and_args("python"), Some(("sh".to_string(), vec![])));
+
+        assert!(servers[0].serves(Feature::Hover));
+        assert!(servers[1].serves(Feature::Format) && servers[1].serves(Feature::Diagnostics));
+        assert!(!servers[1].serves(Feature::Completion));
+        assert!(!servers[2].serves(Feature::Hover) && servers[2].serves(Feature::Completion));
+    }
+
+    #[test]
+    fn test_embedded_config_parses() {
+        // `file-types` mixes extensions with `{ glob = "..." }` entries
+        let config: Config = toml::from_str(HELIX_LANGUAGES_TOML).unwrap();
+        assert!(config.language.len() > 100);
+        assert_eq!(config.get_extension_for_language("rust").as_deref(), Some("rs"));
+    }
+
+    #[test]
+    fn test_language_aliases() {
+        let mut aliases = Config::default().language_aliases();
+        for (alias, lang) in [
+            ("rs", "rust"),
+            ("py", "python"),
+            ("ts", "typescript"),
+            ("js", "javascript"),
+            ("sh", "bash"),
+            ("golang", "go"),
+            ("c++", "cpp"),
+            ("Rust", "rust"),
+            ("rust", "rust"),
+        ] {
+            assert_eq!(aliases.resolve(alias), lang, "alias {}", alias);
+        }
+        assert_eq!(aliases.resolve("nonsense"), "nonsense");
+
+        // User aliases win, and may point at other aliases
+        let user = HashMap::from([("py3".to_string(), "py".to_string()), ("sh".to_string(), "fish".to_string())]);
+        aliases.extend(&user);
+        assert_eq!(aliases.resolve("py3"), "python");
+        assert_eq!(aliases.resolve("sh"), "fish");
+    }
+
     #[test]
     fn test_nested_config_only_without_command() {
         // Test what happens when only nested config is defined, no command
@@ -602,34 +870,27 @@ pub fn find_project_root(start_path: &Path) -> PathBuf {
 /// Load literate-lsp configuration from .literate.toml
 ///
 /// Looks for .literate.toml in the project root. If not found, returns default config.
-pub fn load_literate_config(project_root: &Path) -> VirtualDocConfig {
+pub fn load_literate_config(project_root: &Path) -> LiterateConfig {
     let config_path = project_root.join(".literate.toml");
-
     if !config_path.exists() {
         info!("[Config] No .literate.toml found at {}, using defaults", project_root.display());
-        return VirtualDocConfig {
-            output_dir: default_output_dir(),
-        };
+        return LiterateConfig::default();
     }
 
     match std::fs::read_to_string(&config_path) {
         Ok(content) => match toml::from_str::<LiterateConfig>(&content) {
             Ok(config) => {
                 info!("[Config] Loaded .literate.toml with output_dir: {}", config.literate.output_dir);
-                config.literate
+                config
             }
             Err(e) => {
                 warn!("[Config] Failed to parse .literate.toml: {}, using defaults", e);
-                VirtualDocConfig {
-                    output_dir: default_output_dir(),
-                }
+                LiterateConfig::default()
             }
         },
         Err(e) => {
             warn!("[Config] Failed to read .literate.toml: {}, using defaults", e);
-            VirtualDocConfig {
-                output_dir: default_output_dir(),
-            }
+            LiterateConfig::default()
         }
     }
 }
@@ -674,7 +935,8 @@ mod project_root_tests {
     fn test_load_literate_config_default() {
         let temp_dir = TempDir::new().unwrap();
         let config = load_literate_config(temp_dir.path());
-        assert_eq!(config.output_dir, "./src");
+        assert_eq!(config.literate.output_dir, "./src");
+        assert!(config.wrappers.is_empty());
     }
 
     #[test]
@@ -687,6 +949,43 @@ output_dir = "./generated"
         std::fs::write(temp_dir.path().join(".literate.toml"), config_content).unwrap();
 
         let config = load_literate_config(temp_dir.path());
-        assert_eq!(config.output_dir, "./generated");
+        assert_eq!(config.literate.output_dir, "./generated");
+    }
+
+    #[test]
+    fn test_load_literate_config_wrappers() {
+        let temp_dir = TempDir::new().unwrap();
+        let config_content = r#"
+[wrappers.go]
+prelude = """
+package main
+
+import "fmt"
+"""
+
+[wrappers.rust]
+prelude = "fn main() {"
+epilogue = "}"
+"#;
+        std::fs::write(temp_dir.path().join(".literate.toml"), config_content).unwrap();
+
+        let config = load_literate_config(temp_dir.path());
+        assert_eq!(config.literate.output_dir, "./src");
+        assert_eq!(config.wrappers["go"].prelude, "package main\n\nimport \"fmt\"\n");
+        assert!(config.wrappers["go"].epilogue.is_empty());
+        assert_eq!(config.wrappers["rust"], Wrapper {
+            prelude: "fn main() {".to_string(),
+            epilogue: "}".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_load_literate_config_aliases() {
+        let temp_dir = TempDir::new().unwrap();
+        std::fs::write(temp_dir.path().join(".literate.toml"), "[aliases]
+golang = \"go\"\n").unwrap();
+
+        let config = load_literate_config(temp_dir.path());
+        assert_eq!(config.aliases["golang"], "go");
     }
 }
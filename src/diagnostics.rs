@@ -0,0 +1,114 @@
+use crate::position::PositionMapper;
+use tower_lsp::lsp_types::{Diagnostic, Url};
+
+/// Map diagnostics published for a virtual document back to markdown coordinates
+///
+/// Diagnostics that start or end outside any code block (e.g. on the blank separator
+/// lines between blocks) are dropped. Related information pointing into the virtual
+/// document is rewritten to the markdown URI; entries pointing at other files are kept as-is.
+/// A chunk expanded in several places yields the same diagnostic once per expansion,
+/// so duplicates are dropped after mapping.
+pub fn map_diagnostics(
+    diagnostics: Vec<Diagnostic>,
+    mapper: &PositionMapper,
+    virtual_uri: &Url,
+    markdown_uri: &Url,
+) -> Vec<Diagnostic> {
+    let mut mapped: Vec<Diagnostic> = Vec::new();
+    let diagnostics = diagnostics
+        .into_iter()
+        .filter_map(|mut diagnostic| {
+            diagnostic.range = mapper.virtual_range_to_markdown(diagnostic.range)?;
+
+            if let Some(related) = diagnostic.related_information.take() {
+                let mapped: Vec<_> = related
+                    .into_iter()
+                    .filter_map(|mut info| {
+                        if &info.location.uri == virtual_uri {
+                            info.location = mapper.map_location(info.location, markdown_uri.clone())?;
+                        }
+                        Some(info)
+                    })
+                    .collect();
+                diagnostic.related_information = Some(mapped);
+            }
+
+            Some(diagnostic)
+        });
+    for diagnostic in diagnostics {
+        if !mapped.contains(&diagnostic) {
+            mapped.push(diagnostic);
+        }
+    }
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_doc::build_virtual_document;
+    use tower_lsp::lsp_types::{DiagnosticRelatedInformation, Location, Position, Range};
+
+    fn diagnostic(start: (u32, u32), end: (u32, u32)) -> Diagnostic {
+        Diagnostic::new_simple(
+            Range {
+                start: Position { line: start.0, character: start.1 },
+                end: Position { line: end.0, character: end.1 },
+            },
+            "error".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_map_diagnostics_to_markdown() {
+        let markdown = "# Forth\n\n```forth\n: square ( n -- n ) dup * ;\n```\n\n```forth\n5 square .\n```\n";
+        let vdoc = build_virtual_document(markdown, "forth");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let virtual_uri = Url::parse("file:///project/src/book.forth").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+
+        let mapped = map_diagnostics(
+            vec![diagnostic((0, 2), (0, 8)), diagnostic((1, 0), (1, 0)), diagnostic((2, 2), (3, 0))],
+            &mapper,
+            &virtual_uri,
+            &markdown_uri,
+        );
+
+        // The diagnostic on the separator line is dropped
+        assert_eq!(mapped.len(), 2);
+        assert_eq!(mapped[0].range.start, Position { line: 3, character: 2 });
+        assert_eq!(mapped[0].range.end, Position { line: 3, character: 8 });
+        assert_eq!(mapped[1].range.start, Position { line: 7, character: 2 });
+        assert_eq!(mapped[1].range.end, Position { line: 8, character: 0 });
+    }
+
+    #[test]
+    fn test_map_related_information() {
+        let markdown = "```forth\n: square dup * ;\n```\n";
+        let vdoc = build_virtual_document(markdown, "forth");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let virtual_uri = Url::parse("file:///project/src/book.forth").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+        let other_uri = Url::parse("file:///usr/lib/forth/core.fth").unwrap();
+
+        let mut diag = diagnostic((0, 0), (0, 1));
+        let location = |uri: &Url| Location {
+            uri: uri.clone(),
+            range: Range {
+                start: Position { line: 0, character: 2 },
+                end: Position { line: 0, character: 8 },
+            },
+        };
+        diag.related_information = Some(vec![
+            DiagnosticRelatedInformation { location: location(&virtual_uri), message: "here".to_string() },
+            DiagnosticRelatedInformation { location: location(&other_uri), message: "there".to_string() },
+        ]);
+
+        let mapped = map_diagnostics(vec![diag], &mapper, &virtual_uri, &markdown_uri);
+        let related = mapped[0].related_information.as_ref().unwrap();
+        assert_eq!(related[0].location.uri, markdown_uri);
+        assert_eq!(related[0].location.range.start.line, 1);
+        assert_eq!(related[1].location.uri, other_uri);
+        assert_eq!(related[1].location.range.start.line, 0);
+    }
+}
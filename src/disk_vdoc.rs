@@ -31,30 +31,12 @@ impl DiskVirtualDoc {
         extension: &str,
         content: String,
     ) -> Result<Self> {
-        // Get the basename without extension
-        let basename = markdown_filename
-            .rsplit_once('.')
-            .map(|(name, _)| name)
-            .unwrap_or(markdown_filename);
-
-        // Construct output directory path
-        let output_path = if output_dir.starts_with('/') {
-            // Absolute path
-            PathBuf::from(output_dir)
-        } else if output_dir.starts_with("./") || output_dir.starts_with("../") {
-            // Relative path
-            project_root.join(output_dir)
-        } else {
-            // Treat as relative
-            project_root.join(output_dir)
-        };
+        let file_path = Self::output_path(project_root, output_dir, markdown_filename, extension);
 
         // Create output directory if it doesn't exist
-        std::fs::create_dir_all(&output_path)?;
-
-        // Generate filename: {basename}.{extension}
-        let filename = format!("{}.{}", basename, extension);
-        let file_path = output_path.join(&filename);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
 
         // Write file to disk
         debug!(
@@ -71,12 +53,72 @@ impl DiskVirtualDoc {
         })
     }
 
+    /// Path a virtual document is written to: `{output_dir}/{basename}.{extension}`
+    pub fn output_path(project_root: &Path, output_dir: &str, markdown_filename: &str, extension: &str) -> PathBuf {
+        // Get the basename without extension
+        let basename = match markdown_filename.rsplit_once('.') {
+            Some((name, ext)) if !ext.contains('/') => name,
+            _ => markdown_filename,
+        };
+
+        // Construct output directory path
+        let output_path = if output_dir.starts_with('/') {
+            // Absolute path
+            PathBuf::from(output_dir)
+        } else if output_dir.starts_with("./") || output_dir.starts_with("../") {
+            // Relative path
+            project_root.join(output_dir)
+        } else {
+            // Treat as relative
+            project_root.join(output_dir)
+        };
+
+        // Generate filename: {basename}.{extension}
+        output_path.join(format!("{}.{}", basename, extension))
+    }
+
     /// Convert file path to file:// URI
     pub fn to_uri(&self) -> String {
         format!("file://{}", self.file_path.display())
     }
 }
 
+/// Markdown file name that a document's virtual documents are named after: its path
+/// relative to the project root
+///
+/// Documents with the same name in different directories (`a/README.md`, `b/README.md`)
+/// get their own virtual documents (`a/README.rs`, `b/README.rs`) instead of sharing one.
+pub fn document_filename(project_root: &Path, path: &Path) -> String {
+    let relative = match path.strip_prefix(project_root) {
+        Ok(relative) => relative,
+        Err(_) => Path::new(path.file_name().unwrap_or(path.as_os_str())),
+    };
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Markdown file name to derive a session's virtual document name from
+///
+/// The default session keeps the name of the markdown file (`book.md` -> `book.rs`);
+/// other sessions get their own file (`book.md`, session `server` -> `book.server.rs`).
+pub fn session_filename(markdown_filename: &str, session: Option<&str>) -> String {
+    let session = match session {
+        Some(session) => session,
+        None => return markdown_filename.to_string(),
+    };
+    let session: String = session
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    match markdown_filename.rsplit_once('.') {
+        Some((stem, ext)) if !ext.contains('/') => format!("{}.{}.{}", stem, session, ext),
+        _ => format!("{}.{}", markdown_filename, session),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,4 +184,27 @@ mod tests {
 
         assert!(project_root.join("src").exists());
     }
+
+    #[test]
+    fn test_session_filename() {
+        assert_eq!(session_filename("book.md", None), "book.md");
+        assert_eq!(session_filename("book.md", Some("server")), "book.server.md");
+        assert_eq!(session_filename("book.md", Some("src/lib.rs")), "book.src_lib_rs.md");
+        assert_eq!(session_filename("v1.2/book", Some("server")), "v1.2/book.server");
+    }
+
+    #[test]
+    fn test_same_named_documents_get_their_own_files() {
+        let root = Path::new("/project");
+        let a = document_filename(root, Path::new("/project/docs/a/README.md"));
+        let b = document_filename(root, Path::new("/project/docs/b/README.md"));
+        assert_eq!(a, "docs/a/README.md");
+        assert_eq!(document_filename(root, Path::new("/project/book.md")), "book.md");
+        assert_eq!(document_filename(root, Path::new("/elsewhere/book.md")), "book.md");
+
+        let a = DiskVirtualDoc::output_path(root, "./src", &a, "rs");
+        let b = DiskVirtualDoc::output_path(root, "./src", &b, "rs");
+        assert_eq!(a, PathBuf::from("/project/src/docs/a/README.rs"));
+        assert_ne!(a, b);
+    }
 }
@@ -0,0 +1,258 @@
+use crate::config::{default_output_dir, find_project_root, load_literate_config, LanguageAliases, Wrapper};
+use crate::fence::Fence;
+use crate::format::DocumentFormat;
+use crate::position::PositionEncoding;
+use crate::virtual_doc::{build_session_document, VirtualDocument};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tower_lsp::lsp_types::{Position, TextDocumentContentChangeEvent, Url};
+
+/// Convert an LSP position to a byte offset, clamped to the text
+pub fn position_to_offset(text: &str, position: Position, encoding: PositionEncoding) -> usize {
+    let mut line_start = 0;
+    for _ in 0..position.line {
+        match text[line_start..].find('\n') {
+            Some(i) => line_start += i + 1,
+            None => return text.len(),
+        }
+    }
+
+    let line = &text[line_start..];
+    let line = &line[..line.find('\n').unwrap_or(line.len())];
+    let line = line.strip_suffix('\r').unwrap_or(line);
+
+    line_start + encoding.byte_offset(line, position.character).min(line.len())
+}
+
+/// An open literate document
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub text: String,
+    pub version: i32,
+    /// How code blocks are written, from the file extension
+    pub format: DocumentFormat,
+    /// Project root detected from the document path, if it is a local file
+    pub project_root: Option<PathBuf>,
+    /// Where virtual documents are written, relative to the project root
+    pub output_dir: String,
+    /// Prelude and epilogue per language, from `.literate.toml`
+    pub wrappers: HashMap<String, Wrapper>,
+    /// Language names of code blocks, from the language config and `.literate.toml`
+    ///
+    /// Shared with every document whose project adds no aliases of its own.
+    pub aliases: Arc<LanguageAliases>,
+}
+
+impl Document {
+    /// Create a document, detecting its project root and `.literate.toml` settings from the URI
+    ///
+    /// `aliases` are the language config's, built once per server or command.
+    pub fn new(uri: &Url, text: String, version: i32, aliases: &Arc<LanguageAliases>) -> Self {
+        let mut aliases = Arc::clone(aliases);
+        let (project_root, output_dir, wrappers) = match uri.to_file_path() {
+            Ok(path) => {
+                let project_root = find_project_root(&path);
+                let literate_config = load_literate_config(&project_root);
+                if !literate_config.aliases.is_empty() {
+                    Arc::make_mut(&mut aliases).extend(&literate_config.aliases);
+                }
+                (Some(project_root), literate_config.literate.output_dir, literate_config.wrappers)
+            }
+            Err(_) => (None, default_output_dir(), HashMap::new()),
+        };
+        // Wrappers may be keyed by any name of their language
+        let wrappers = wrappers
+            .into_iter()
+            .map(|(lang, wrapper)| (aliases.resolve(&lang).to_string(), wrapper))
+            .collect();
+
+        Document {
+            text,
+            version,
+            format: DocumentFormat::of(uri),
+            project_root,
+            output_dir,
+            wrappers,
+            aliases,
+        }
+    }
+
+    /// Scan the code blocks of the document
+    ///
+    /// Scanning parses the whole text, so it is done once per change and the blocks are
+    /// passed to everything that builds on them.
+    pub fn fences(&self) -> Vec<Fence> {
+        self.format.scan(&self.text)
+    }
+
+    /// Build the virtual document of a (language, session) group from the document's
+    /// `fences`, with the language's wrapper
+    pub fn virtual_document(&self, fences: &[Fence], lang: &str, session: Option<&str>) -> VirtualDocument {
+        let vdoc = build_session_document(fences, &self.aliases, lang, session);
+        match self.wrappers.get(lang) {
+            Some(wrapper) => vdoc.wrap(wrapper),
+            None => vdoc,
+        }
+    }
+
+    /// Apply a content change: a range edit, or a full replacement when no range is given
+    pub fn apply_change(&mut self, change: &TextDocumentContentChangeEvent, encoding: PositionEncoding) {
+        match change.range {
+            Some(range) => {
+                let start = position_to_offset(&self.text, range.start, encoding);
+                let end = position_to_offset(&self.text, range.end, encoding).max(start);
+                self.text.replace_range(start..end, &change.text);
+            }
+            None => self.text = change.text.clone(),
+        }
+    }
+}
+
+/// All open documents, keyed by URI
+#[derive(Debug, Default)]
+pub struct DocumentStore {
+    documents: HashMap<Url, Document>,
+    /// Encoding of the columns in the editor's range edits
+    position_encoding: PositionEncoding,
+}
+
+impl DocumentStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the position encoding negotiated with the editor
+    pub fn set_position_encoding(&mut self, encoding: PositionEncoding) {
+        self.position_encoding = encoding;
+    }
+
+    /// Track a newly opened document, replacing any previous state for the URI
+    pub fn open(&mut self, uri: Url, document: Document) {
+        self.documents.insert(uri, document);
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+
+    /// Apply a batch of content changes, in order, to an open document
+    ///
+    /// Returns false if the document is not open.
+    pub fn apply_changes(
+        &mut self,
+        uri: &Url,
+        changes: &[TextDocumentContentChangeEvent],
+        version: i32,
+    ) -> bool {
+        match self.documents.get_mut(uri) {
+            Some(doc) => {
+                for change in changes {
+                    doc.apply_change(change, self.position_encoding);
+                }
+                doc.version = version;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop tracking a document, returning its last state
+    pub fn close(&mut self, uri: &Url) -> Option<Document> {
+        self.documents.remove(uri)
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn document(text: &str) -> Document {
+        Document {
+            text: text.to_string(),
+            version: 1,
+            format: DocumentFormat::Markdown,
+            project_root: None,
+            output_dir: default_output_dir(),
+            wrappers: HashMap::new(),
+            aliases: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_documents_are_independent() {
+        let mut store = DocumentStore::new();
+        let intro = Url::parse("file:///book/intro.md").unwrap();
+        let chapter = Url::parse("file:///book/chapter1.md").unwrap();
+
+        store.open(intro.clone(), document("intro"));
+        store.open(chapter.clone(), document("chapter"));
+        assert_eq!(store.len(), 2);
+
+        let full = |text: &str| TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: text.to_string(),
+        };
+        assert!(store.apply_changes(&chapter, &[full("chapter v2")], 2));
+        assert_eq!(store.get(&intro).unwrap().text, "intro");
+        assert_eq!(store.get(&chapter).unwrap().text, "chapter v2");
+        assert_eq!(store.get(&chapter).unwrap().version, 2);
+
+        assert!(store.close(&intro).is_some());
+        assert!(store.get(&intro).is_none());
+        assert!(!store.apply_changes(&intro, &[full("gone")], 3));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_position_to_offset_counts_utf16() {
+        let text = "a😀b\nsecond\n";
+        let utf16 = PositionEncoding::Utf16;
+        // The emoji is two UTF-16 code units but four bytes
+        assert_eq!(position_to_offset(text, Position::new(0, 3), utf16), 5);
+        assert_eq!(position_to_offset(text, Position::new(1, 2), utf16), 9);
+        // Columns past the end of a line clamp to the line end
+        assert_eq!(position_to_offset(text, Position::new(0, 40), utf16), 6);
+        assert_eq!(position_to_offset(text, Position::new(9, 0), utf16), text.len());
+        // The same character in the other encodings
+        assert_eq!(position_to_offset(text, Position::new(0, 5), PositionEncoding::Utf8), 5);
+        assert_eq!(position_to_offset(text, Position::new(0, 2), PositionEncoding::Utf32), 5);
+    }
+
+    #[test]
+    fn test_apply_incremental_changes() {
+        let mut store = DocumentStore::new();
+        let uri = Url::parse("file:///book/intro.md").unwrap();
+        store.open(uri.clone(), document("```rust\nfn a() {}\n```\n"));
+
+        let change = |range: Option<(u32, u32, u32, u32)>, text: &str| TextDocumentContentChangeEvent {
+            range: range.map(|(sl, sc, el, ec)| tower_lsp::lsp_types::Range::new(
+                Position::new(sl, sc),
+                Position::new(el, ec),
+            )),
+            range_length: None,
+            text: text.to_string(),
+        };
+
+        assert!(store.apply_changes(
+            &uri,
+            &[change(Some((1, 3, 1, 4)), "square"), change(Some((1, 14, 1, 14)), "\nfn b() {}")],
+            2,
+        ));
+        let doc = store.get(&uri).unwrap();
+        assert_eq!(doc.text, "```rust\nfn square() {}\nfn b() {}\n```\n");
+        assert_eq!(doc.version, 2);
+
+        assert!(store.apply_changes(&uri, &[change(None, "replaced")], 3));
+        assert_eq!(store.get(&uri).unwrap().text, "replaced");
+    }
+}
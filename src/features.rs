@@ -0,0 +1,174 @@
+use serde_json::{json, Value};
+
+/// An LSP feature that `only-features` / `except-features` give to some of a
+/// language's servers, with Helix's names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feature {
+    Format,
+    GotoDefinition,
+    GotoReference,
+    Hover,
+    Completion,
+    CodeAction,
+    DocumentSymbols,
+    Diagnostics,
+    RenameSymbol,
+}
+
+/// How the answers of a language's servers to one request become one answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Combine {
+    /// Only the first server with the feature is asked
+    First,
+    /// Every server is asked; the first answer with content wins
+    FirstNonEmpty,
+    /// Every server is asked and the answers are concatenated
+    Merge,
+}
+
+impl Feature {
+    /// Feature of a request, and the capability a server advertises it with
+    pub fn of_method(method: &str) -> Option<(Feature, &'static str)> {
+        let routed = match method {
+            "textDocument/formatting" => (Feature::Format, "documentFormattingProvider"),
+            "textDocument/rangeFormatting" => (Feature::Format, "documentRangeFormattingProvider"),
+            "textDocument/definition" => (Feature::GotoDefinition, "definitionProvider"),
+            "textDocument/references" => (Feature::GotoReference, "referencesProvider"),
+            "textDocument/hover" => (Feature::Hover, "hoverProvider"),
+            "textDocument/completion" => (Feature::Completion, "completionProvider"),
+            "textDocument/codeAction" => (Feature::CodeAction, "codeActionProvider"),
+            "textDocument/documentSymbol" => (Feature::DocumentSymbols, "documentSymbolProvider"),
+            "textDocument/prepareRename" | "textDocument/rename" => (Feature::RenameSymbol, "renameProvider"),
+            _ => return None,
+        };
+        Some(routed)
+    }
+
+    /// Name of the feature in `languages.toml`
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::Format => "format",
+            Feature::GotoDefinition => "goto-definition",
+            Feature::GotoReference => "goto-reference",
+            Feature::Hover => "hover",
+            Feature::Completion => "completion",
+            Feature::CodeAction => "code-action",
+            Feature::DocumentSymbols => "document-symbols",
+            Feature::Diagnostics => "diagnostics",
+            Feature::RenameSymbol => "rename-symbol",
+        }
+    }
+
+    /// How answers to the feature's requests are combined
+    ///
+    /// Edits from two servers would conflict, so formatting and rename go to one server.
+    pub fn combine(self) -> Combine {
+        match self {
+            Feature::Completion | Feature::CodeAction | Feature::Diagnostics => Combine::Merge,
+            Feature::Hover | Feature::GotoDefinition => Combine::FirstNonEmpty,
+            _ => Combine::First,
+        }
+    }
+}
+
+/// Whether a server's capabilities advertise a provider
+///
+/// A server whose capabilities are not known yet is assumed to have it.
+pub fn advertises(capabilities: Option<&Value>, capability: &str) -> bool {
+    match capabilities {
+        Some(capabilities) => !matches!(capabilities.get(capability), None | Some(Value::Null) | Some(Value::Bool(false))),
+        None => true,
+    }
+}
+
+/// Whether a result carries nothing: `null`, an empty list, or a hover without contents
+fn is_empty(result: &Value) -> bool {
+    match result {
+        Value::Null => true,
+        Value::Array(items) => items.is_empty(),
+        Value::Object(map) => match map.get("contents") {
+            Some(Value::String(text)) => text.is_empty(),
+            Some(Value::Array(items)) => items.is_empty(),
+            Some(Value::Object(markup)) => markup.get("value").and_then(Value::as_str) == Some(""),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Combine the results of several servers, in the order of the language's server list
+pub fn combine_results(feature: Feature, results: Vec<Value>) -> Value {
+    match feature.combine() {
+        Combine::First => results.into_iter().next().unwrap_or(Value::Null),
+        Combine::FirstNonEmpty => results.into_iter().find(|result| !is_empty(result)).unwrap_or(Value::Null),
+        Combine::Merge => merge_results(results),
+    }
+}
+
+/// Concatenate lists of items; completion lists stay incomplete if any of them is
+fn merge_results(results: Vec<Value>) -> Value {
+    let mut results: Vec<Value> = results.into_iter().filter(|result| !result.is_null()).collect();
+    if results.len() <= 1 {
+        return results.pop().unwrap_or(Value::Null);
+    }
+
+    let mut items = Vec::new();
+    let mut is_list = false;
+    let mut incomplete = false;
+    for result in results {
+        match result {
+            Value::Array(more) => items.extend(more),
+            Value::Object(mut list) => {
+                is_list = true;
+                incomplete |= list.get("isIncomplete").and_then(Value::as_bool).unwrap_or(false);
+                if let Some(Value::Array(more)) = list.remove("items") {
+                    items.extend(more);
+                }
+            }
+            _ => {}
+        }
+    }
+    if is_list {
+        json!({ "isIncomplete": incomplete, "items": items })
+    } else {
+        Value::Array(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_non_empty_hover() {
+        let results = vec![json!(null), json!({ "contents": "" }), json!({ "contents": "fn a()" }), json!({ "contents": "b" })];
+        assert_eq!(combine_results(Feature::Hover, results), json!({ "contents": "fn a()" }));
+        assert_eq!(combine_results(Feature::GotoDefinition, vec![json!([]), json!(null)]), json!(null));
+        assert_eq!(combine_results(Feature::RenameSymbol, vec![json!({ "changes": {} }), json!(null)]), json!({ "changes": {} }));
+    }
+
+    #[test]
+    fn test_merged_completions_and_actions() {
+        let results = vec![json!([{ "label": "a" }]), json!(null), json!({ "isIncomplete": true, "items": [{ "label": "b" }] })];
+        assert_eq!(
+            combine_results(Feature::Completion, results),
+            json!({ "isIncomplete": true, "items": [{ "label": "a" }, { "label": "b" }] })
+        );
+
+        let actions = vec![json!([{ "title": "Import os" }]), json!([{ "title": "Fix all" }])];
+        assert_eq!(combine_results(Feature::CodeAction, actions), json!([{ "title": "Import os" }, { "title": "Fix all" }]));
+        // A single answer is passed through as it is
+        assert_eq!(combine_results(Feature::Completion, vec![json!({ "isIncomplete": false, "items": [] })]), json!({ "isIncomplete": false, "items": [] }));
+    }
+
+    #[test]
+    fn test_advertised_capabilities() {
+        let capabilities = json!({ "hoverProvider": true, "renameProvider": { "prepareProvider": true }, "definitionProvider": false });
+        assert!(advertises(Some(&capabilities), "hoverProvider"));
+        assert!(advertises(Some(&capabilities), "renameProvider"));
+        assert!(!advertises(Some(&capabilities), "definitionProvider"));
+        assert!(!advertises(Some(&capabilities), "completionProvider"));
+        assert!(advertises(None, "completionProvider"));
+        assert_eq!(Feature::of_method("textDocument/rangeFormatting"), Some((Feature::Format, "documentRangeFormattingProvider")));
+    }
+}
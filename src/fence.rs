@@ -0,0 +1,464 @@
+use crate::attributes::{chunk_header_options, is_executable_chunk, parse_info, BlockAttributes};
+use crate::format::{BlockExtractor, DocumentFormat};
+use crate::position::PositionEncoding;
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use std::collections::HashMap;
+
+/// Where a content line sits in the document, for code that is not simply the rest
+/// of its line after an ASCII prefix: code sharing a line with other text, or escaped
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineSource {
+    /// Line of the document holding the code
+    pub line: usize,
+    /// Full text of that line
+    pub text: String,
+    /// Where each run of code starts, as (byte in the code line, byte in `text`), and
+    /// where the code ends if its last run is an escape sequence. A run is either literal
+    /// text or a single escape sequence.
+    pub anchors: Vec<(usize, usize)>,
+}
+
+impl LineSource {
+    /// A line whose code starts `prefix` bytes in, with no escapes
+    pub fn new(line: usize, text: &str, prefix: usize) -> Self {
+        LineSource {
+            line,
+            text: text.to_string(),
+            anchors: vec![(0, prefix)],
+        }
+    }
+
+    /// Byte in the document line of a byte in the code line
+    pub fn source_byte(&self, code: usize) -> usize {
+        let run = self.anchors.partition_point(|&(c, _)| c <= code).saturating_sub(1);
+        match self.anchors.get(run) {
+            Some(&(c, s)) => s + code.saturating_sub(c),
+            None => code,
+        }
+    }
+
+    /// Byte in the code line of a byte in the document line
+    ///
+    /// Bytes before the code map to its start, and bytes inside an escape sequence to
+    /// the character it stands for.
+    pub fn code_byte(&self, source: usize) -> usize {
+        let run = self.anchors.partition_point(|&(_, s)| s <= source);
+        if run == 0 {
+            return 0;
+        }
+        let (c, s) = self.anchors[run - 1];
+        match self.anchors.get(run) {
+            Some(&(next_c, next_s)) if next_c - c != next_s - s => c,
+            _ => c + (source - s),
+        }
+    }
+
+    /// Drop the first `width` bytes of the code line
+    pub fn strip_code_prefix(&mut self, width: usize) {
+        let start = (0, self.source_byte(width));
+        self.anchors.retain(|&(c, _)| c > width);
+        for (c, _) in &mut self.anchors {
+            *c -= width;
+        }
+        self.anchors.insert(0, start);
+    }
+}
+
+/// A fenced code block as located by the CommonMark parser, or a block of another
+/// document format in the same shape
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fence {
+    /// Fence character: '`' or '~' in markdown; other formats use the first character
+    /// of what opens their blocks
+    pub fence_char: char,
+    /// Number of fence characters on the opening line
+    pub fence_len: usize,
+    /// Column of the opening fence, including any list or blockquote prefix
+    pub indent: usize,
+    /// Full info string after the opening fence
+    pub info: String,
+    /// Line with the opening fence
+    pub start_line: usize,
+    /// Line with the closing fence, or one past the last content line if unclosed
+    pub end_line: usize,
+    /// Content lines with container prefixes and fence indentation stripped
+    pub lines: Vec<String>,
+    /// Byte width of what was stripped from each content line
+    pub prefixes: Vec<usize>,
+    /// Where each content line sits, when they are not simply the lines after the opening one
+    pub sources: Vec<LineSource>,
+    /// Session set by a `<!-- literate: session=... -->` directive in an enclosing heading section
+    pub scope_session: Option<String>,
+    /// Quarto / R Markdown chunk options from the header and the leading `#| key: value`
+    /// lines, which are not part of `lines`, or the header arguments of an Org block
+    pub options: Vec<(String, String)>,
+}
+
+impl Fence {
+    /// Language of the block: the first word of the info string, or the first pandoc class
+    pub fn lang(&self) -> &str {
+        parse_info(&self.info).0
+    }
+
+    /// Attributes following the language in the info string, and from cell options
+    pub fn attributes(&self) -> BlockAttributes {
+        let mut attributes = parse_info(&self.info).1;
+        for (key, value) in &self.options {
+            attributes.apply_option(key, value);
+        }
+        attributes
+    }
+
+    /// Session the block belongs to: its `session` attribute, the enclosing heading's
+    /// directive, or its `file` attribute, in that order. `None` is the default session.
+    pub fn session(&self) -> Option<String> {
+        let attributes = self.attributes();
+        attributes.session.or_else(|| self.scope_session.clone()).or(attributes.file)
+    }
+
+    /// First line of actual content
+    pub fn content_start(&self) -> usize {
+        self.sources.first().map_or(self.start_line + 1, |source| source.line)
+    }
+
+    /// Last line of actual content
+    pub fn content_end(&self) -> usize {
+        self.sources.last().map_or(self.end_line.saturating_sub(1), |source| source.line)
+    }
+
+    /// Line of the document holding a content line, by offset from the first
+    pub fn content_line(&self, offset: usize) -> usize {
+        self.sources.get(offset).map_or(self.content_start() + offset, |source| source.line)
+    }
+
+    /// Column in the document of a byte offset into a content line
+    pub fn column(&self, offset: usize, byte: usize, encoding: PositionEncoding) -> u32 {
+        match self.sources.get(offset) {
+            Some(source) => encoding.column(&source.text, source.source_byte(byte)),
+            None => {
+                let prefix = self.prefixes.get(offset).copied().unwrap_or(self.indent);
+                let line = self.lines.get(offset).map_or("", String::as_str);
+                prefix as u32 + encoding.column(line, byte)
+            }
+        }
+    }
+}
+
+/// Finds the fenced code blocks of markdown documents
+pub struct MarkdownExtractor;
+
+impl BlockExtractor for MarkdownExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_fences(text)
+    }
+}
+
+/// Byte offsets of the start of every line
+pub(crate) fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(i, _)| i + 1))
+        .collect()
+}
+
+/// Stripped prefix width and text of each content line of the open fence, by line
+type LineTexts = HashMap<usize, (usize, String)>;
+
+/// Parse a `<!-- literate: session=name -->` directive from raw HTML
+fn session_directive(html: &str) -> Option<String> {
+    let comment = html.trim().strip_prefix("<!--")?.strip_suffix("-->")?;
+    let directive = comment.trim().strip_prefix("literate:")?;
+    let value = directive.trim().strip_prefix("session")?.trim_start().strip_prefix('=')?;
+    let value = value.trim().trim_matches('"');
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Width of a line's leading spaces and tabs
+pub(crate) fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Line index containing the given byte offset
+pub(crate) fn line_of(starts: &[usize], offset: usize) -> usize {
+    starts.partition_point(|&s| s <= offset).saturating_sub(1)
+}
+
+/// Check whether a raw markdown line closes a fence of the given char and length
+fn is_closing_fence(line: &str, fence_char: char, fence_len: usize) -> bool {
+    let trimmed = line.trim_start_matches([' ', '\t', '>']);
+    let count = trimmed.chars().take_while(|&c| c == fence_char).count();
+    count >= fence_len && trimmed[count * fence_char.len_utf8()..].trim().is_empty()
+}
+
+/// Text after the marker of a Quarto cell option line: `#|`, or `//|` and `--|` in
+/// languages where `#` is not a comment
+fn cell_option(line: &str) -> Option<&str> {
+    ["#|", "//|", "--|"].iter().find_map(|marker| line.strip_prefix(marker))
+}
+
+/// Move the leading cell option lines of an executable chunk into `options`
+///
+/// The remaining lines no longer start right after the opening fence, so each records
+/// its source line. Option lines without a `key:` (YAML continuations) are dropped too.
+fn strip_cell_options(fence: &mut Fence, source_lines: &[&str]) {
+    let count = fence.lines.iter().take_while(|line| cell_option(line).is_some()).count();
+    if count == 0 {
+        return;
+    }
+    let first = fence.content_start() + count;
+    fence.options = fence
+        .lines
+        .drain(..count)
+        .filter_map(|line| {
+            let (key, value) = cell_option(&line)?.split_once(':')?;
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect();
+    fence.prefixes.drain(..count);
+    fence.sources = fence
+        .prefixes
+        .iter()
+        .enumerate()
+        .map(|(offset, &prefix)| {
+            let line = first + offset;
+            LineSource::new(line, source_lines[line].trim_end_matches('\r'), prefix)
+        })
+        .collect();
+}
+
+/// Find every fenced code block in a markdown document
+///
+/// Follows CommonMark: `~~~` and backtick fences, fences longer than three characters
+/// (so a four-backtick block can wrap a triple-backtick example), and fences nested in
+/// list items and blockquotes. Indented (non-fenced) code blocks are ignored. Quarto and
+/// R Markdown chunks (```` ```{python} ````) have their cell options split off.
+pub fn scan_fences(markdown: &str) -> Vec<Fence> {
+    let starts = line_starts(markdown);
+    let source_lines: Vec<&str> = markdown.split('\n').collect();
+    let mut fences = Vec::new();
+    let mut current: Option<(Fence, LineTexts)> = None;
+    // Open heading sections (level, session directive); level 0 is the document itself
+    let mut scopes: Vec<(usize, Option<String>)> = vec![(0, None)];
+
+    for (event, range) in Parser::new_ext(markdown, Options::empty()).into_offset_iter() {
+        match event {
+            Event::Start(Tag::Heading { level, .. }) => {
+                let level = level as usize;
+                scopes.retain(|(open, _)| *open < level);
+                scopes.push((level, None));
+            }
+            Event::Html(html) | Event::InlineHtml(html) if current.is_none() => {
+                if let Some(session) = session_directive(&html) {
+                    if let Some((_, scope)) = scopes.last_mut() {
+                        *scope = Some(session);
+                    }
+                }
+            }
+            Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(info))) => {
+                let start_line = line_of(&starts, range.start);
+                let fence_char = markdown[range.start..].chars().next().unwrap_or('`');
+                let fence_len = markdown[range.start..]
+                    .chars()
+                    .take_while(|&c| c == fence_char)
+                    .count();
+                let fence = Fence {
+                    fence_char,
+                    fence_len,
+                    indent: range.start - starts[start_line],
+                    info: info.trim().to_string(),
+                    start_line,
+                    end_line: start_line,
+                    lines: Vec::new(),
+                    prefixes: Vec::new(),
+                    sources: Vec::new(),
+                    scope_session: scopes.iter().rev().find_map(|(_, session)| session.clone()),
+                    options: Vec::new(),
+                };
+                current = Some((fence, HashMap::new()));
+            }
+            Event::Text(text) => {
+                if let Some((_, texts)) = current.as_mut() {
+                    // Inside containers the parser emits one event per line; at the top
+                    // level a single event may span several lines, which then carry no prefix
+                    let mut line = line_of(&starts, range.start);
+                    let mut prefix = range.start - starts[line];
+                    for piece in text.split_inclusive('\n') {
+                        let (_, entry) = texts.entry(line).or_insert((prefix, String::new()));
+                        entry.push_str(piece.trim_end_matches(['\n', '\r']));
+                        if piece.ends_with('\n') {
+                            line += 1;
+                            prefix = 0;
+                        }
+                    }
+                }
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                if let Some((mut fence, mut texts)) = current.take() {
+                    let last_line = line_of(&starts, range.end.saturating_sub(1).max(range.start));
+                    let closed = last_line > fence.start_line
+                        && !texts.contains_key(&last_line)
+                        && is_closing_fence(source_lines[last_line], fence.fence_char, fence.fence_len);
+                    fence.end_line = if closed { last_line } else { last_line + 1 };
+                    // Lines without text (blank in a quote or list item) are all prefix
+                    (fence.prefixes, fence.lines) = (fence.content_start()..fence.end_line)
+                        .map(|line| {
+                            texts.remove(&line).unwrap_or_else(|| {
+                                let raw = source_lines.get(line).copied().unwrap_or("");
+                                (raw.trim_end_matches('\r').len(), String::new())
+                            })
+                        })
+                        .unzip();
+                    if is_executable_chunk(&fence.info) {
+                        strip_cell_options(&mut fence, &source_lines);
+                        // Options in the header come before those on `#|` lines
+                        fence.options.splice(0..0, chunk_header_options(&fence.info));
+                    }
+                    fences.push(fence);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fences
+}
+
+/// Find the code block containing a line, fences included
+pub fn find_fence_at_line(text: &str, format: DocumentFormat, line: usize) -> Option<Fence> {
+    format
+        .scan(text)
+        .into_iter()
+        .find(|fence| line >= fence.start_line && line <= fence.end_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backtick_and_tilde_fences() {
+        let markdown = "```forth\n: square dup * ;\n```\n\n~~~ rust {ignore}\nfn main() {}\n~~~\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 2);
+
+        assert_eq!(fences[0].fence_char, '`');
+        assert_eq!(fences[0].lang(), "forth");
+        assert_eq!((fences[0].start_line, fences[0].end_line), (0, 2));
+        assert_eq!(fences[0].lines, vec![": square dup * ;"]);
+
+        assert_eq!(fences[1].fence_char, '~');
+        assert_eq!(fences[1].info, "rust {ignore}");
+        assert_eq!(fences[1].lang(), "rust");
+        assert_eq!((fences[1].start_line, fences[1].end_line), (4, 6));
+    }
+
+    #[test]
+    fn test_longer_fence_wraps_triple_backticks() {
+        let markdown = "````markdown\n```rust\nfn a() {}\n```\n````\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].fence_len, 4);
+        assert_eq!(fences[0].lang(), "markdown");
+        assert_eq!(fences[0].end_line, 4);
+        assert_eq!(fences[0].lines, vec!["```rust", "fn a() {}", "```"]);
+    }
+
+    #[test]
+    fn test_fence_in_list_item() {
+        let markdown = "- item\n\n  ```python\n  x = 1\n\n  if x:\n      y = 2\n  ```\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].indent, 2);
+        assert_eq!((fences[0].start_line, fences[0].end_line), (2, 7));
+        assert_eq!(fences[0].lines, vec!["x = 1", "", "if x:", "    y = 2"]);
+    }
+
+    #[test]
+    fn test_fence_in_blockquote() {
+        let markdown = "> ```rust\n> fn a() {}\n>   let x;\n> ```\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].indent, 2);
+        assert_eq!(fences[0].end_line, 3);
+        assert_eq!(fences[0].lines, vec!["fn a() {}", "  let x;"]);
+        assert_eq!(fences[0].prefixes, vec![2, 2]);
+    }
+
+    #[test]
+    fn test_heading_scoped_sessions() {
+        let markdown = "\
+# Tutorial
+
+```rust
+fn shared() {}
+```
+
+## The wrong way
+
+<!-- literate: session=wrong -->
+
+```rust
+fn main() { broken }
+```
+
+### Details
+
+```rust {session=aside}
+fn aside() {}
+```
+
+```rust
+fn detail() {}
+```
+
+## The right way
+
+```rust
+fn main() {}
+```
+";
+        let sessions: Vec<_> = scan_fences(markdown).iter().map(Fence::session).collect();
+        assert_eq!(
+            sessions,
+            vec![None, Some("wrong".to_string()), Some("aside".to_string()), Some("wrong".to_string()), None]
+        );
+        assert_eq!(session_directive("<!-- literate: session = \"a b\" -->"), Some("a b".to_string()));
+        assert_eq!(session_directive("<!-- a comment -->"), None);
+    }
+
+    #[test]
+    fn test_prefix_width_per_line() {
+        // Quote markers with and without a following space, and a blank quoted line
+        let markdown = "> ```python\n>x = 1\n>\n> if x:\n>     y = 2\n> ```\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences[0].lines, vec!["x = 1", "", "if x:", "    y = 2"]);
+        assert_eq!(fences[0].prefixes, vec![1, 1, 2, 2]);
+
+        // A list item line indented less than the fence keeps its own width
+        let markdown = "- item\n\n   ```sh\n   echo a\n  echo b\n   ```\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences[0].lines, vec!["echo a", "echo b"]);
+        assert_eq!(fences[0].prefixes, vec![3, 2]);
+
+        // Top-level blocks have no prefix
+        let fences = scan_fences("```rust\nfn a() {}\nfn b() {}\n```\n");
+        assert_eq!(fences[0].prefixes, vec![0, 0]);
+    }
+
+    #[test]
+    fn test_inline_backticks_are_not_fences() {
+        let markdown = "Use ```rust to open a block.\n\n```rust\nlet x = 1;\n```\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].start_line, 2);
+    }
+
+    #[test]
+    fn test_unclosed_fence_runs_to_end() {
+        let markdown = "text\n\n```rust\nlet x = 1;\nlet y = 2;\n";
+        let fences = scan_fences(markdown);
+        assert_eq!(fences.len(), 1);
+        assert_eq!(fences[0].end_line, 5);
+        assert_eq!(fences[0].lines, vec!["let x = 1;", "let y = 2;"]);
+    }
+}
@@ -0,0 +1,125 @@
+use crate::asciidoc::AsciidocExtractor;
+use crate::fence::{Fence, MarkdownExtractor};
+use crate::latex::LatexExtractor;
+use crate::notebook::NotebookExtractor;
+use crate::org::OrgExtractor;
+use crate::rst::RstExtractor;
+use crate::typst::TypstExtractor;
+use tower_lsp::lsp_types::Url;
+
+/// Get the language of the document based on file extension
+pub fn get_document_language(uri: &Url) -> Option<String> {
+    let path = uri.path();
+    let ext = path.rsplit('.').next()?.to_lowercase();
+    match ext.as_str() {
+        // Markdown variants
+        "md" | "markdown" | "mdown" | "mkdn" | "mdx" | "mmd" => Some("markdown".to_string()),
+        // Quarto and R Markdown
+        "qmd" | "rmd" => Some("markdown".to_string()),
+        // Typst
+        "typ" => Some("typst".to_string()),
+        // Org and reStructuredText
+        "org" => Some("org".to_string()),
+        "rst" | "rest" => Some("rst".to_string()),
+        // AsciiDoc and LaTeX
+        "adoc" | "asciidoc" => Some("asciidoc".to_string()),
+        "tex" | "ltx" => Some("latex".to_string()),
+        // Jupyter notebooks
+        "ipynb" => Some("jupyter".to_string()),
+        // Other languages
+        "go" => Some("go".to_string()),
+        "forth" | "fth" => Some("forth".to_string()),
+        _ => None,
+    }
+}
+
+/// Finds the code blocks of one document format
+///
+/// Every format yields its blocks as `Fence`s, so grouping, chunk expansion and position
+/// mapping work the same for all of them. A new format implements this trait and gets a
+/// `DocumentFormat` variant.
+pub trait BlockExtractor: Send + Sync {
+    /// Find every code block in a document, in order
+    fn extract(&self, text: &str) -> Vec<Fence>;
+}
+
+/// How code blocks are written in a literate document
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DocumentFormat {
+    /// Fenced code blocks; also used for documents of unknown type
+    #[default]
+    Markdown,
+    /// Raw blocks and `#raw` calls
+    Typst,
+    /// `#+begin_src` blocks
+    Org,
+    /// `code-block`, `code` and `sourcecode` directives
+    Rst,
+    /// `[source,lang]` blocks
+    Asciidoc,
+    /// `minted` and `lstlisting` environments
+    Latex,
+    /// Code cells of Jupyter notebooks
+    Notebook,
+}
+
+impl DocumentFormat {
+    /// Format of a document, from the language `get_document_language` detects
+    pub fn from_language(lang: Option<&str>) -> Self {
+        match lang {
+            Some("typst") => Self::Typst,
+            Some("org") => Self::Org,
+            Some("rst") => Self::Rst,
+            Some("asciidoc") => Self::Asciidoc,
+            Some("latex") => Self::Latex,
+            Some("jupyter") => Self::Notebook,
+            _ => Self::Markdown,
+        }
+    }
+
+    /// Format of the document at a URI
+    pub fn of(uri: &Url) -> Self {
+        Self::from_language(get_document_language(uri).as_deref())
+    }
+
+    /// The extractor finding this format's code blocks
+    pub fn extractor(self) -> &'static dyn BlockExtractor {
+        match self {
+            Self::Markdown => &MarkdownExtractor,
+            Self::Typst => &TypstExtractor,
+            Self::Org => &OrgExtractor,
+            Self::Rst => &RstExtractor,
+            Self::Asciidoc => &AsciidocExtractor,
+            Self::Latex => &LatexExtractor,
+            Self::Notebook => &NotebookExtractor,
+        }
+    }
+
+    /// Find every code block in a document of this format
+    pub fn scan(self, text: &str) -> Vec<Fence> {
+        self.extractor().extract(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_from_extension() {
+        let format = |path: &str| DocumentFormat::of(&Url::parse(&format!("file:///book/{}", path)).unwrap());
+        assert_eq!(format("intro.md"), DocumentFormat::Markdown);
+        assert_eq!(format("report.Rmd"), DocumentFormat::Markdown);
+        assert_eq!(format("paper.typ"), DocumentFormat::Typst);
+        assert_eq!(format("notes.ORG"), DocumentFormat::Org);
+        assert_eq!(format("guide.rst"), DocumentFormat::Rst);
+        assert_eq!(format("design.adoc"), DocumentFormat::Asciidoc);
+        assert_eq!(format("paper.tex"), DocumentFormat::Latex);
+        assert_eq!(format("analysis.ipynb"), DocumentFormat::Notebook);
+        assert_eq!(format("README"), DocumentFormat::Markdown);
+
+        let org = "#+begin_src rust\nfn a() {}\n#+end_src\n";
+        assert_eq!(DocumentFormat::Org.scan(org).len(), 1);
+        assert!(DocumentFormat::Markdown.scan(org).is_empty());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::fence::{indent_of, Fence};
+use crate::format::BlockExtractor;
+
+/// Finds the `minted` and `lstlisting` environments of LaTeX documents
+pub struct LatexExtractor;
+
+impl BlockExtractor for LatexExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_listings(text)
+    }
+}
+
+/// Text of a group opened by `open` at the start of `text`, and the rest after it
+fn group(text: &str, open: char, close: char) -> Option<(&str, &str)> {
+    let rest = text.trim_start().strip_prefix(open)?;
+    let mut depth = 0;
+    for (at, ch) in rest.char_indices() {
+        match ch {
+            _ if ch == open => depth += 1,
+            _ if ch == close && depth == 0 => return Some((&rest[..at], &rest[at + 1..])),
+            _ if ch == close => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Value of a `key=value` option in a comma-separated option list
+fn option<'a>(options: &'a str, key: &str) -> Option<&'a str> {
+    options.split(',').find_map(|option| {
+        let (name, value) = option.split_once('=')?;
+        (name.trim() == key).then(|| value.trim())
+    })
+}
+
+/// `lstlisting` dialects as (dialect, language, language of the code)
+///
+/// Most dialects are versions of their language; `[Sharp]C` is C#.
+const DIALECTS: &[(&str, &str, &str)] = &[
+    ("sharp", "c", "csharp"),
+    ("ansi", "c", "c"),
+    ("posix", "c", "c"),
+    ("ansi", "c++", "c++"),
+    ("gnu", "c++", "c++"),
+    ("iso", "c++", "c++"),
+    ("visual", "c++", "c++"),
+    ("77", "fortran", "fortran"),
+    ("90", "fortran", "fortran"),
+    ("95", "fortran", "fortran"),
+    ("03", "fortran", "fortran"),
+    ("08", "fortran", "fortran"),
+];
+
+/// Language of the code in a `[dialect]language` listing; unknown dialects have none
+fn dialect_language(dialect: &str, lang: &str) -> Option<&'static str> {
+    DIALECTS
+        .iter()
+        .find(|(known, known_lang, _)| known.eq_ignore_ascii_case(dialect.trim()) && known_lang.eq_ignore_ascii_case(lang.trim()))
+        .map(|(_, _, code)| *code)
+}
+
+/// Environment name, language and options of a `\begin{minted}[opts]{lang}` or
+/// `\begin{lstlisting}[language=lang, opts]` line
+fn begin(line: &str) -> Option<(&str, String, &str)> {
+    let (name, rest) = group(line.trim_start().strip_prefix("\\begin")?, '{', '}')?;
+    match name {
+        "minted" => {
+            let (options, rest) = group(rest, '[', ']').unwrap_or(("", rest));
+            let (lang, _) = group(rest, '{', '}')?;
+            Some((name, lang.trim().to_lowercase(), options))
+        }
+        "lstlisting" => {
+            let options = group(rest, '[', ']').map_or("", |(options, _)| options);
+            // `language={[Sharp]C}` names a dialect before the language
+            let lang = option(options, "language").unwrap_or_default();
+            let lang = lang.trim_matches(['{', '}']).trim();
+            let lang = match lang.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+                Some((dialect, lang)) => dialect_language(dialect, lang).unwrap_or_default().to_string(),
+                None => lang.to_lowercase(),
+            };
+            Some((name, lang, options))
+        }
+        _ => None,
+    }
+}
+
+/// Find the code listings of a LaTeX document
+///
+/// Listings are verbatim up to `\end{...}`. Indentation is kept, except what the
+/// environment itself removes: `gobble=N` characters, or the common indentation with
+/// minted's `autogobble`. Commented-out environments are skipped.
+pub fn scan_listings(text: &str) -> Vec<Fence> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut start_line = 0;
+    while start_line < lines.len() {
+        let (name, lang, options) = match begin(lines[start_line]) {
+            Some(begin) => begin,
+            None => {
+                start_line += 1;
+                continue;
+            }
+        };
+        let end_marker = format!("\\end{{{}}}", name);
+        let end_line = (start_line + 1..lines.len())
+            .find(|&line| lines[line].trim_start().starts_with(&end_marker))
+            .unwrap_or(lines.len());
+
+        let body = &lines[start_line + 1..end_line];
+        let gobble = match option(options, "gobble").and_then(|count| count.parse().ok()) {
+            Some(count) => count,
+            None if options.split(',').any(|option| option.trim() == "autogobble") => body
+                .iter()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| indent_of(line))
+                .min()
+                .unwrap_or(0),
+            None => 0,
+        };
+        let (prefixes, code) = body
+            .iter()
+            .map(|line| {
+                let line = line.trim_end_matches('\r');
+                let prefix = gobble.min(indent_of(line));
+                (prefix, line[prefix..].to_string())
+            })
+            .unzip();
+
+        blocks.push(Fence {
+            fence_char: '\\',
+            fence_len: 1,
+            indent: indent_of(lines[start_line]),
+            info: lang,
+            start_line,
+            end_line,
+            lines: code,
+            prefixes,
+            sources: Vec::new(),
+            scope_session: None,
+            options: Vec::new(),
+        });
+        start_line = end_line + 1;
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_minted_and_lstlisting() {
+        let text = "\
+\\section{Code}
+\\begin{minted}[linenos]{Python}
+def f():
+    return 1
+\\end{minted}
+% \\begin{minted}{rust}
+\\begin{lstlisting}[caption={Hello}, language={[ANSI]C}]
+int main(void) { return 0; }
+\\end{lstlisting}
+  \\begin{minted}[autogobble]{rust}
+    fn main() {
+        run();
+    }
+  \\end{minted}
+";
+        let blocks = scan_listings(text);
+        assert_eq!(blocks.len(), 3);
+
+        assert_eq!(blocks[0].lang(), "python");
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (1, 4));
+        assert_eq!(blocks[0].lines, vec!["def f():", "    return 1"]);
+
+        assert_eq!(blocks[1].lang(), "c");
+        assert_eq!(blocks[1].content_start(), 7);
+
+        assert_eq!(blocks[2].lang(), "rust");
+        assert_eq!(blocks[2].lines, vec!["fn main() {", "    run();", "}"]);
+        assert_eq!(blocks[2].prefixes, vec![4, 4, 4]);
+    }
+
+    #[test]
+    fn test_listings_dialects() {
+        let text = "\\begin{lstlisting}[language={[Sharp]C}]\nvar x = 1;\n\\end{lstlisting}\n\\begin{lstlisting}[language={[Objective]Caml}]\nlet x = 1\n\\end{lstlisting}\n";
+        let blocks = scan_listings(text);
+        assert_eq!(blocks[0].lang(), "csharp");
+        // An unknown dialect may be another language altogether, so it has none
+        assert_eq!(blocks[1].lang(), "");
+    }
+
+    #[test]
+    fn test_other_environments_are_not_code() {
+        let text = "\\begin{verbatim}\nx\n\\end{verbatim}\n\\begin{itemize}\n\\item a\n\\end{itemize}\n";
+        assert!(scan_listings(text).is_empty());
+    }
+}
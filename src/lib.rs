@@ -1,10 +1,28 @@
+pub mod asciidoc;
+pub mod attributes;
+pub mod check;
 pub mod child_lsp;
 pub mod child_lsp_init;
+pub mod chunks;
 pub mod config;
+pub mod diagnostics;
 pub mod disk_vdoc;
+pub mod document_store;
+pub mod features;
+pub mod fence;
+pub mod format;
 pub mod health;
+pub mod latex;
+pub mod notebook;
+pub mod notebook_sync;
+pub mod org;
 pub mod position;
 pub mod request_mapper;
+pub mod rst;
 pub mod server;
+pub mod sync;
+pub mod tangle;
+pub mod typst;
 pub mod virtual_doc;
+pub mod workspace_edit;
 pub mod utils;
@@ -1,6 +1,9 @@
+use literate_lsp::check::{self, CheckOptions};
 use literate_lsp::config::Config;
 use literate_lsp::health;
+use literate_lsp::notebook_sync::NotebookCapabilities;
 use literate_lsp::server::LiterateLsp;
+use literate_lsp::tangle;
 use tower_lsp::{LspService, Server};
 
 #[tokio::main]
@@ -22,6 +25,36 @@ async fn main() {
         return;
     }
 
+    // Write every virtual document without an editor: literate-lsp tangle [paths or globs...]
+    if args.len() > 1 && args[1] == "tangle" {
+        let config = Config::load_for_health_check();
+        let report = tangle::tangle(&config, &args[2..]);
+        println!("{}", report);
+        std::process::exit(report.exit_code());
+    }
+
+    // Type-check code blocks headlessly: literate-lsp check [--format text|json|sarif] [--timeout SECS] [--assume-clean] [paths...]
+    if args.len() > 1 && args[1] == "check" {
+        let options = match CheckOptions::parse(&args[2..]) {
+            Ok(options) => options,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        };
+        let config = Config::load_for_health_check();
+        let report = check::check(&config, &options).await;
+        print!("{}", report.render(options.format));
+        for error in &report.errors {
+            eprintln!("error: {}", error);
+        }
+        for unchecked in &report.unchecked {
+            eprintln!("unchecked: {}", unchecked);
+        }
+        eprintln!("{}", report.summary());
+        std::process::exit(report.exit_code());
+    }
+
     // Normal LSP server mode
     // CRITICAL: All logs MUST go to stderr, not stdout
     // stdout is used for LSP JSON-RPC protocol communication with the editor
@@ -41,6 +74,12 @@ async fn main() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(move |client| LiterateLsp::new(client, config.clone()));
+    let (service, socket) = LspService::build(move |client| LiterateLsp::new(client, config.clone()))
+        .custom_method("notebookDocument/didOpen", LiterateLsp::notebook_did_open)
+        .custom_method("notebookDocument/didChange", LiterateLsp::notebook_did_change)
+        .custom_method("notebookDocument/didClose", LiterateLsp::notebook_did_close)
+        .finish();
+    let registration = service.inner().notebook_registration();
+    let service = NotebookCapabilities::new(service, registration);
     Server::new(stdin, stdout, socket).serve(service).await;
 }
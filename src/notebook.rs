@@ -0,0 +1,418 @@
+use crate::fence::{line_of, line_starts, Fence, LineSource};
+use crate::format::BlockExtractor;
+
+/// Finds the code cells of Jupyter notebooks (`.ipynb`)
+pub struct NotebookExtractor;
+
+impl BlockExtractor for NotebookExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_code_cells(text)
+    }
+}
+
+/// A JSON value; strings keep the offset of their opening quote
+#[derive(Debug)]
+enum Json {
+    Object(Vec<(String, Json)>),
+    Array(Vec<Json>),
+    String { value: String, open: usize },
+    Other,
+}
+
+impl Json {
+    fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// A JSON reader that remembers where strings are, so cell sources can be mapped back
+struct Parser<'a> {
+    text: &'a str,
+    at: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        let bytes = self.text.as_bytes();
+        while bytes.get(self.at).is_some_and(u8::is_ascii_whitespace) {
+            self.at += 1;
+        }
+    }
+
+    /// Consume `byte` after optional whitespace
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.text.as_bytes().get(self.at) == Some(&byte);
+        if found {
+            self.at += 1;
+        }
+        found
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.skip_whitespace();
+        match self.text.as_bytes().get(self.at)? {
+            b'{' => {
+                self.at += 1;
+                let mut members = Vec::new();
+                if self.eat(b'}') {
+                    return Some(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let name = match self.string()? {
+                        Json::String { value, .. } => value,
+                        _ => return None,
+                    };
+                    if !self.eat(b':') {
+                        return None;
+                    }
+                    members.push((name, self.value()?));
+                    if self.eat(b'}') {
+                        return Some(Json::Object(members));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            b'[' => {
+                self.at += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Some(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']') {
+                        return Some(Json::Array(items));
+                    }
+                    if !self.eat(b',') {
+                        return None;
+                    }
+                }
+            }
+            b'"' => self.string(),
+            _ => {
+                // Numbers, booleans and null are not needed, only skipped
+                let len = self.text[self.at..]
+                    .find(|ch: char| ch.is_ascii_whitespace() || matches!(ch, ',' | ']' | '}'))
+                    .unwrap_or(self.text.len() - self.at);
+                if len == 0 {
+                    return None;
+                }
+                self.at += len;
+                Some(Json::Other)
+            }
+        }
+    }
+
+    /// Read the string starting at the current offset, which must be its opening quote
+    fn string(&mut self) -> Option<Json> {
+        let open = self.at;
+        let bytes = self.text.as_bytes();
+        if bytes.get(open) != Some(&b'"') {
+            return None;
+        }
+        let mut at = open + 1;
+        loop {
+            match bytes.get(at)? {
+                b'\\' => at += 2,
+                b'"' => break,
+                _ => at += 1,
+            }
+        }
+        self.at = at + 1;
+        let value = serde_json::from_str(&self.text[open..self.at]).ok()?;
+        Some(Json::String { value, open })
+    }
+}
+
+/// Decode a `\uXXXX` escape at `at`, with the low surrogate that may follow it
+///
+/// Returns the character and the length of its escapes in the source.
+fn unicode_escape(text: &str, at: usize) -> Option<(char, usize)> {
+    let unit = |at: usize| u32::from_str_radix(text.get(at + 2..at + 6)?, 16).ok();
+    let high = unit(at)?;
+    if (0xD800..0xDC00).contains(&high) && text[at + 6..].starts_with("\\u") {
+        let low = unit(at + 6)?;
+        let decoded = char::from_u32(0x10000 + ((high - 0xD800) << 10) + (low.checked_sub(0xDC00)?))?;
+        return Some((decoded, 12));
+    }
+    Some((char::from_u32(high).unwrap_or(char::REPLACEMENT_CHARACTER), 6))
+}
+
+/// Split a JSON string at `open` into the lines of code it holds
+///
+/// Lines end at `\n` escapes, and a `\r` just before one is dropped; a final empty line after
+/// the last one is not code. Each line keeps where its characters sit in the notebook, with
+/// escapes as runs of their own.
+fn string_lines(text: &str, starts: &[usize], open: usize) -> Vec<(String, LineSource)> {
+    let line = line_of(starts, open);
+    let line_start = starts[line];
+    let line_text = {
+        let rest = &text[line_start..];
+        rest[..rest.find('\n').unwrap_or(rest.len())].trim_end_matches('\r')
+    };
+
+    let mut lines = Vec::new();
+    let mut code = String::new();
+    let mut anchors = vec![(0, open + 1 - line_start)];
+    let mut at = open + 1;
+    let mut finish = |code: &mut String, anchors: &mut Vec<(usize, usize)>, end: usize| {
+        if anchors.last() != Some(&(code.len(), end)) {
+            anchors.push((code.len(), end));
+        }
+        let source = LineSource { line, text: line_text.to_string(), anchors: std::mem::take(anchors) };
+        lines.push((std::mem::take(code), source));
+    };
+    while let Some(ch) = text[at..].chars().next() {
+        let column = at - line_start;
+        match ch {
+            '"' => {
+                if !code.is_empty() || anchors.len() > 1 {
+                    finish(&mut code, &mut anchors, column);
+                }
+                break;
+            }
+            '\\' => {
+                let (decoded, len) = match text.as_bytes().get(at + 1) {
+                    Some(b'n') => {
+                        // A `\r\n` line ending is not code, as in the other formats
+                        let mut end = column;
+                        if code.ends_with('\r') {
+                            code.pop();
+                            anchors.pop();
+                            end -= 2;
+                        }
+                        finish(&mut code, &mut anchors, end);
+                        anchors.push((0, column + 2));
+                        at += 2;
+                        continue;
+                    }
+                    Some(b'u') => match unicode_escape(text, at) {
+                        Some(escape) => escape,
+                        None => break,
+                    },
+                    Some(b't') => ('\t', 2),
+                    Some(b'r') => ('\r', 2),
+                    Some(b'b') => ('\u{8}', 2),
+                    Some(b'f') => ('\u{c}', 2),
+                    Some(&escaped) => (char::from(escaped), 2),
+                    None => break,
+                };
+                if anchors.last() != Some(&(code.len(), column)) {
+                    anchors.push((code.len(), column));
+                }
+                code.push(decoded);
+                anchors.push((code.len(), column + len));
+                at += len;
+            }
+            _ => {
+                code.push(ch);
+                at += ch.len_utf8();
+            }
+        }
+    }
+    lines
+}
+
+/// Whether a line of a Python cell is an IPython line magic or shell escape (`%time`, `!pip`)
+pub(crate) fn is_line_magic(line: &str) -> bool {
+    line.trim_start().starts_with(['%', '!'])
+}
+
+/// Whether a Python cell is run by a cell magic (`%%bash`, `%%timeit`) instead of Python
+pub(crate) fn is_cell_magic(first_line: &str) -> bool {
+    first_line.trim_start().starts_with("%%")
+}
+
+/// Language of the notebook's kernel, from `kernelspec` or `language_info`
+fn kernel_language(notebook: &Json) -> Option<&str> {
+    let metadata = notebook.get("metadata")?;
+    metadata
+        .get("kernelspec")
+        .and_then(|spec| spec.get("language"))
+        .or_else(|| metadata.get("language_info").and_then(|info| info.get("name")))
+        .and_then(Json::as_str)
+}
+
+/// Find the code cells of a Jupyter notebook
+///
+/// Every code cell is a block in the kernel's language, unless VS Code recorded another
+/// one for the cell (`metadata.vscode.languageId`). Cell sources are stored as arrays of
+/// lines, or as one string; both are read with their escapes, so each code line maps back
+/// to its place in the JSON. In Python cells, IPython magics and shell escapes are blank
+/// lines, and cells starting with a `%%` cell magic are skipped. A notebook that is not
+/// valid JSON has no blocks.
+pub fn scan_code_cells(text: &str) -> Vec<Fence> {
+    let notebook = match (Parser { text, at: 0 }).value() {
+        Some(notebook) => notebook,
+        None => return Vec::new(),
+    };
+    let kernel = kernel_language(&notebook).unwrap_or_default();
+    let cells = match notebook.get("cells") {
+        Some(Json::Array(cells)) => cells,
+        _ => return Vec::new(),
+    };
+
+    let starts = line_starts(text);
+    let mut blocks = Vec::new();
+    for cell in cells {
+        if cell.get("cell_type").and_then(Json::as_str) != Some("code") {
+            continue;
+        }
+        let strings: Vec<usize> = match cell.get("source") {
+            Some(Json::Array(items)) => items
+                .iter()
+                .filter_map(|item| match item {
+                    Json::String { open, .. } => Some(*open),
+                    _ => None,
+                })
+                .collect(),
+            Some(Json::String { open, .. }) => vec![*open],
+            _ => continue,
+        };
+        let lang = cell
+            .get("metadata")
+            .and_then(|metadata| metadata.get("vscode"))
+            .and_then(|vscode| vscode.get("languageId"))
+            .and_then(Json::as_str)
+            .unwrap_or(kernel);
+
+        // nbformat splits sources after each newline, so every array item starts a line
+        let (mut lines, mut sources): (Vec<String>, Vec<LineSource>) =
+            strings.iter().flat_map(|&open| string_lines(text, &starts, open)).unzip();
+        // IPython syntax is not Python: magics are blanked, cells run by a cell magic skipped
+        if lang == "python" {
+            if lines.first().is_some_and(|line| is_cell_magic(line)) {
+                continue;
+            }
+            for (line, source) in lines.iter_mut().zip(&mut sources) {
+                if is_line_magic(line) {
+                    line.clear();
+                    source.anchors.truncate(1);
+                }
+            }
+        }
+        let start_line = match (strings.first(), sources.first()) {
+            (_, Some(source)) => source.line,
+            (Some(&open), None) => line_of(&starts, open),
+            (None, None) => continue,
+        };
+        let end_line = sources.last().map_or(start_line, |source| source.line);
+        blocks.push(Fence {
+            fence_char: '"',
+            fence_len: 1,
+            indent: 0,
+            info: lang.to_string(),
+            start_line,
+            end_line,
+            lines,
+            prefixes: sources.iter().map(|source| source.anchors[0].1).collect(),
+            sources,
+            scope_session: None,
+            options: Vec::new(),
+        });
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTEBOOK: &str = r##"{
+ "cells": [
+  {
+   "cell_type": "markdown",
+   "metadata": {},
+   "source": ["# Squares\n", "```python\n"]
+  },
+  {
+   "cell_type": "code",
+   "execution_count": 1,
+   "metadata": {},
+   "outputs": [],
+   "source": [
+    "def square(x):\n",
+    "    return x * x\n",
+    "print(\"hé\", square(2))"
+   ]
+  },
+  {
+   "cell_type": "code",
+   "metadata": {"vscode": {"languageId": "shellscript"}},
+   "source": "ls\n"
+  }
+ ],
+ "metadata": {
+  "kernelspec": {"display_name": "Python 3", "language": "python", "name": "python3"}
+ },
+ "nbformat": 4,
+ "nbformat_minor": 5
+}
+"##;
+
+    #[test]
+    fn test_code_cells() {
+        let blocks = scan_code_cells(NOTEBOOK);
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].lang(), "python");
+        assert_eq!(blocks[0].lines, vec!["def square(x):", "    return x * x", "print(\"hé\", square(2))"]);
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (13, 15));
+        assert_eq!(blocks[0].content_line(1), 14);
+        assert_eq!(blocks[0].prefixes, vec![5, 5, 5]);
+
+        assert_eq!(blocks[1].lang(), "shellscript");
+        assert_eq!(blocks[1].lines, vec!["ls"]);
+        assert_eq!(blocks[1].start_line, 21);
+    }
+
+    #[test]
+    fn test_escapes_map_to_json_columns() {
+        let blocks = scan_code_cells(NOTEBOOK);
+        let source = &blocks[0].sources[2];
+
+        // `print(\"hé\"`: each quote escape is one character of code
+        assert_eq!(source.source_byte(6), 11);
+        assert_eq!(source.source_byte(7), 13);
+        assert_eq!(source.source_byte(10), 16);
+        assert_eq!(source.code_byte(17), 10);
+
+        // A surrogate pair is one character
+        let text = r#"{"metadata": {"language_info": {"name": "python"}}, "cells": [{"cell_type": "code", "source": ["s = \"\ud83d\ude00\""]}]}"#;
+        let blocks = scan_code_cells(text);
+        assert_eq!(blocks[0].lines, vec!["s = \"😀\""]);
+        let source = &blocks[0].sources[0];
+        assert_eq!(source.source_byte(9) - source.source_byte(5), 12);
+
+        assert!(scan_code_cells("not json").is_empty());
+    }
+
+    #[test]
+    fn test_crlf_sources_and_magics() {
+        let text = r#"{"metadata": {"kernelspec": {"language": "python"}}, "cells": [
+ {"cell_type": "code", "source": ["%matplotlib inline\r\n", "!pip install numpy\r\n", "x = 1\r\n", "x"]},
+ {"cell_type": "code", "source": ["%%bash\n", "ls\n"]},
+ {"cell_type": "code", "source": "y = x\r\nprint(y)"}
+]}"#;
+        let blocks = scan_code_cells(text);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].lines, vec!["", "", "x = 1", "x"]);
+        assert_eq!(blocks[0].sources[2].anchors, vec![(0, 87), (5, 92)]);
+        assert_eq!(blocks[1].lines, vec!["y = x", "print(y)"]);
+        assert_eq!(blocks[1].sources[0].anchors, vec![(0, 34), (5, 39)]);
+    }
+}
@@ -0,0 +1,661 @@
+use crate::document_store::position_to_offset;
+use crate::notebook::{is_cell_magic, is_line_magic};
+use crate::position::PositionEncoding;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, OnceLock};
+use std::task::{Context, Poll};
+use tower_lsp::jsonrpc::Request;
+use tower_lsp::lsp_types::{
+    Diagnostic, Position, TextDocumentContentChangeEvent, TextDocumentIdentifier, TextDocumentItem, Url,
+    VersionedTextDocumentIdentifier,
+};
+
+// LSP 3.17 notebook synchronization; lsp-types 0.94 predates it, so only the fields
+// literate-lsp reads are declared.
+
+/// `NotebookCellKind.Code`; the other kind, markup, is prose
+const CODE_CELL: u8 = 2;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookCell {
+    pub kind: u8,
+    pub document: Url,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookDocument {
+    pub uri: Url,
+    pub version: i32,
+    pub cells: Vec<NotebookCell>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidOpenNotebookDocumentParams {
+    pub notebook_document: NotebookDocument,
+    pub cell_text_documents: Vec<TextDocumentItem>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct VersionedNotebookDocumentIdentifier {
+    pub version: i32,
+    pub uri: Url,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookDocumentIdentifier {
+    pub uri: Url,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellArrayChange {
+    pub start: u32,
+    pub delete_count: u32,
+    pub cells: Option<Vec<NotebookCell>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellStructureChange {
+    pub array: NotebookCellArrayChange,
+    pub did_open: Option<Vec<TextDocumentItem>>,
+    pub did_close: Option<Vec<TextDocumentIdentifier>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookCellTextChange {
+    pub document: VersionedTextDocumentIdentifier,
+    pub changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellsChange {
+    pub structure: Option<NotebookCellStructureChange>,
+    pub data: Option<Vec<NotebookCell>>,
+    pub text_content: Option<Vec<NotebookCellTextChange>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct NotebookDocumentChangeEvent {
+    pub cells: Option<NotebookCellsChange>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeNotebookDocumentParams {
+    pub notebook_document: VersionedNotebookDocumentIdentifier,
+    pub change: NotebookDocumentChangeEvent,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidCloseNotebookDocumentParams {
+    pub notebook_document: NotebookDocumentIdentifier,
+}
+
+/// Options for registering `notebookDocument/sync`: every `.ipynb` notebook, without saves
+pub fn registration_options() -> Value {
+    json!({
+        "notebookSelector": [{ "notebook": { "pattern": "**/*.ipynb" } }],
+        "save": false,
+    })
+}
+
+/// Whether `initialize` params say the editor can register `notebookDocument/sync` dynamically
+pub fn supports_notebook_registration(params: &Value) -> bool {
+    params
+        .pointer("/capabilities/notebookDocument/synchronization/dynamicRegistration")
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Passes every request on to the language server, recording from the raw `initialize`
+/// request whether the editor can register notebook sync
+///
+/// lsp-types 0.94 drops `capabilities.notebookDocument` when it parses `initialize`.
+pub struct NotebookCapabilities<S> {
+    inner: S,
+    registration: Arc<OnceLock<bool>>,
+}
+
+impl<S> NotebookCapabilities<S> {
+    pub fn new(inner: S, registration: Arc<OnceLock<bool>>) -> Self {
+        NotebookCapabilities { inner, registration }
+    }
+}
+
+impl<S: tower_service::Service<Request>> tower_service::Service<Request> for NotebookCapabilities<S> {
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if request.method() == "initialize" {
+            let supported = request.params().is_some_and(supports_notebook_registration);
+            let _ = self.registration.set(supported);
+        }
+        self.inner.call(request)
+    }
+}
+
+/// A cell of a synced notebook, with the text of its cell document
+#[derive(Debug, Clone)]
+pub struct Cell {
+    pub uri: Url,
+    pub code: bool,
+    pub language: String,
+    pub text: String,
+    pub version: i32,
+}
+
+impl Cell {
+    fn new(cell: &NotebookCell, item: Option<&TextDocumentItem>) -> Self {
+        Cell {
+            uri: cell.document.clone(),
+            code: cell.kind == CODE_CELL,
+            language: item.map(|item| item.language_id.clone()).unwrap_or_default(),
+            text: item.map(|item| item.text.clone()).unwrap_or_default(),
+            version: item.map_or(0, |item| item.version),
+        }
+    }
+
+    /// The cell's text as code for its language server
+    ///
+    /// In Python cells, IPython magics and shell escapes are blank lines, and a cell run by
+    /// a `%%` cell magic is blank altogether. Every line is kept, so positions still match.
+    fn code_text(&self) -> String {
+        if self.language != "python" {
+            return self.text.clone();
+        }
+        let cell_magic = self.text.lines().next().is_some_and(is_cell_magic);
+        self.text
+            .split('\n')
+            .map(|line| if cell_magic || is_line_magic(line) { "" } else { line })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// A notebook synced with `notebookDocument/*` notifications
+///
+/// Its code cells are served as one markdown document, with a fenced block per cell in
+/// the cell's language, so the notebook goes through the same virtual documents and child
+/// LSPs as any literate document. `Layout` maps between the cells and that document.
+#[derive(Debug, Clone)]
+pub struct Notebook {
+    pub uri: Url,
+    pub version: i32,
+    pub cells: Vec<Cell>,
+}
+
+impl Notebook {
+    pub fn open(params: &DidOpenNotebookDocumentParams) -> Self {
+        let item = |uri: &Url| params.cell_text_documents.iter().find(|item| &item.uri == uri);
+        Notebook {
+            uri: params.notebook_document.uri.clone(),
+            version: params.notebook_document.version,
+            cells: params
+                .notebook_document
+                .cells
+                .iter()
+                .map(|cell| Cell::new(cell, item(&cell.document)))
+                .collect(),
+        }
+    }
+
+    /// Apply a change to the cell list, the cell kinds and the cell texts
+    ///
+    /// Returns the cells that were removed from the notebook.
+    pub fn apply_change(&mut self, params: &DidChangeNotebookDocumentParams, encoding: PositionEncoding) -> Vec<Url> {
+        self.version = params.notebook_document.version;
+        let cells = match &params.change.cells {
+            Some(cells) => cells,
+            None => return Vec::new(),
+        };
+
+        let mut removed = Vec::new();
+        if let Some(structure) = &cells.structure {
+            let opened = structure.did_open.as_deref().unwrap_or_default();
+            let start = (structure.array.start as usize).min(self.cells.len());
+            let end = (start + structure.array.delete_count as usize).min(self.cells.len());
+            let deleted: Vec<Cell> = self.cells.drain(start..end).collect();
+            // A moved cell is deleted and inserted again, keeping its open document
+            let inserted: Vec<Cell> = structure
+                .array
+                .cells
+                .iter()
+                .flatten()
+                .map(|cell| match deleted.iter().find(|old| old.uri == cell.document) {
+                    Some(old) => Cell { code: cell.kind == CODE_CELL, ..old.clone() },
+                    None => Cell::new(cell, opened.iter().find(|item| item.uri == cell.document)),
+                })
+                .collect();
+            removed = deleted
+                .into_iter()
+                .map(|cell| cell.uri)
+                .filter(|uri| !inserted.iter().any(|cell| &cell.uri == uri))
+                .collect();
+            self.cells.splice(start..start, inserted);
+        }
+
+        for data in cells.data.iter().flatten() {
+            if let Some(cell) = self.cells.iter_mut().find(|cell| cell.uri == data.document) {
+                cell.code = data.kind == CODE_CELL;
+            }
+        }
+
+        for change in cells.text_content.iter().flatten() {
+            let cell = match self.cells.iter_mut().find(|cell| cell.uri == change.document.uri) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            for edit in &change.changes {
+                match edit.range {
+                    Some(range) => {
+                        let start = position_to_offset(&cell.text, range.start, encoding);
+                        let end = position_to_offset(&cell.text, range.end, encoding).max(start);
+                        cell.text.replace_range(start..end, &edit.text);
+                    }
+                    None => cell.text = edit.text.clone(),
+                }
+            }
+            cell.version = change.document.version;
+        }
+        removed
+    }
+
+    /// The fence around cells: longer than any backtick run in them, so none closes it early
+    fn fence(&self) -> String {
+        let longest = self
+            .cells
+            .iter()
+            .filter(|cell| cell.code)
+            .flat_map(|cell| cell.text.split(|ch| ch != '`'))
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        "`".repeat((longest + 1).max(3))
+    }
+
+    /// The notebook as a markdown document: each code cell is a fenced block, in order
+    pub fn markdown(&self) -> String {
+        let fence = self.fence();
+        let mut markdown = String::new();
+        for cell in self.cells.iter().filter(|cell| cell.code) {
+            if !markdown.is_empty() {
+                markdown.push('\n');
+            }
+            markdown.push_str(&format!("{}{}\n{}\n{}\n", fence, cell.language, cell.code_text(), fence));
+        }
+        markdown
+    }
+
+    /// Where each code cell sits in `markdown()`
+    pub fn layout(&self) -> Layout {
+        let mut cells = Vec::new();
+        let mut line = 0;
+        for cell in self.cells.iter().filter(|cell| cell.code) {
+            let line_count = cell.text.split('\n').count() as u32;
+            // The opening fence comes first, and a blank line and the closing fence after
+            cells.push(CellSpan {
+                uri: cell.uri.clone(),
+                version: cell.version,
+                first_line: line + 1,
+                line_count,
+            });
+            line += line_count + 3;
+        }
+        Layout { notebook_uri: self.uri.clone(), cells }
+    }
+}
+
+/// The lines of one code cell in a notebook's markdown document
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellSpan {
+    pub uri: Url,
+    pub version: i32,
+    pub first_line: u32,
+    pub line_count: u32,
+}
+
+impl CellSpan {
+    /// Whether a markdown line is in the cell; one past the end is the end of its last line
+    fn contains(&self, line: u32) -> bool {
+        line >= self.first_line && line <= self.first_line + self.line_count
+    }
+}
+
+/// Maps positions between notebook cells and the notebook's markdown document
+///
+/// Cells are copied into the document line for line, so only lines move; columns are the same.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    pub notebook_uri: Url,
+    pub cells: Vec<CellSpan>,
+}
+
+fn start_line(range: &Value) -> Option<u32> {
+    range.get("start")?.get("line")?.as_u64().map(|line| line as u32)
+}
+
+fn is_position_object(map: &Map<String, Value>) -> bool {
+    map.len() == 2 && map.contains_key("line") && map.contains_key("character")
+}
+
+impl Layout {
+    pub fn cell(&self, uri: &Url) -> Option<&CellSpan> {
+        self.cells.iter().find(|cell| &cell.uri == uri)
+    }
+
+    /// The cell holding a line of the markdown document
+    pub fn cell_at(&self, line: u32) -> Option<&CellSpan> {
+        self.cells.iter().find(|cell| cell.contains(line))
+    }
+
+    /// A position in a cell, in the notebook's markdown document
+    pub fn to_notebook(&self, cell: &Url, position: Position) -> Option<Position> {
+        let cell = self.cell(cell)?;
+        Some(Position::new(cell.first_line + position.line, position.character))
+    }
+
+    fn is_notebook_uri(&self, uri: Option<&Value>) -> bool {
+        uri.and_then(Value::as_str)
+            .and_then(|uri| Url::parse(uri).ok())
+            .is_some_and(|uri| uri == self.notebook_uri)
+    }
+
+    /// Group edits in markdown coordinates by the cell they are in, in cell coordinates
+    ///
+    /// Edits outside the cells would land on the fences literate-lsp made up, and are dropped.
+    fn split_edits(&self, edits: Vec<Value>) -> Vec<(&CellSpan, Vec<Value>)> {
+        let mut split: Vec<(&CellSpan, Vec<Value>)> = Vec::new();
+        for mut edit in edits {
+            let cell = match edit.get("range").and_then(start_line).and_then(|line| self.cell_at(line)) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            self.rewrite(&mut edit, cell);
+            match split.iter_mut().find(|(span, _)| span.uri == cell.uri) {
+                Some((_, edits)) => edits.push(edit),
+                None => split.push((cell, vec![edit])),
+            }
+        }
+        split
+    }
+
+    /// Rewrite markdown positions to `cell` positions, and notebook locations to cell locations
+    fn rewrite(&self, value: &mut Value, cell: &CellSpan) {
+        match value {
+            Value::Object(map) => {
+                if is_position_object(map) {
+                    if let Some(line) = map.get("line").and_then(Value::as_u64) {
+                        map["line"] = json!((line as u32).saturating_sub(cell.first_line));
+                    }
+                    return;
+                }
+
+                // A location in the notebook is in whichever cell its range starts in
+                let cell_of = |range: Option<&Value>| range.and_then(start_line).and_then(|line| self.cell_at(line));
+                if let Some(target_uri) = map.get("targetUri") {
+                    let target = match self.is_notebook_uri(Some(target_uri)) {
+                        true => Some(cell_of(map.get("targetRange")).unwrap_or(cell)),
+                        false => None,
+                    };
+                    if let Some(target) = target {
+                        map.insert("targetUri".to_string(), json!(target.uri));
+                    }
+                    for (key, val) in map.iter_mut() {
+                        match (key.as_str(), target) {
+                            ("originSelectionRange", _) => self.rewrite(val, cell),
+                            ("targetRange" | "targetSelectionRange", Some(target)) => self.rewrite(val, target),
+                            _ => {}
+                        }
+                    }
+                    return;
+                }
+
+                // Objects naming another document keep its coordinates
+                let uri = map.get("uri").or_else(|| map.get("textDocument").and_then(|doc| doc.get("uri")));
+                if uri.is_some() && !self.is_notebook_uri(uri) {
+                    return;
+                }
+                let mut cell = cell;
+                if self.is_notebook_uri(map.get("uri")) {
+                    cell = cell_of(map.get("range")).unwrap_or(cell);
+                    map.insert("uri".to_string(), json!(cell.uri));
+                }
+
+                for (key, val) in map.iter_mut() {
+                    match (key.as_str(), val) {
+                        ("uri", _) => {}
+                        // WorkspaceEdit.changes is keyed by document URI
+                        ("changes", Value::Object(changes)) => {
+                            // Edits on other files are in their own coordinates
+                            if let Some(Value::Array(edits)) = changes.remove(self.notebook_uri.as_str()) {
+                                for (span, edits) in self.split_edits(edits) {
+                                    changes.insert(span.uri.to_string(), Value::Array(edits));
+                                }
+                            }
+                        }
+                        ("documentChanges", Value::Array(changes)) => {
+                            let mut split = Vec::new();
+                            for mut change in std::mem::take(changes) {
+                                let edits = match change.get("textDocument").map(|doc| doc.get("uri")) {
+                                    Some(uri) if self.is_notebook_uri(uri) => change["edits"].take(),
+                                    _ => {
+                                        self.rewrite(&mut change, cell);
+                                        split.push(change);
+                                        continue;
+                                    }
+                                };
+                                let edits = match edits {
+                                    Value::Array(edits) => edits,
+                                    _ => continue,
+                                };
+                                for (span, edits) in self.split_edits(edits) {
+                                    split.push(json!({
+                                        "textDocument": { "uri": span.uri, "version": span.version },
+                                        "edits": edits,
+                                    }));
+                                }
+                            }
+                            *changes = split;
+                        }
+                        (_, val) => self.rewrite(val, cell),
+                    }
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.rewrite(item, cell);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Rewrite a response in markdown coordinates to the cell a request was made on
+    ///
+    /// Positions naming no document are in that cell; locations and edits in the notebook
+    /// move to the cell they fall in.
+    pub fn rewrite_response(&self, value: &mut Value, cell: &Url) {
+        if let Some(cell) = self.cell(cell) {
+            self.rewrite(value, cell);
+        }
+    }
+
+    /// Drop the items of a document-wide result (symbols, edits) that are outside a cell
+    pub fn retain_cell(&self, result: &mut Value, cell: &Url) {
+        let (cell, items) = match (self.cell(cell), result.as_array_mut()) {
+            (Some(cell), Some(items)) => (cell, items),
+            _ => return,
+        };
+        items.retain(|item| {
+            let range = item.get("range").or_else(|| item.get("location").and_then(|location| location.get("range")));
+            range.and_then(start_line).is_some_and(|line| cell.contains(line))
+        });
+    }
+
+    /// Split a notebook's diagnostics by cell, with an entry for every code cell
+    ///
+    /// Each cell document holds its own diagnostics in the editor, so cells without any
+    /// still get an empty list to clear what was published before.
+    pub fn split_diagnostics(&self, diagnostics: Vec<Diagnostic>) -> Vec<(Url, Vec<Diagnostic>)> {
+        let mut split: Vec<(Url, Vec<Diagnostic>)> =
+            self.cells.iter().map(|cell| (cell.uri.clone(), Vec::new())).collect();
+        for diagnostic in diagnostics {
+            let cell = match self.cell_at(diagnostic.range.start.line) {
+                Some(cell) => cell,
+                None => continue,
+            };
+            let mut value = json!(diagnostic);
+            self.rewrite(&mut value, cell);
+            if let (Ok(diagnostic), Some((_, cell_diagnostics))) = (
+                serde_json::from_value(value),
+                split.iter_mut().find(|(uri, _)| uri == &cell.uri),
+            ) {
+                cell_diagnostics.push(diagnostic);
+            }
+        }
+        split
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower_lsp::lsp_types::Range;
+
+    fn open_params() -> DidOpenNotebookDocumentParams {
+        serde_json::from_value(json!({
+            "notebookDocument": {
+                "uri": "file:///nb/analysis.ipynb",
+                "notebookType": "jupyter-notebook",
+                "version": 1,
+                "cells": [
+                    { "kind": 2, "document": "vscode-notebook-cell:/nb/analysis.ipynb#a" },
+                    { "kind": 1, "document": "vscode-notebook-cell:/nb/analysis.ipynb#b" },
+                    { "kind": 2, "document": "vscode-notebook-cell:/nb/analysis.ipynb#c" }
+                ]
+            },
+            "cellTextDocuments": [
+                { "uri": "vscode-notebook-cell:/nb/analysis.ipynb#a", "languageId": "python", "version": 1, "text": "import os\nx = 1" },
+                { "uri": "vscode-notebook-cell:/nb/analysis.ipynb#b", "languageId": "markdown", "version": 1, "text": "# Notes" },
+                { "uri": "vscode-notebook-cell:/nb/analysis.ipynb#c", "languageId": "python", "version": 1, "text": "s = '````'" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    fn cell(id: &str) -> Url {
+        Url::parse(&format!("vscode-notebook-cell:/nb/analysis.ipynb#{}", id)).unwrap()
+    }
+
+    #[test]
+    fn test_notebook_markdown_and_layout() {
+        let notebook = Notebook::open(&open_params());
+        assert_eq!(
+            notebook.markdown(),
+            "`````python\nimport os\nx = 1\n`````\n\n`````python\ns = '````'\n`````\n"
+        );
+
+        let layout = notebook.layout();
+        assert_eq!(layout.cells.iter().map(|cell| (cell.first_line, cell.line_count)).collect::<Vec<_>>(), vec![(1, 2), (6, 1)]);
+        assert_eq!(layout.to_notebook(&cell("c"), Position::new(0, 4)), Some(Position::new(6, 4)));
+        assert_eq!(layout.to_notebook(&cell("b"), Position::new(0, 0)), None);
+        assert_eq!(layout.cell_at(4).map(|cell| &cell.uri), None);
+    }
+
+    #[test]
+    fn test_notebook_registration_capability() {
+        let params = json!({ "capabilities": { "notebookDocument": { "synchronization": { "dynamicRegistration": true } } } });
+        assert!(supports_notebook_registration(&params));
+        assert!(!supports_notebook_registration(&json!({ "capabilities": { "notebookDocument": {} } })));
+        assert!(!supports_notebook_registration(&json!({ "capabilities": {} })));
+    }
+
+    #[test]
+    fn test_magics_are_blank_lines() {
+        let mut notebook = Notebook::open(&open_params());
+        notebook.cells[0].text = "%matplotlib inline\nimport os\n!ls".to_string();
+        notebook.cells[2].text = "%%bash\nls".to_string();
+        assert_eq!(
+            notebook.markdown(),
+            "```python\n\nimport os\n\n```\n\n```python\n\n\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_notebook_changes() {
+        let mut notebook = Notebook::open(&open_params());
+        let change: DidChangeNotebookDocumentParams = serde_json::from_value(json!({
+            "notebookDocument": { "uri": "file:///nb/analysis.ipynb", "version": 2 },
+            "change": { "cells": {
+                "structure": {
+                    "array": { "start": 1, "deleteCount": 1, "cells": [{ "kind": 2, "document": "vscode-notebook-cell:/nb/analysis.ipynb#d" }] },
+                    "didOpen": [{ "uri": "vscode-notebook-cell:/nb/analysis.ipynb#d", "languageId": "python", "version": 1, "text": "y = 2" }],
+                    "didClose": [{ "uri": "vscode-notebook-cell:/nb/analysis.ipynb#b" }]
+                },
+                "textContent": [{
+                    "document": { "uri": "vscode-notebook-cell:/nb/analysis.ipynb#a", "version": 2 },
+                    "changes": [{ "range": { "start": { "line": 1, "character": 4 }, "end": { "line": 1, "character": 5 } }, "text": "42" }]
+                }]
+            } }
+        }))
+        .unwrap();
+
+        let removed = notebook.apply_change(&change, PositionEncoding::Utf16);
+        assert_eq!(removed, vec![cell("b")]);
+        assert_eq!(notebook.version, 2);
+        let texts: Vec<&str> = notebook.cells.iter().map(|cell| cell.text.as_str()).collect();
+        assert_eq!(texts, vec!["import os\nx = 42", "y = 2", "s = '````'"]);
+        assert_eq!(notebook.cells[0].version, 2);
+    }
+
+    #[test]
+    fn test_responses_and_diagnostics_move_to_cells() {
+        let layout = Notebook::open(&open_params()).layout();
+        let notebook_uri = "file:///nb/analysis.ipynb";
+
+        // A hover range is in the requesting cell; locations go to the cell they are in
+        let mut response = json!({
+            "result": [
+                { "uri": notebook_uri, "range": { "start": { "line": 6, "character": 0 }, "end": { "line": 6, "character": 1 } } },
+                { "uri": "file:///lib/os.py", "range": { "start": { "line": 6, "character": 0 }, "end": { "line": 6, "character": 1 } } }
+            ],
+            "range": { "start": { "line": 2, "character": 0 }, "end": { "line": 2, "character": 1 } }
+        });
+        layout.rewrite_response(&mut response, &cell("a"));
+        assert_eq!(response["result"][0]["uri"], json!(cell("c")));
+        assert_eq!(response["result"][0]["range"]["start"]["line"], json!(0));
+        assert_eq!(response["result"][1]["range"]["start"]["line"], json!(6));
+        assert_eq!(response["range"]["start"]["line"], json!(1));
+
+        // Rename edits are split by cell, with the cell versions
+        let mut edit = json!({ "documentChanges": [{
+            "textDocument": { "uri": notebook_uri, "version": 1 },
+            "edits": [
+                { "range": { "start": { "line": 2, "character": 0 }, "end": { "line": 2, "character": 1 } }, "newText": "z" },
+                { "range": { "start": { "line": 6, "character": 0 }, "end": { "line": 6, "character": 1 } }, "newText": "z" }
+            ]
+        }] });
+        layout.rewrite_response(&mut edit, &cell("a"));
+        let changes = edit["documentChanges"].as_array().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1]["textDocument"]["uri"], json!(cell("c")));
+        assert_eq!(changes[1]["edits"][0]["range"]["start"]["line"], json!(0));
+
+        let diagnostic = |line| Diagnostic::new_simple(Range::new(Position::new(line, 0), Position::new(line, 1)), "bad".to_string());
+        let split = layout.split_diagnostics(vec![diagnostic(7), diagnostic(4)]);
+        assert_eq!(split.len(), 2);
+        assert!(split[0].1.is_empty());
+        assert_eq!(split[1].1[0].range.start.line, 1);
+    }
+}
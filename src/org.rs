@@ -0,0 +1,130 @@
+use crate::attributes::header_arguments;
+use crate::fence::{indent_of, Fence};
+use crate::format::BlockExtractor;
+
+/// Finds the `#+begin_src` blocks of Org documents
+pub struct OrgExtractor;
+
+impl BlockExtractor for OrgExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_src_blocks(text)
+    }
+}
+
+/// Rest of a line after a case-insensitive keyword, if the line starts with it
+fn keyword<'a>(line: &'a str, keyword: &str) -> Option<&'a str> {
+    let trimmed = line.trim_start();
+    if !trimmed.get(..keyword.len())?.eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let rest = &trimmed[keyword.len()..];
+    (rest.is_empty() || rest.starts_with(char::is_whitespace)).then_some(rest)
+}
+
+/// Find the source blocks of an Org document
+///
+/// `#+begin_src lang :header args` opens a block and `#+end_src` closes it, in any case.
+/// The block's lines lose their common indentation, and the comma Org puts in front of
+/// code lines starting with `*` or `#+` is stripped like a prefix.
+pub fn scan_src_blocks(text: &str) -> Vec<Fence> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut start_line = 0;
+    while start_line < lines.len() {
+        let info = match keyword(lines[start_line], "#+begin_src") {
+            Some(info) => info.trim(),
+            None => {
+                start_line += 1;
+                continue;
+            }
+        };
+        let end_line = (start_line + 1..lines.len())
+            .find(|&line| keyword(lines[line], "#+end_src").is_some())
+            .unwrap_or(lines.len());
+
+        let body = &lines[start_line + 1..end_line];
+        let dedent = body
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| indent_of(line))
+            .min()
+            .unwrap_or(0);
+        let (prefixes, code) = body
+            .iter()
+            .map(|line| {
+                if line.trim().is_empty() {
+                    return (line.len(), String::new());
+                }
+                let code = &line[dedent..];
+                let escaped = code.strip_prefix(',').filter(|rest| {
+                    let rest = rest.trim_start_matches(',');
+                    rest.starts_with('*') || rest.starts_with("#+")
+                });
+                match escaped {
+                    Some(rest) => (dedent + 1, rest.to_string()),
+                    None => (dedent, code.to_string()),
+                }
+            })
+            .unzip();
+
+        blocks.push(Fence {
+            fence_char: '#',
+            fence_len: 1,
+            indent: indent_of(lines[start_line]),
+            info: info.to_string(),
+            start_line,
+            end_line,
+            lines: code,
+            prefixes,
+            sources: Vec::new(),
+            scope_session: None,
+            options: header_arguments(info),
+        });
+        start_line = end_line + 1;
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_src_blocks() {
+        let text = "\
+* Setup
+#+begin_src rust :tangle src/main.rs
+fn main() {}
+#+end_src
+
+  #+BEGIN_SRC python :session repl
+    def f():
+        return 1
+  #+END_SRC
+";
+        let blocks = scan_src_blocks(text);
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].lang(), "rust");
+        assert_eq!(blocks[0].attributes().file.as_deref(), Some("src/main.rs"));
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (1, 3));
+        assert_eq!(blocks[0].lines, vec!["fn main() {}"]);
+
+        assert_eq!(blocks[1].lang(), "python");
+        assert_eq!(blocks[1].session().as_deref(), Some("repl"));
+        assert_eq!(blocks[1].lines, vec!["def f():", "    return 1"]);
+        assert_eq!(blocks[1].prefixes, vec![4, 4]);
+        assert_eq!(blocks[1].content_start(), 6);
+    }
+
+    #[test]
+    fn test_escaped_lines_and_unclosed_blocks() {
+        let text = "#+begin_src python\n,* not a heading\n,#+not a keyword\nx = 1\n";
+        let blocks = scan_src_blocks(text);
+        assert_eq!(blocks[0].lines, vec!["* not a heading", "#+not a keyword", "x = 1"]);
+        assert_eq!(blocks[0].prefixes, vec![1, 1, 0]);
+        assert_eq!(blocks[0].end_line, 4);
+
+        assert!(scan_src_blocks("#+begin_example\ntext\n#+end_example\n").is_empty());
+    }
+}
@@ -1,25 +1,152 @@
-use tower_lsp::lsp_types::{Position, Range, Location, Url};
+use tower_lsp::lsp_types::{Position, PositionEncodingKind, Range, Location, Url};
 use crate::virtual_doc::CodeBlock;
 
+/// Unit in which an LSP peer counts the `character` of a position
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    /// The LSP default when nothing is negotiated
+    #[default]
+    Utf16,
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Encodings offered to child LSPs, in order of preference
+    pub const SUPPORTED: [PositionEncoding; 3] = [Self::Utf8, Self::Utf16, Self::Utf32];
+
+    pub fn from_kind(kind: &PositionEncodingKind) -> Option<Self> {
+        match kind.as_str() {
+            "utf-8" => Some(Self::Utf8),
+            "utf-16" => Some(Self::Utf16),
+            "utf-32" => Some(Self::Utf32),
+            _ => None,
+        }
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    /// Pick the first encoding a peer offers that we understand, falling back to UTF-16
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        offered
+            .unwrap_or_default()
+            .iter()
+            .find_map(Self::from_kind)
+            .unwrap_or_default()
+    }
+
+    fn char_units(self, ch: char) -> usize {
+        match self {
+            Self::Utf8 => ch.len_utf8(),
+            Self::Utf16 => ch.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+
+    /// Length of a text in this encoding's units
+    pub fn text_len(self, text: &str) -> usize {
+        text.chars().map(|ch| self.char_units(ch)).sum()
+    }
+
+    /// Byte offset of a column in a line
+    ///
+    /// A column inside a character snaps to its start; columns past the end of the
+    /// line keep their distance from it, so out-of-range positions stay out of range.
+    pub fn byte_offset(self, line: &str, column: u32) -> usize {
+        let column = column as usize;
+        let mut units = 0;
+        for (byte, ch) in line.char_indices() {
+            let next = units + self.char_units(ch);
+            if next > column {
+                return byte;
+            }
+            units = next;
+        }
+        line.len() + (column - units)
+    }
+
+    /// Column of a byte offset in a line, the inverse of `byte_offset`
+    pub fn column(self, line: &str, byte: usize) -> u32 {
+        if byte > line.len() {
+            return (self.text_len(line) + byte - line.len()) as u32;
+        }
+        let mut start = byte;
+        while !line.is_char_boundary(start) {
+            start -= 1;
+        }
+        self.text_len(&line[..start]) as u32
+    }
+}
+
+/// Text of a content line of a block, by offset from its first content line
+fn block_line(block: &CodeBlock, offset: usize) -> &str {
+    block.content.lines().nth(offset).unwrap_or("")
+}
+
 pub struct PositionMapper<'a> {
     blocks: &'a [CodeBlock],
+    markdown_encoding: PositionEncoding,
+    virtual_encoding: PositionEncoding,
 }
 
 impl<'a> PositionMapper<'a> {
     pub fn new(blocks: &'a [CodeBlock]) -> Self {
-        PositionMapper { blocks }
+        PositionMapper {
+            blocks,
+            markdown_encoding: PositionEncoding::default(),
+            virtual_encoding: PositionEncoding::default(),
+        }
+    }
+
+    /// Set the encodings negotiated with the editor (markdown side) and the child LSP
+    pub fn with_encodings(mut self, markdown: PositionEncoding, virtual_doc: PositionEncoding) -> Self {
+        self.markdown_encoding = markdown;
+        self.virtual_encoding = virtual_doc;
+        self
     }
 
     pub fn markdown_to_virtual(&self, markdown_line: u32, col: u32) -> Option<(u32, u32)> {
         for block in self.blocks {
             // Check if position is within the content range (not in fence lines)
-            if markdown_line >= block.content_start as u32
-                && markdown_line <= block.content_end as u32
-            {
-                let offset = markdown_line as usize - block.content_start;
-                let virtual_line = block.virtual_start as u32 + offset as u32;
-                return Some((virtual_line, col));
+            if markdown_line < block.content_start as u32 || markdown_line > block.content_end as u32 {
+                continue;
             }
+
+            let (offset, byte) = if block.sources.is_empty() {
+                let offset = markdown_line as usize - block.content_start;
+                // The stripped prefix (indentation, quote and list markers) is ASCII, so it
+                // is the same width in every encoding
+                let line = block_line(block, offset);
+                let prefix = block.prefix_width(offset) as u32;
+                (offset, self.markdown_encoding.byte_offset(line, col.saturating_sub(prefix)))
+            } else {
+                // Several content lines may share a document line (split by an escaped
+                // newline); the column picks the last one starting at or before it
+                let mut found = None;
+                for (offset, source) in block.sources.iter().enumerate() {
+                    if source.line != markdown_line as usize {
+                        continue;
+                    }
+                    let byte = self.markdown_encoding.byte_offset(&source.text, col);
+                    if found.is_none() || source.anchors.first().is_some_and(|&(_, start)| start <= byte) {
+                        found = Some((offset, source.code_byte(byte)));
+                    }
+                }
+                match found {
+                    Some(found) => found,
+                    None => continue,
+                }
+            };
+
+            let line = block_line(block, offset);
+            let column = self.virtual_encoding.column(line, byte) + block.expansion_indent as u32;
+            return Some(((block.virtual_start + offset) as u32, column));
         }
         None
     }
@@ -30,13 +157,43 @@ impl<'a> PositionMapper<'a> {
                 && virtual_line < block.virtual_end as u32
             {
                 let offset = virtual_line as usize - block.virtual_start;
+                let line = block_line(block, offset);
+                // Indentation added by a chunk reference is ASCII whitespace too
+                let col = col.saturating_sub(block.expansion_indent as u32);
+                let byte = self.virtual_encoding.byte_offset(line, col);
+                if let Some(source) = block.sources.get(offset) {
+                    let column = self.markdown_encoding.column(&source.text, source.source_byte(byte));
+                    return Some((source.line as u32, column));
+                }
                 let markdown_line = block.content_start as u32 + offset as u32;
-                return Some((markdown_line, col));
+                let prefix = block.prefix_width(offset) as u32;
+                return Some((markdown_line, self.markdown_encoding.column(line, byte) + prefix));
             }
         }
         None
     }
 
+    /// Map a virtual document range back to markdown
+    ///
+    /// A range end sitting at column 0 just past a block's last line is valid LSP
+    /// (it means "up to the end of the previous line"), so it is mapped to column 0
+    /// of the line after the block content instead of being rejected.
+    pub fn virtual_range_to_markdown(&self, range: Range) -> Option<Range> {
+        let (line, character) = self.virtual_to_markdown(range.start.line, range.start.character)?;
+        let start = Position { line, character };
+
+        let end = match self.virtual_to_markdown(range.end.line, range.end.character) {
+            Some((line, character)) => Position { line, character },
+            None if range.end.character == 0 && range.end.line > 0 => {
+                let (line, _) = self.virtual_to_markdown(range.end.line - 1, 0)?;
+                Position { line: line + 1, character: 0 }
+            }
+            None => return None,
+        };
+
+        Some(Range { start, end })
+    }
+
     pub fn map_location(
         &self,
         virtual_location: Location,
@@ -60,6 +217,28 @@ impl<'a> PositionMapper<'a> {
             },
         })
     }
+
+    /// Map the locations in the virtual document back to markdown
+    ///
+    /// Locations in other files are returned untouched; virtual locations outside any
+    /// code block are dropped.
+    pub fn map_locations(
+        &self,
+        locations: Vec<Location>,
+        virtual_uri: &Url,
+        markdown_uri: &Url,
+    ) -> Vec<Location> {
+        locations
+            .into_iter()
+            .filter_map(|location| {
+                if &location.uri == virtual_uri {
+                    self.map_location(location, markdown_uri.clone())
+                } else {
+                    Some(location)
+                }
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
@@ -78,6 +257,7 @@ mod tests {
                 virtual_start: 0,
                 virtual_end: 2,
                 content: ": square ( n -- n ) dup * ;\n".to_string(),
+                ..Default::default()
             },
             CodeBlock {
                 lang: "forth".to_string(),
@@ -88,6 +268,7 @@ mod tests {
                 virtual_start: 2,
                 virtual_end: 4,
                 content: "5 square .\n".to_string(),
+                ..Default::default()
             },
         ];
 
@@ -113,6 +294,7 @@ mod tests {
                 virtual_start: 0,
                 virtual_end: 2,
                 content: ": square ( n -- n ) dup * ;\n".to_string(),
+                ..Default::default()
             },
             CodeBlock {
                 lang: "forth".to_string(),
@@ -123,6 +305,7 @@ mod tests {
                 virtual_start: 2,
                 virtual_end: 4,
                 content: "5 square .\n".to_string(),
+                ..Default::default()
             },
         ];
 
@@ -135,4 +318,96 @@ mod tests {
         assert_eq!(mline, 8);
         assert_eq!(col, 2);
     }
+
+    #[test]
+    fn test_map_locations_keeps_real_files() {
+        let blocks = vec![CodeBlock {
+            lang: "rust".to_string(),
+            markdown_start: 4,
+            markdown_end: 7,
+            content_start: 5,
+            content_end: 6,
+            virtual_start: 0,
+            virtual_end: 2,
+            content: "use mylib::greet;\nfn main() { greet(); }\n".to_string(),
+            ..Default::default()
+        }];
+        let mapper = PositionMapper::new(&blocks);
+        let virtual_uri = Url::parse("file:///project/target/literate/book.rs").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+        let library_uri = Url::parse("file:///project/mylib/src/lib.rs").unwrap();
+        let location = |uri: &Url, line: u32| Location {
+            uri: uri.clone(),
+            range: Range {
+                start: Position { line, character: 12 },
+                end: Position { line, character: 17 },
+            },
+        };
+
+        let mapped = mapper.map_locations(
+            vec![location(&virtual_uri, 1), location(&library_uri, 1), location(&virtual_uri, 9)],
+            &virtual_uri,
+            &markdown_uri,
+        );
+
+        // The virtual location past the last block is dropped
+        assert_eq!(mapped.len(), 2);
+        assert_eq!(mapped[0], location(&markdown_uri, 6));
+        assert_eq!(mapped[1], location(&library_uri, 1));
+    }
+
+    #[test]
+    fn test_encoding_columns() {
+        let line = "let 😀 = \"数\"; x";
+        let x = line.find('x').unwrap();
+        assert_eq!(PositionEncoding::Utf8.column(line, x), x as u32);
+        assert_eq!(PositionEncoding::Utf16.column(line, x), 14);
+        assert_eq!(PositionEncoding::Utf32.column(line, x), 13);
+        for encoding in PositionEncoding::SUPPORTED {
+            let column = encoding.column(line, x);
+            assert_eq!(encoding.byte_offset(line, column), x);
+        }
+
+        // Inside a surrogate pair snaps to the character start; past the end stays past it
+        assert_eq!(PositionEncoding::Utf16.byte_offset(line, 5), 4);
+        assert_eq!(PositionEncoding::Utf16.byte_offset("ab", 4), 4);
+        assert_eq!(PositionEncoding::Utf16.column("ab", 4), 4);
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let offered = [PositionEncodingKind::new("utf-7"), PositionEncodingKind::UTF8];
+        assert_eq!(PositionEncoding::negotiate(Some(&offered)), PositionEncoding::Utf8);
+        assert_eq!(PositionEncoding::negotiate(None), PositionEncoding::Utf16);
+    }
+
+    #[test]
+    fn test_map_columns_across_encodings() {
+        // A list-item block with an emoji before `name` on its first line
+        let blocks = vec![CodeBlock {
+            lang: "python".to_string(),
+            markdown_start: 2,
+            markdown_end: 5,
+            content_start: 3,
+            content_end: 4,
+            virtual_start: 0,
+            virtual_end: 2,
+            content: "print(\"😀\", name)\nname\n".to_string(),
+            indent: 2,
+            prefixes: vec![2, 2],
+            ..Default::default()
+        }];
+
+        // Editor counts UTF-16, child counts UTF-8: `name` is at UTF-16 column 12 (+2 indent)
+        let mapper = PositionMapper::new(&blocks)
+            .with_encodings(PositionEncoding::Utf16, PositionEncoding::Utf8);
+        assert_eq!(mapper.markdown_to_virtual(3, 14), Some((0, 14)));
+        assert_eq!(mapper.virtual_to_markdown(0, 14), Some((3, 14)));
+
+        // Editor counts UTF-32
+        let mapper = PositionMapper::new(&blocks)
+            .with_encodings(PositionEncoding::Utf32, PositionEncoding::Utf8);
+        assert_eq!(mapper.markdown_to_virtual(3, 13), Some((0, 14)));
+        assert_eq!(mapper.virtual_to_markdown(0, 14), Some((3, 13)));
+    }
 }
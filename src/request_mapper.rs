@@ -1,4 +1,5 @@
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
+use tower_lsp::lsp_types::Url;
 use crate::position::PositionMapper;
 
 /// Recursively rewrite all Position and Range objects in a JSON value
@@ -50,3 +51,216 @@ pub fn rewrite_positions(
 fn is_position_object(map: &serde_json::Map<String, Value>) -> bool {
     map.len() == 2 && map.contains_key("line") && map.contains_key("character")
 }
+
+/// Whether a URI string from a response names the virtual document
+fn is_virtual_uri(uri: &Value, virtual_uri: &Url) -> bool {
+    uri.as_str()
+        .and_then(|uri| Url::parse(uri).ok())
+        .is_some_and(|uri| &uri == virtual_uri)
+}
+
+/// The document whose coordinates an object's positions use, if it names one
+fn document_uri(map: &Map<String, Value>) -> Option<&Value> {
+    map.get("uri")
+        .or_else(|| map.get("textDocument").and_then(|doc| doc.get("uri")))
+}
+
+/// Rewrite a child LSP response from virtual document to markdown coordinates
+///
+/// Only the virtual document URI is replaced by the markdown URI, and only positions in
+/// the virtual document are mapped. Objects naming another file (a dependency, the
+/// standard library, a sibling module) are left untouched. `LocationLink`s are mapped
+/// field by field: `originSelectionRange` is always in the requesting document, while
+/// `targetRange` and `targetSelectionRange` follow `targetUri`. Positions in objects
+/// that name no document refer to the requesting document and are mapped.
+pub fn rewrite_response(
+    value: &mut Value,
+    mapper: &PositionMapper,
+    virtual_uri: &Url,
+    markdown_uri: &Url,
+) {
+    match value {
+        Value::Object(map) => {
+            if is_position_object(map) {
+                rewrite_positions(value, mapper, false);
+                return;
+            }
+
+            if let Some(target_uri) = map.get("targetUri") {
+                let target_is_virtual = is_virtual_uri(target_uri, virtual_uri);
+                if let Some(origin) = map.get_mut("originSelectionRange") {
+                    rewrite_positions(origin, mapper, false);
+                }
+                if target_is_virtual {
+                    map.insert("targetUri".to_string(), json!(markdown_uri));
+                    for key in ["targetRange", "targetSelectionRange"] {
+                        if let Some(range) = map.get_mut(key) {
+                            rewrite_positions(range, mapper, false);
+                        }
+                    }
+                }
+                return;
+            }
+
+            if let Some(uri) = document_uri(map) {
+                if !is_virtual_uri(uri, virtual_uri) {
+                    return;
+                }
+                if map.contains_key("uri") {
+                    map.insert("uri".to_string(), json!(markdown_uri));
+                } else if let Some(doc) = map.get_mut("textDocument") {
+                    doc["uri"] = json!(markdown_uri);
+                }
+            }
+
+            for (key, val) in map.iter_mut() {
+                match (key.as_str(), val) {
+                    ("uri", _) | ("textDocument", _) => {}
+                    // WorkspaceEdit.changes is keyed by document URI
+                    ("changes", Value::Object(changes)) => {
+                        let mut mapped = Map::new();
+                        for (uri, mut edits) in std::mem::take(changes) {
+                            if is_virtual_uri(&json!(uri), virtual_uri) {
+                                rewrite_response(&mut edits, mapper, virtual_uri, markdown_uri);
+                                mapped.insert(markdown_uri.to_string(), edits);
+                            } else {
+                                mapped.insert(uri, edits);
+                            }
+                        }
+                        *changes = mapped;
+                    }
+                    (_, val) => rewrite_response(val, mapper, virtual_uri, markdown_uri),
+                }
+            }
+        }
+        Value::Array(arr) => {
+            for val in arr.iter_mut() {
+                rewrite_response(val, mapper, virtual_uri, markdown_uri);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_doc::build_virtual_document;
+
+    const BOOK: &str = "# Book\n\n```rust\nuse std::fmt;\n```\n\nText\n\n```rust\nfn main() {}\n```\n";
+
+    fn range(line: u32, start: u32, end: u32) -> Value {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end }
+        })
+    }
+
+    #[test]
+    fn test_rewrite_locations_only_for_virtual_uri() {
+        let vdoc = build_virtual_document(BOOK, "rust");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let virtual_uri = Url::parse("file:///project/target/literate/book.rs").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+
+        let mut response = json!({ "result": [
+            { "uri": virtual_uri, "range": range(2, 3, 7) },
+            { "uri": "file:///rustlib/src/fmt/mod.rs", "range": range(2, 3, 7) }
+        ]});
+        rewrite_response(&mut response, &mapper, &virtual_uri, &markdown_uri);
+
+        assert_eq!(response["result"][0]["uri"], json!(markdown_uri));
+        assert_eq!(response["result"][0]["range"], range(9, 3, 7));
+        assert_eq!(response["result"][1]["uri"], "file:///rustlib/src/fmt/mod.rs");
+        assert_eq!(response["result"][1]["range"], range(2, 3, 7));
+    }
+
+    #[test]
+    fn test_rewrite_location_links() {
+        let vdoc = build_virtual_document(BOOK, "rust");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let virtual_uri = Url::parse("file:///project/target/literate/book.rs").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+
+        let mut response = json!({ "result": [
+            {
+                "originSelectionRange": range(0, 4, 7),
+                "targetUri": "file:///rustlib/src/fmt/mod.rs",
+                "targetRange": range(0, 0, 20),
+                "targetSelectionRange": range(0, 4, 7)
+            },
+            {
+                "originSelectionRange": range(2, 3, 7),
+                "targetUri": virtual_uri,
+                "targetRange": range(2, 0, 12),
+                "targetSelectionRange": range(2, 3, 7)
+            }
+        ]});
+        rewrite_response(&mut response, &mapper, &virtual_uri, &markdown_uri);
+
+        let dependency = &response["result"][0];
+        assert_eq!(dependency["originSelectionRange"], range(3, 4, 7));
+        assert_eq!(dependency["targetUri"], "file:///rustlib/src/fmt/mod.rs");
+        assert_eq!(dependency["targetRange"], range(0, 0, 20));
+
+        let local = &response["result"][1];
+        assert_eq!(local["originSelectionRange"], range(9, 3, 7));
+        assert_eq!(local["targetUri"], json!(markdown_uri));
+        assert_eq!(local["targetRange"], range(9, 0, 12));
+        assert_eq!(local["targetSelectionRange"], range(9, 3, 7));
+    }
+
+    #[test]
+    fn test_rewrite_unnamed_positions_and_changes() {
+        let vdoc = build_virtual_document(BOOK, "rust");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let virtual_uri = Url::parse("file:///project/target/literate/book.rs").unwrap();
+        let markdown_uri = Url::parse("file:///project/book.md").unwrap();
+
+        let mut response = json!({ "result": {
+            "contents": "fn main()",
+            "range": range(2, 3, 7),
+            "edit": { "changes": {
+                virtual_uri.as_str(): [{ "range": range(0, 4, 7), "newText": "x" }],
+                "file:///project/src/lib.rs": [{ "range": range(0, 4, 7), "newText": "x" }]
+            }}
+        }});
+        rewrite_response(&mut response, &mapper, &virtual_uri, &markdown_uri);
+
+        let result = &response["result"];
+        assert_eq!(result["range"], range(9, 3, 7));
+        let changes = &result["edit"]["changes"];
+        assert_eq!(changes[markdown_uri.as_str()][0]["range"], range(3, 4, 7));
+        assert_eq!(changes["file:///project/src/lib.rs"][0]["range"], range(0, 4, 7));
+        assert!(changes.get(virtual_uri.as_str()).is_none());
+    }
+
+    #[test]
+    fn test_rewrite_positions_with_line_prefixes() {
+        // Quote markers of different widths on each line
+        let markdown = "> ```python\n>x = 1\n> if x:\n>     y = 2\n> ```\n";
+        let vdoc = build_virtual_document(markdown, "python");
+        assert_eq!(vdoc.content, "x = 1\nif x:\n    y = 2\n");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+
+        // `x` on each line, in markdown coordinates
+        let mut params = json!({ "positions": [
+            { "line": 1, "character": 1 },
+            { "line": 2, "character": 5 },
+            { "line": 3, "character": 6 }
+        ]});
+        rewrite_positions(&mut params, &mapper, true);
+        assert_eq!(params["positions"], json!([
+            { "line": 0, "character": 0 },
+            { "line": 1, "character": 3 },
+            { "line": 2, "character": 4 }
+        ]));
+
+        rewrite_positions(&mut params, &mapper, false);
+        assert_eq!(params["positions"], json!([
+            { "line": 1, "character": 1 },
+            { "line": 2, "character": 5 },
+            { "line": 3, "character": 6 }
+        ]));
+    }
+}
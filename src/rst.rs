@@ -0,0 +1,139 @@
+use crate::fence::{indent_of, Fence, LineSource};
+use crate::format::BlockExtractor;
+
+/// Finds the code directives of reStructuredText documents
+pub struct RstExtractor;
+
+impl BlockExtractor for RstExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_code_directives(text)
+    }
+}
+
+/// Indentation and argument of a `.. code-block:: lang` line (or `code`, `sourcecode`)
+fn directive(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start();
+    let rest = trimmed.strip_prefix("..")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let (name, argument) = rest.split_once("::")?;
+    matches!(name.trim(), "code-block" | "code" | "sourcecode").then(|| (indent_of(line), argument.trim()))
+}
+
+/// Find the code directives of a reStructuredText document
+///
+/// The body of a directive is every following line indented past it, after its
+/// `:option:` lines and a blank line. Body lines lose their common indentation. There is
+/// no closing line: the block ends one past its last code line.
+pub fn scan_code_directives(text: &str) -> Vec<Fence> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut blocks = Vec::new();
+    let mut start_line = 0;
+    while start_line < lines.len() {
+        let (indent, argument) = match directive(lines[start_line]) {
+            Some(directive) => directive,
+            None => {
+                start_line += 1;
+                continue;
+            }
+        };
+
+        let mut end_line = start_line + 1;
+        while end_line < lines.len()
+            && (lines[end_line].trim().is_empty() || indent_of(lines[end_line]) > indent)
+        {
+            end_line += 1;
+        }
+        // Trailing blank lines separate the block from what follows
+        while end_line > start_line + 1 && lines[end_line - 1].trim().is_empty() {
+            end_line -= 1;
+        }
+        let mut first = start_line + 1;
+        while first < end_line && lines[first].trim_start().starts_with(':') {
+            first += 1;
+        }
+        while first < end_line && lines[first].trim().is_empty() {
+            first += 1;
+        }
+
+        let body = &lines[first..end_line];
+        let dedent = body
+            .iter()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| indent_of(line))
+            .min()
+            .unwrap_or(0);
+        let mut fence = Fence {
+            fence_char: '.',
+            fence_len: 2,
+            indent,
+            info: argument.to_string(),
+            start_line,
+            end_line,
+            lines: Vec::new(),
+            prefixes: Vec::new(),
+            sources: Vec::new(),
+            scope_session: None,
+            options: Vec::new(),
+        };
+        // Options and the blank line put the code further down than the line after the directive
+        for (offset, line) in body.iter().enumerate() {
+            let prefix = if line.trim().is_empty() { line.len() } else { dedent };
+            fence.lines.push(line[prefix..].to_string());
+            fence.prefixes.push(prefix);
+            fence.sources.push(LineSource::new(first + offset, line, prefix));
+        }
+        blocks.push(fence);
+        start_line = end_line;
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_directives() {
+        let text = "\
+Example
+=======
+
+.. code-block:: python
+   :linenos:
+   :caption: Squares
+
+   def square(x):
+
+       return x * x
+
+Text after.
+
+- Item
+
+  .. code:: rust
+
+     fn main() {}
+";
+        let blocks = scan_code_directives(text);
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].lang(), "python");
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (3, 10));
+        assert_eq!(blocks[0].lines, vec!["def square(x):", "", "    return x * x"]);
+        assert_eq!((blocks[0].content_start(), blocks[0].content_end()), (7, 9));
+        assert_eq!(blocks[0].prefixes, vec![3, 0, 3]);
+
+        assert_eq!(blocks[1].lang(), "rust");
+        assert_eq!(blocks[1].indent, 2);
+        assert_eq!(blocks[1].lines, vec!["fn main() {}"]);
+        assert_eq!(blocks[1].content_line(0), 17);
+    }
+
+    #[test]
+    fn test_other_directives_are_not_code() {
+        let text = ".. note:: Not code\n\n   text\n\n..code-block:: rust\n\n   fn a() {}\n";
+        assert!(scan_code_directives(text).is_empty());
+    }
+}
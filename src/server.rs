@@ -1,82 +1,219 @@
-use crate::child_lsp::ChildLspManager;
+use crate::child_lsp::{ChildExit, ChildLspManager};
 use crate::child_lsp_init::{ChildLspInitializer, ChildLspInitParams};
-use crate::config::Config;
-use crate::disk_vdoc::DiskVirtualDoc;
-use crate::position::PositionMapper;
+use crate::chunks;
+use crate::config::{Config, LanguageAliases, LanguageServerCommand};
+use crate::diagnostics;
+use crate::disk_vdoc::{document_filename, session_filename, DiskVirtualDoc};
+use crate::document_store::{Document, DocumentStore};
+use crate::features::{self, Combine, Feature};
+use crate::fence::{find_fence_at_line, Fence};
+use crate::format::{get_document_language, DocumentFormat};
+use crate::notebook_sync::{
+    self, DidChangeNotebookDocumentParams, DidCloseNotebookDocumentParams, DidOpenNotebookDocumentParams, Layout,
+    Notebook,
+};
+use crate::position::{PositionEncoding, PositionMapper};
 use crate::request_mapper;
-use crate::virtual_doc::{build_virtual_document, find_code_block_at_line};
+use crate::sync;
+use crate::virtual_doc::{collect_languages, collect_sessions, VirtualDocument};
+use crate::workspace_edit;
 use crate::utils::constants;
+use crate::utils::uri_helpers::{construct_disk_uri, extract_filename};
 use regex::Regex;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tower_lsp::jsonrpc::Result as JsonrpcResult;
+use tokio::sync::{mpsc, Mutex, OnceCell, RwLock};
+use tower_lsp::jsonrpc::{Error as JsonrpcError, ErrorCode, Result as JsonrpcResult};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer};
 use tracing::{debug, info, warn};
 
+/// Child LSPs are shared by all documents of a project: (project root, language, server name)
+type ChildKey = (PathBuf, String, String);
+
+/// A child LSP start in progress, shared by every request waiting for it
+type PendingChild = Arc<OnceCell<Arc<ChildLspManager>>>;
+
+/// A virtual document is open in every server of its language: (virtual document URI, server name)
+type VirtualDocKey = (Url, String);
+
+/// Mapped diagnostics per markdown document, per virtual document and server
+///
+/// literate-lsp's own diagnostics (broken chunk references) are keyed by the markdown URI
+/// itself, with no server name.
+type DiagnosticsCache = HashMap<Url, HashMap<VirtualDocKey, Vec<Diagnostic>>>;
+
+/// A virtual document opened in a child LSP on behalf of a markdown document
+#[derive(Debug, Clone)]
+struct OpenVirtualDoc {
+    markdown_uri: Url,
+    lang: String,
+    /// Session of the blocks in the document; `None` for the default session
+    session: Option<String>,
+    child_key: ChildKey,
+    version: i32,
+    /// Content last sent to the child
+    content: String,
+    /// Diagnostics last published by the child, in virtual document coordinates
+    diagnostics: Vec<Diagnostic>,
+}
+
+/// Where a crashed child LSP is in its restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RestartState {
+    /// Waiting out the backoff before restart `attempt`
+    Restarting { attempt: u32 },
+    /// Running again after `attempt` consecutive crashes
+    Restarted { attempt: u32 },
+    /// Crashed more than `CHILD_LSP_MAX_RESTARTS` times in a row
+    GaveUp,
+}
+
+/// Crash restarts and failed starts of the child LSPs, kept across their processes
+#[derive(Debug, Default)]
+struct Restarts(HashMap<ChildKey, RestartState>);
+
+impl Restarts {
+    /// Record a crash and return the restart attempt, or `None` once the restarts are used up
+    ///
+    /// A child that ran for `CHILD_LSP_STABLE_SECS` before crashing starts counting again.
+    fn crashed(&mut self, child_key: &ChildKey, uptime: std::time::Duration) -> Option<u32> {
+        let previous = match self.0.get(child_key) {
+            Some(RestartState::Restarted { attempt })
+                if uptime < std::time::Duration::from_secs(constants::CHILD_LSP_STABLE_SECS) =>
+            {
+                *attempt
+            }
+            _ => 0,
+        };
+        let attempt = previous + 1;
+        if attempt > constants::CHILD_LSP_MAX_RESTARTS {
+            self.0.insert(child_key.clone(), RestartState::GaveUp);
+            return None;
+        }
+        self.0.insert(child_key.clone(), RestartState::Restarting { attempt });
+        Some(attempt)
+    }
+
+    /// The backoff is over and the child may be spawned again
+    fn restarting(&mut self, child_key: &ChildKey) {
+        if let Some(RestartState::Restarting { attempt }) = self.0.get(child_key).copied() {
+            self.0.insert(child_key.clone(), RestartState::Restarted { attempt });
+        }
+    }
+
+    /// Whether a process may be spawned for the child: not during its backoff, nor after giving up
+    fn may_spawn(&self, child_key: &ChildKey) -> bool {
+        !matches!(
+            self.0.get(child_key),
+            Some(RestartState::Restarting { .. }) | Some(RestartState::GaveUp)
+        )
+    }
+
+    /// Give the children that were given up on a fresh restart budget
+    fn forgive(&mut self) {
+        self.0.retain(|_, state| *state != RestartState::GaveUp);
+    }
+}
+
+/// A position request routed to the child LSPs serving the code block under the cursor
+struct RoutedRequest {
+    /// Servers of the block's language that serve the request, in the order they are configured
+    children: Vec<Arc<ChildLspManager>>,
+    /// URI of the virtual document in the children
+    file_uri: String,
+    lang: String,
+    vdoc: VirtualDocument,
+}
+
+/// A request made on a cell of a synced notebook, served from the notebook's markdown document
+struct CellRequest {
+    cell: Url,
+    layout: Layout,
+}
+
+/// Move a typed result from a notebook's markdown document to the cell it was requested on
+fn cell_result<T: Serialize + DeserializeOwned>(cell: &Option<CellRequest>, result: T) -> T {
+    let cell = match cell {
+        Some(cell) => cell,
+        None => return result,
+    };
+    let mut value = match serde_json::to_value(&result) {
+        Ok(value) => value,
+        Err(_) => return result,
+    };
+    cell.layout.rewrite_response(&mut value, &cell.cell);
+    serde_json::from_value(value).unwrap_or(result)
+}
+
+impl RoutedRequest {
+    /// Mapper between the editor's markdown positions and a child's virtual document
+    fn mapper(&self, child_lsp: &ChildLspManager, client_encoding: PositionEncoding) -> PositionMapper<'_> {
+        PositionMapper::new(&self.vdoc.blocks)
+            .with_encodings(client_encoding, child_lsp.position_encoding())
+    }
+}
+
+#[derive(Clone)]
 pub struct LiterateLsp {
     client: Client,
-    config: Config,
-    document: Arc<RwLock<Option<String>>>,
-    document_uri: Arc<RwLock<Option<Url>>>,
-    document_version: Arc<RwLock<i32>>,
-    child_lsps: Arc<RwLock<std::collections::HashMap<String, ChildLspManager>>>,
-    child_versions: Arc<RwLock<std::collections::HashMap<String, i32>>>,
-    completion_triggers: Arc<RwLock<std::collections::HashMap<String, Vec<String>>>>,
-    project_root: Arc<RwLock<Option<std::path::PathBuf>>>,
-    output_dir: Arc<RwLock<String>>,
+    config: Arc<Config>,
+    /// Language names from `config`, built once and shared by every document
+    aliases: Arc<LanguageAliases>,
+    documents: Arc<RwLock<DocumentStore>>,
+    child_lsps: Arc<RwLock<HashMap<ChildKey, Arc<ChildLspManager>>>>,
+    /// Child LSPs being spawned and initialized, outside the `child_lsps` lock
+    spawning: Arc<Mutex<HashMap<ChildKey, PendingChild>>>,
+    virtual_docs: Arc<RwLock<HashMap<VirtualDocKey, OpenVirtualDoc>>>,
+    completion_triggers: Arc<RwLock<HashMap<String, Vec<String>>>>,
+    diagnostics: Arc<RwLock<DiagnosticsCache>>,
+    /// Notebooks synced with `notebookDocument/*`, also open in `documents` as markdown
+    notebooks: Arc<RwLock<HashMap<Url, Notebook>>>,
+    /// Crash restarts per child LSP; no process is spawned while one is restarting or given up
+    restarts: Arc<RwLock<Restarts>>,
+    /// Position encoding negotiated with the editor in `initialize`
+    position_encoding: Arc<std::sync::OnceLock<PositionEncoding>>,
+    /// Whether the editor can register notebook sync, set by `NotebookCapabilities`
+    notebook_registration: Arc<std::sync::OnceLock<bool>>,
 }
 
 impl LiterateLsp {
     pub fn new(client: Client, config: Config) -> Self {
         LiterateLsp {
             client,
-            config,
-            document: Arc::new(RwLock::new(None)),
-            document_uri: Arc::new(RwLock::new(None)),
-            document_version: Arc::new(RwLock::new(0)),
-            child_lsps: Arc::new(RwLock::new(std::collections::HashMap::new())),
-            child_versions: Arc::new(RwLock::new(std::collections::HashMap::new())),
-            completion_triggers: Arc::new(RwLock::new(std::collections::HashMap::new())),
-            project_root: Arc::new(RwLock::new(None)),
-            output_dir: Arc::new(RwLock::new("./src".to_string())),
-        }
-    }
-
-    /// Get the language of the document based on file extension
-    fn get_document_language(uri: &Url) -> Option<String> {
-        let path = uri.path();
-        let ext = path.rsplit('.').next()?.to_lowercase();
-        match ext.as_str() {
-            // Markdown variants
-            "md" | "markdown" | "mdown" | "mkdn" | "mdx" | "mmd" => Some("markdown".to_string()),
-            // Typst
-            "typ" => Some("typst".to_string()),
-            // Other languages
-            "go" => Some("go".to_string()),
-            "forth" | "fth" => Some("forth".to_string()),
-            _ => None,
-        }
-    }
-
-    /// Check if a code block language should be skipped for this document
-    /// Skips self-referential cases like markdown blocks in markdown files
-    fn should_skip_language(doc_lang: Option<&str>, block_lang: &str) -> bool {
-        match doc_lang {
-            Some("markdown") if block_lang == "markdown" => true,
-            Some("typst") if block_lang == "typst" => true,
-            Some("go") if block_lang == "go" => true,
-            Some("forth") if block_lang == "forth" => true,
-            _ => false,
+            aliases: Arc::new(config.language_aliases()),
+            config: Arc::new(config),
+            documents: Arc::new(RwLock::new(DocumentStore::new())),
+            child_lsps: Arc::new(RwLock::new(HashMap::new())),
+            spawning: Arc::new(Mutex::new(HashMap::new())),
+            virtual_docs: Arc::new(RwLock::new(HashMap::new())),
+            completion_triggers: Arc::new(RwLock::new(HashMap::new())),
+            diagnostics: Arc::new(RwLock::new(HashMap::new())),
+            notebooks: Arc::new(RwLock::new(HashMap::new())),
+            restarts: Arc::new(RwLock::new(Restarts::default())),
+            position_encoding: Arc::new(std::sync::OnceLock::new()),
+            notebook_registration: Arc::new(std::sync::OnceLock::new()),
         }
     }
 
+    /// Where `NotebookCapabilities` records whether the editor can register notebook sync
+    pub fn notebook_registration(&self) -> Arc<std::sync::OnceLock<bool>> {
+        Arc::clone(&self.notebook_registration)
+    }
+
+    /// Position encoding of the editor's positions
+    fn client_encoding(&self) -> PositionEncoding {
+        self.position_encoding.get().copied().unwrap_or_default()
+    }
+
     /// Cache completion trigger characters from a child LSP
-    async fn cache_completion_triggers(&self, lang: &str, child_lsp: &ChildLspManager) {
+    async fn cache_completion_triggers(&self, server: &str, child_lsp: &ChildLspManager) {
         if let Some(triggers) = child_lsp.get_completion_trigger_characters().await {
             let mut cache = self.completion_triggers.write().await;
-            cache.insert(lang.to_string(), triggers);
+            cache.insert(server.to_string(), triggers);
         }
     }
 
@@ -92,6 +229,22 @@ impl LiterateLsp {
         triggers.into_iter().collect()
     }
 
+    /// Markdown file name of a session's virtual documents, unique within the project
+    fn markdown_filename(uri: &Url, project_root: &std::path::Path, session: Option<&str>) -> String {
+        let filename = match uri.to_file_path() {
+            Ok(path) => document_filename(project_root, &path),
+            Err(_) => extract_filename(uri).to_string(),
+        };
+        session_filename(&filename, session)
+    }
+
+    /// URI of the file a virtual document is written to, without writing it
+    fn virtual_doc_uri(&self, project_root: &std::path::Path, output_dir: &str, markdown_filename: &str, lang: &str) -> String {
+        let extension = self.config.get_extension_for_language(lang)
+            .unwrap_or_else(|| lang.to_string());
+        construct_disk_uri(&DiskVirtualDoc::output_path(project_root, output_dir, markdown_filename, &extension))
+    }
+
     /// Helper method to write virtual document to disk and get file URI
     fn write_virtual_doc_to_disk(
         &self,
@@ -126,55 +279,621 @@ impl LiterateLsp {
         format!("file://{}", output_path.display())
     }
 
-    /// Update all child LSPs with changed virtual documents
-    async fn update_child_lsps(&self, doc_content: &str, _new_version: i32) {
-        let mut child_lsps = self.child_lsps.write().await;
-        let mut child_versions = self.child_versions.write().await;
-        let uri = self.document_uri.read().await;
-        let markdown_filename = match uri.as_ref() {
-            Some(u) => {
-                u
-                    .path_segments()
-                    .and_then(|mut segs| segs.next_back())
-                    .unwrap_or("document")
-                    .to_string()
-            },
+    /// Spawn and initialize a child LSP, then start forwarding its diagnostics
+    ///
+    /// Diagnostics of a server configured without the `diagnostics` feature are dropped.
+    async fn spawn_child_lsp(
+        &self,
+        lang: &str,
+        server: &LanguageServerCommand,
+        root_uri: String,
+        file_uri: String,
+        file_content: String,
+    ) -> Result<Arc<ChildLspManager>, String> {
+        let init_params = ChildLspInitParams {
+            lang: lang.to_string(),
+            binary_name: server.command.clone(),
+            args: server.args.clone(),
+            root_uri,
+            file_uri,
+            file_content,
+            init_options: self.config.get_init_options(&server.name),
+        };
+
+        let result = ChildLspInitializer::initialize_child_lsp(init_params).await?;
+        // Cache completion triggers after successful initialization
+        self.cache_completion_triggers(&server.name, &result.lsp).await;
+        if let Some(rx) = result.lsp.take_diagnostics().await {
+            if server.serves(Feature::Diagnostics) {
+                self.forward_diagnostics(rx, result.lsp.position_encoding(), server.name.clone());
+            }
+        }
+        Ok(Arc::new(result.lsp))
+    }
+
+    /// Republish a child's diagnostics against the markdown documents they belong to
+    ///
+    /// Diagnostics for a virtual document are mapped back through `PositionMapper`
+    /// and merged with those of the document's other virtual documents and other servers,
+    /// since publishing replaces every diagnostic the editor holds for the markdown URI.
+    fn forward_diagnostics(
+        &self,
+        mut rx: mpsc::UnboundedReceiver<PublishDiagnosticsParams>,
+        child_encoding: PositionEncoding,
+        server_name: String,
+    ) {
+        let client_encoding = self.client_encoding();
+        let server = self.clone();
+
+        tokio::spawn(async move {
+            while let Some(params) = rx.recv().await {
+                let key = (params.uri.clone(), server_name.clone());
+                let open = match server.virtual_docs.write().await.get_mut(&key) {
+                    Some(open) => {
+                        // Kept to map them again when an edit moves the blocks
+                        open.diagnostics = params.diagnostics.clone();
+                        open.clone()
+                    }
+                    None => {
+                        debug!("[Diagnostics] Ignoring diagnostics for {}", params.uri);
+                        continue;
+                    }
+                };
+                let doc = match server.documents.read().await.get(&open.markdown_uri) {
+                    Some(doc) => doc.clone(),
+                    None => continue,
+                };
+
+                let vdoc = doc.virtual_document(&doc.fences(), &open.lang, open.session.as_deref());
+                let mapper = PositionMapper::new(&vdoc.blocks).with_encodings(client_encoding, child_encoding);
+                let mapped = diagnostics::map_diagnostics(
+                    params.diagnostics,
+                    &mapper,
+                    &params.uri,
+                    &open.markdown_uri,
+                );
+                debug!("[Diagnostics] {} diagnostics for '{}' in {}", mapped.len(), open.lang, open.markdown_uri);
+
+                let all = {
+                    let mut cache = server.diagnostics.write().await;
+                    let per_vdoc = cache.entry(open.markdown_uri.clone()).or_default();
+                    per_vdoc.insert(key, mapped);
+                    per_vdoc.values().flatten().cloned().collect::<Vec<_>>()
+                };
+                server.publish(open.markdown_uri, all).await;
+            }
+        });
+    }
+
+    /// Watch a child LSP and restart it if its process dies
+    ///
+    /// Only a weak reference is held, so a child removed from `child_lsps` (on shutdown
+    /// or after being replaced) is not restarted.
+    fn supervise_child(&self, child_key: ChildKey, child_lsp: &Arc<ChildLspManager>) {
+        let server = self.clone();
+        let weak = Arc::downgrade(child_lsp);
+        let mut exit_rx = child_lsp.exit_watch();
+
+        tokio::spawn(async move {
+            let exit = match exit_rx.wait_for(|exit| exit.is_some()).await {
+                Ok(exit) => exit.clone(),
+                Err(_) => return,
+            };
+            let exit = match exit {
+                Some(exit) => exit,
+                None => return,
+            };
+            // The weak reference keeps the allocation, so the pointer cannot be reused
+            let still_registered = {
+                let mut child_lsps = server.child_lsps.write().await;
+                let current = child_lsps.get(&child_key).map(Arc::as_ptr);
+                if current.is_some() && current == Some(weak.as_ptr()) {
+                    child_lsps.remove(&child_key);
+                    true
+                } else {
+                    false
+                }
+            };
+            if still_registered {
+                server.restart_child(child_key, exit).await;
+            }
+        });
+    }
+
+    /// Report a crashed child LSP and respawn it with exponential backoff
+    ///
+    /// Every virtual document the child had open is reopened in the new process.
+    /// No other request spawns the child during the backoff. After `CHILD_LSP_MAX_RESTARTS`
+    /// consecutive crashes the language is left without that server until the editor
+    /// opens another document.
+    async fn restart_child(&self, child_key: ChildKey, exit: ChildExit) {
+        let (_, lang, server_name) = child_key.clone();
+        let stale: Vec<OpenVirtualDoc> = {
+            let mut virtual_docs = self.virtual_docs.write().await;
+            let keys: Vec<VirtualDocKey> = virtual_docs
+                .iter()
+                .filter(|(_, open)| open.child_key == child_key)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.iter().filter_map(|key| virtual_docs.remove(key)).collect()
+        };
+
+        let attempt = self.restarts.write().await.crashed(&child_key, exit.uptime);
+
+        let mut message = format!("The '{}' language server exited ({}).", server_name, exit);
+        if !exit.stderr_tail.is_empty() {
+            message.push_str(&format!("\n\nLast output:\n{}", exit.stderr_tail.join("\n")));
+        }
+        warn!("[LiterateLsp] {}", message);
+
+        let attempt = match attempt {
+            Some(attempt) => attempt,
+            None => {
+                message.push_str(&format!(
+                    "\n\nGiving up after {} restarts.",
+                    constants::CHILD_LSP_MAX_RESTARTS
+                ));
+                self.client.show_message(MessageType::ERROR, message).await;
+                return;
+            }
+        };
+
+        let delay = constants::CHILD_LSP_RESTART_BACKOFF_MS << (attempt - 1);
+        message.push_str(&format!(
+            "\n\nRestarting in {} ms (attempt {} of {}).",
+            delay,
+            attempt,
+            constants::CHILD_LSP_MAX_RESTARTS
+        ));
+        self.client.show_message(MessageType::WARNING, message).await;
+        tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+        self.restarts.write().await.restarting(&child_key);
+
+        let server = match self
+            .config
+            .get_language_servers(&lang)
+            .into_iter()
+            .find(|server| server.name == server_name)
+        {
+            Some(server) => server,
             None => return,
         };
-        drop(uri);
+        let mut reopen: Vec<(Url, Option<String>)> = stale
+            .into_iter()
+            .map(|open| (open.markdown_uri, open.session))
+            .collect();
+        reopen.sort();
+        reopen.dedup();
+        for (uri, session) in reopen {
+            let doc = match self.documents.read().await.get(&uri).cloned() {
+                Some(doc) => doc,
+                None => continue,
+            };
+            let vdoc = doc.virtual_document(&doc.fences(), &lang, session.as_deref());
+            self.open_virtual_doc(&uri, &doc, &lang, session.as_deref(), &vdoc.content, &server)
+                .await;
+        }
+    }
+
+    /// Hold off a child LSP that failed to start the way a crashed one is held off
+    ///
+    /// A missing binary, or a server that exits or fails during `initialize`, never
+    /// reaches `supervise_child`; without this every request would spawn it again.
+    /// Returns the error with the backoff appended.
+    async fn start_failed(&self, child_key: &ChildKey, error: String) -> String {
+        let attempt = self.restarts.write().await.crashed(child_key, std::time::Duration::ZERO);
+        let attempt = match attempt {
+            Some(attempt) => attempt,
+            None => {
+                let message = format!("{}\n\nGiving up after {} attempts.", error, constants::CHILD_LSP_MAX_RESTARTS);
+                self.client.show_message(MessageType::ERROR, &message).await;
+                return message;
+            }
+        };
 
-        let project_root = self.project_root.read().await;
-        let output_dir = self.output_dir.read().await;
+        let delay = constants::CHILD_LSP_RESTART_BACKOFF_MS << (attempt - 1);
+        let (server, child_key) = (self.clone(), child_key.clone());
+        tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+            server.restarts.write().await.restarting(&child_key);
+        });
+        format!(
+            "{}\n\nRetrying in {} ms (attempt {} of {}).",
+            error,
+            delay,
+            attempt,
+            constants::CHILD_LSP_MAX_RESTARTS
+        )
+    }
 
-        for (lang, child_lsp) in child_lsps.iter_mut() {
-            let vdoc = build_virtual_document(doc_content, lang);
+    /// Write a document's virtual file for a `lang` session and make sure one of the project's
+    /// child LSPs has it open
+    ///
+    /// The child is spawned on first use and shared with every other document and
+    /// session of the same project. A child that failed to start or crashed is not
+    /// spawned during its backoff or after it was given up. Returns the child and the
+    /// virtual file URI, or `None` after logging why the server cannot serve the language.
+    async fn open_virtual_doc(
+        &self,
+        uri: &Url,
+        doc: &Document,
+        lang: &str,
+        session: Option<&str>,
+        content: &str,
+        server: &LanguageServerCommand,
+    ) -> Option<(Arc<ChildLspManager>, String)> {
+        let project_root = match doc.project_root.as_ref() {
+            Some(root) => root,
+            None => {
+                warn!("Project root not detected, cannot write virtual document to disk");
+                return None;
+            }
+        };
 
-            // Write virtual doc to disk
-            let file_uri = match project_root.as_ref() {
-                Some(root) => {
-                    match self.write_virtual_doc_to_disk(root, &output_dir, &markdown_filename, lang, vdoc.content.clone()) {
-                        Ok(uri) => uri,
-                        Err(e) => {
-                            warn!("Failed to write virtual doc for '{}': {}", lang, e);
-                            continue;
+        let markdown_filename = Self::markdown_filename(uri, project_root, session);
+        let planned_uri = self.virtual_doc_uri(project_root, &doc.output_dir, &markdown_filename, lang);
+        let virtual_uri = match Url::parse(&planned_uri) {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("Invalid virtual document URI '{}': {}", planned_uri, e);
+                return None;
+            }
+        };
+
+        // Overwriting another document's virtual file would leave the child analysing a mix of both
+        let owner = self
+            .virtual_docs
+            .read()
+            .await
+            .iter()
+            .find(|((open_uri, _), open)| {
+                open_uri == &virtual_uri && (&open.markdown_uri != uri || open.session.as_deref() != session)
+            })
+            .map(|(_, open)| open.markdown_uri.clone());
+        if let Some(owner) = owner {
+            let message = format!("conflict: {} would be written by {} and {}, keeping the first", virtual_uri, owner, uri);
+            warn!("[LiterateLsp] {}", message);
+            self.client.log_message(MessageType::WARNING, message).await;
+            return None;
+        }
+
+        let file_uri = match self.write_virtual_doc_to_disk(project_root, &doc.output_dir, &markdown_filename, lang, content.to_string()) {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("Failed to write virtual document to disk: {}", e);
+                return None;
+            }
+        };
+
+        let child_key: ChildKey = (project_root.clone(), lang.to_string(), server.name.clone());
+        if !self.restarts.read().await.may_spawn(&child_key) {
+            debug!("[LiterateLsp] '{}' is restarting or was given up, not spawning it", server.name);
+            return None;
+        }
+        let existing = self.child_lsps.read().await.get(&child_key).cloned();
+        let (child_lsp, spawned) = match existing {
+            Some(lsp) => (lsp, false),
+            None => {
+                // Concurrent requests for the same child wait on one start; other children stay reachable
+                let cell = Arc::clone(self.spawning.lock().await.entry(child_key.clone()).or_default());
+                let mut spawned = false;
+                let result = cell
+                    .get_or_try_init(|| async {
+                        // Another request may have finished the start before this one got the cell
+                        if let Some(lsp) = self.child_lsps.read().await.get(&child_key) {
+                            return Ok(Arc::clone(lsp));
+                        }
+                        // Or failed it, which starts the backoff
+                        if !self.restarts.read().await.may_spawn(&child_key) {
+                            return Err(format!("'{}' failed to start, not spawning it again yet", server.name));
                         }
+                        let root_uri = self.get_lsp_root_uri(project_root, &doc.output_dir);
+                        let lsp = match self
+                            .spawn_child_lsp(lang, server, root_uri, file_uri.clone(), content.to_string())
+                            .await
+                        {
+                            Ok(lsp) => lsp,
+                            Err(error) => return Err(self.start_failed(&child_key, error).await),
+                        };
+                        self.child_lsps.write().await.insert(child_key.clone(), Arc::clone(&lsp));
+                        self.supervise_child(child_key.clone(), &lsp);
+                        spawned = true;
+                        Ok::<_, String>(lsp)
+                    })
+                    .await
+                    .cloned();
+                {
+                    let mut spawning = self.spawning.lock().await;
+                    if spawning.get(&child_key).is_some_and(|current| Arc::ptr_eq(current, &cell)) {
+                        spawning.remove(&child_key);
                     }
                 }
-                None => {
-                    warn!("Project root not available for language '{}'", lang);
+                match result {
+                    Ok(lsp) => (lsp, spawned),
+                    Err(error_msg) => {
+                        self.client
+                            .log_message(MessageType::ERROR, error_msg)
+                            .await;
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let mut virtual_docs = self.virtual_docs.write().await;
+        if let std::collections::hash_map::Entry::Vacant(entry) = virtual_docs.entry((virtual_uri, server.name.clone())) {
+            // A freshly spawned child opened the document during initialization
+            if !spawned {
+                if let Err(e) = child_lsp.did_open(file_uri.clone(), lang.to_string(), content.to_string()).await {
+                    warn!("Failed to open virtual document for '{}' in '{}': {}", lang, server.name, e);
+                    return None;
+                }
+            }
+            entry.insert(OpenVirtualDoc {
+                markdown_uri: uri.clone(),
+                lang: lang.to_string(),
+                session: session.map(str::to_string),
+                child_key,
+                version: 1,
+                content: content.to_string(),
+                diagnostics: Vec::new(),
+            });
+        }
+
+        Some((child_lsp, file_uri))
+    }
+
+    /// Start child LSPs for every language in the document
+    ///
+    /// Children only publish diagnostics for documents they have opened, so they are
+    /// started eagerly instead of waiting for the first position request.
+    async fn start_child_lsps(&self, uri: &Url, doc: &Document, fences: &[Fence]) {
+        let langs = collect_languages(fences, &doc.aliases);
+        self.open_sessions(uri, doc, fences, &langs).await;
+    }
+
+    /// Open the virtual document of every session of `langs` in every server of the
+    /// language that does not have it open yet
+    async fn open_sessions(&self, uri: &Url, doc: &Document, fences: &[Fence], langs: &[String]) {
+        let doc_lang = get_document_language(uri);
+
+        for lang in langs {
+            if self.config.skips_language(doc_lang.as_deref(), lang) {
+                continue;
+            }
+            let servers = self.config.get_language_servers(lang);
+            if servers.is_empty() {
+                continue;
+            }
+
+            for session in collect_sessions(fences, &doc.aliases, lang) {
+                let vdoc = doc.virtual_document(fences, lang, session.as_deref());
+                if vdoc.blocks.is_empty() {
+                    continue;
+                }
+                for server in &servers {
+                    let already_open = self.virtual_docs.read().await.values().any(|open| {
+                        &open.markdown_uri == uri
+                            && &open.lang == lang
+                            && open.session == session
+                            && open.child_key.2 == server.name
+                    });
+                    if !already_open {
+                        self.open_virtual_doc(uri, doc, lang, session.as_deref(), &vdoc.content, server)
+                            .await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update child LSPs with the changed virtual documents of one markdown document
+    ///
+    /// Only the virtual documents of `langs` are rebuilt, and only those whose content
+    /// actually changed are re-sent - as a range edit when the child supports it.
+    /// Sessions that no longer have blocks are closed and new ones are opened.
+    async fn update_child_lsps(&self, uri: &Url, doc: &Document, fences: &[Fence], langs: &[String]) {
+        let vanished: Vec<VirtualDocKey> = self
+            .virtual_docs
+            .read()
+            .await
+            .iter()
+            .filter(|(_, open)| {
+                &open.markdown_uri == uri
+                    && langs.contains(&open.lang)
+                    && !collect_sessions(fences, &doc.aliases, &open.lang).contains(&open.session)
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in vanished {
+            self.close_virtual_doc(&key).await;
+        }
+
+        let open_docs: Vec<(VirtualDocKey, OpenVirtualDoc)> = self
+            .virtual_docs
+            .read()
+            .await
+            .iter()
+            .filter(|(_, open)| &open.markdown_uri == uri && langs.contains(&open.lang))
+            .map(|(key, open)| (key.clone(), open.clone()))
+            .collect();
+
+        let project_root = match doc.project_root.as_ref() {
+            Some(root) => root,
+            None => return,
+        };
+        for (key, open) in open_docs {
+            let lang = &open.lang;
+            let vdoc = doc.virtual_document(fences, lang, open.session.as_deref());
+            let child_lsp = match self.child_lsps.read().await.get(&open.child_key) {
+                Some(lsp) => Arc::clone(lsp),
+                None => continue,
+            };
+            let change = match sync::incremental_change(&open.content, &vdoc.content, child_lsp.position_encoding()) {
+                Some(change) => change,
+                None => continue,
+            };
+
+            // Write virtual doc to disk
+            let markdown_filename = Self::markdown_filename(uri, project_root, open.session.as_deref());
+            let file_uri = match self.write_virtual_doc_to_disk(project_root, &doc.output_dir, &markdown_filename, lang, vdoc.content.clone()) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Failed to write virtual doc for '{}': {}", lang, e);
                     continue;
                 }
             };
 
-            let current_version = child_versions.get(lang).copied().unwrap_or(0);
+            let new_version = open.version + 1;
+            let result = if child_lsp.supports_incremental_sync().await {
+                child_lsp
+                    .did_change_incremental(file_uri, new_version, vec![change])
+                    .await
+            } else {
+                child_lsp
+                    .did_change(file_uri, new_version, vdoc.content.clone())
+                    .await
+            };
 
-            if let Err(e) = child_lsp
-                .did_change(file_uri, current_version + 1, vdoc.content)
-                .await
-            {
+            if let Err(e) = result {
                 warn!("Failed to update child LSP for '{}': {}", lang, e);
-            } else {
-                child_versions.insert(lang.to_string(), current_version + 1);
+            } else if let Some(open) = self.virtual_docs.write().await.get_mut(&key) {
+                open.version = new_version;
+                open.content = vdoc.content;
+            }
+        }
+
+        self.open_sessions(uri, doc, fences, langs).await;
+    }
+
+    /// Check a document's chunk references and publish the problems with the child diagnostics
+    async fn publish_chunk_diagnostics(&self, uri: &Url, doc: &Document, fences: &[Fence]) {
+        let problems = chunks::chunk_diagnostics(fences, &doc.aliases, self.client_encoding());
+        let all = {
+            let mut cache = self.diagnostics.write().await;
+            let per_vdoc = cache.entry(uri.clone()).or_default();
+            let key = (uri.clone(), String::new());
+            let unchanged = match per_vdoc.get(&key) {
+                Some(cached) => cached == &problems,
+                None => problems.is_empty(),
+            };
+            if unchanged {
+                return;
+            }
+            per_vdoc.insert(key, problems);
+            per_vdoc.values().flatten().cloned().collect::<Vec<_>>()
+        };
+        self.publish(uri.clone(), all).await;
+    }
+
+    /// Map the child diagnostics of a document again after an edit, and republish them if they moved
+    ///
+    /// Edits in prose shift the blocks below them without changing any virtual document,
+    /// so the children have nothing new to publish.
+    async fn remap_diagnostics(&self, uri: &Url, doc: &Document, fences: &[Fence]) {
+        let open_docs: Vec<(VirtualDocKey, OpenVirtualDoc)> = self
+            .virtual_docs
+            .read()
+            .await
+            .iter()
+            .filter(|(_, open)| &open.markdown_uri == uri)
+            .map(|(key, open)| (key.clone(), open.clone()))
+            .collect();
+
+        let mut remapped = Vec::new();
+        for (key, open) in open_docs {
+            let child_encoding = match self.child_lsps.read().await.get(&open.child_key) {
+                Some(lsp) => lsp.position_encoding(),
+                None => continue,
+            };
+            let vdoc = doc.virtual_document(fences, &open.lang, open.session.as_deref());
+            let mapper = PositionMapper::new(&vdoc.blocks).with_encodings(self.client_encoding(), child_encoding);
+            let mapped = diagnostics::map_diagnostics(open.diagnostics, &mapper, &key.0, uri);
+            remapped.push((key, mapped));
+        }
+
+        let all = {
+            let mut cache = self.diagnostics.write().await;
+            let per_vdoc = match cache.get_mut(uri) {
+                Some(per_vdoc) => per_vdoc,
+                None => return,
+            };
+            let mut changed = false;
+            for (key, mapped) in remapped {
+                if let Some(cached) = per_vdoc.get_mut(&key) {
+                    if *cached != mapped {
+                        *cached = mapped;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                return;
+            }
+            per_vdoc.values().flatten().cloned().collect::<Vec<_>>()
+        };
+        self.publish(uri.clone(), all).await;
+    }
+
+    /// Publish a document's diagnostics; a synced notebook's go to the cells they are in
+    async fn publish(&self, uri: Url, diagnostics: Vec<Diagnostic>) {
+        let layout = self.notebooks.read().await.get(&uri).map(Notebook::layout);
+        match layout {
+            Some(layout) => {
+                for (cell, diagnostics) in layout.split_diagnostics(diagnostics) {
+                    self.client.publish_diagnostics(cell, diagnostics, None).await;
+                }
+            }
+            None => self.client.publish_diagnostics(uri, diagnostics, None).await,
+        }
+    }
+
+    /// Close one virtual document in a child LSP and drop the child's diagnostics for it
+    async fn close_virtual_doc(&self, key: &VirtualDocKey) {
+        let open = match self.virtual_docs.write().await.remove(key) {
+            Some(open) => open,
+            None => return,
+        };
+
+        if let Some(child_lsp) = self.child_lsps.read().await.get(&open.child_key).cloned() {
+            if let Err(e) = child_lsp.did_close(key.0.to_string()).await {
+                warn!("Failed to close virtual document for '{}': {}", open.lang, e);
+            }
+        }
+
+        let remaining = {
+            let mut cache = self.diagnostics.write().await;
+            cache.get_mut(&open.markdown_uri).and_then(|per_vdoc| {
+                per_vdoc.remove(key)?;
+                Some(per_vdoc.values().flatten().cloned().collect::<Vec<_>>())
+            })
+        };
+        if let Some(remaining) = remaining {
+            self.publish(open.markdown_uri, remaining).await;
+        }
+    }
+
+    /// Close the virtual documents of a markdown document in their child LSPs
+    async fn close_virtual_docs(&self, uri: &Url) {
+        let closed: Vec<(VirtualDocKey, OpenVirtualDoc)> = {
+            let mut virtual_docs = self.virtual_docs.write().await;
+            let keys: Vec<VirtualDocKey> = virtual_docs
+                .iter()
+                .filter(|(_, open)| &open.markdown_uri == uri)
+                .map(|(key, _)| key.clone())
+                .collect();
+            keys.into_iter()
+                .filter_map(|key| virtual_docs.remove(&key).map(|open| (key, open)))
+                .collect()
+        };
+
+        for ((virtual_uri, _), open) in closed {
+            let child_lsp = match self.child_lsps.read().await.get(&open.child_key) {
+                Some(lsp) => Arc::clone(lsp),
+                None => continue,
+            };
+            if let Err(e) = child_lsp.did_close(virtual_uri.to_string()).await {
+                warn!("Failed to close virtual document for '{}': {}", open.lang, e);
             }
         }
     }
@@ -233,52 +952,63 @@ impl LiterateLsp {
         }
     }
 
-    /// Generic handler for position-based LSP requests
-    async fn handle_position_request(
+    /// Find the child LSPs serving `method` for the code block at a position, opening its
+    /// virtual document in every server of the block's language
+    ///
+    /// A server serves a request when its `only-features` / `except-features` allow the
+    /// request's feature and it advertises the capability; the result may have no children.
+    /// When the request cannot be forwarded, `Err` holds the response for the editor:
+    /// `null`, or a hover explaining why the block has no language server.
+    async fn route_position_request(
         &self,
         method: &str,
         position: Position,
-        uri: Url,
-    ) -> JsonrpcResult<serde_json::Value> {
-        info!(
-            "Request: {} at line:{} char:{}",
-            method, position.line, position.character
-        );
-
-        let doc = self.document.read().await;
-        let doc_content = match doc.as_ref() {
-            Some(c) => c,
+        uri: &Url,
+    ) -> Result<RoutedRequest, serde_json::Value> {
+        let doc = match self.documents.read().await.get(uri).cloned() {
+            Some(doc) => doc,
             None => {
-                debug!("[Hover] No document loaded");
-                return Ok(json!(null));
+                debug!("[Hover] No document loaded for {}", uri);
+                return Err(json!(null));
             }
         };
+        let doc_content = &doc.text;
 
         let markdown_line = position.line as usize;
 
-        let (lang, _block_start, _block_end) =
-            match find_code_block_at_line(doc_content, markdown_line) {
-                Some(result) => result,
-                None => {
-                    debug!("[Hover] No code block found at line {}", markdown_line);
-                    return Ok(json!(null));
-                }
-            };
+        let fence = match find_fence_at_line(doc_content, doc.format, markdown_line) {
+            Some(fence) => fence,
+            None => {
+                debug!("[Hover] No code block found at line {}", markdown_line);
+                return Err(json!(null));
+            }
+        };
+        let lang = doc.aliases.resolve(fence.lang()).to_string();
 
         debug!("[LiterateLsp] Detected code block language: '{}'", lang);
 
-        // Skip self-referential cases (e.g., markdown blocks in markdown files)
-        let doc_lang = Self::get_document_language(&uri);
-        if Self::should_skip_language(doc_lang.as_deref(), &lang) {
+        // Blocks marked {ignore} or {no-lsp} are not part of any virtual document
+        if fence.attributes().excluded() {
+            debug!("[LiterateLsp] Block at line {} is excluded from LSP features", markdown_line);
+            return Err(json!(null));
+        }
+
+        // Skip documentation formats and self-referential cases (e.g., markdown blocks in markdown files)
+        let doc_lang = get_document_language(uri);
+        if self.config.skips_language(doc_lang.as_deref(), &lang) {
             info!(
-                "Skipping language '{}' (self-referential)",
+                "Skipping language '{}' (documentation format or self-referential)",
                 lang
             );
             let message = format!(
                 "Cannot provide IDE features for **{}** code blocks inside **{}** documents.\n\n\
-                 **Why?** This would create a recursive loop (literate-lsp acting on itself).\n\n\
+                 **Why?** Documentation formats and the document's own language are never sent to child LSPs; \
+                 this would create a recursive loop (literate-lsp acting on itself).\n\n\
                  **Solution:** Move the {} code outside the {} fence, or use a different documentation format.",
-                lang, lang, lang, lang
+                lang,
+                doc_lang.as_deref().unwrap_or(&lang),
+                lang,
+                lang
             );
             debug!("[Hover] Returning self-referential message: {}", message);
             let hover_response = json!({
@@ -287,11 +1017,12 @@ impl LiterateLsp {
                 }
             });
             debug!("[Hover] Serialized response: {}", hover_response);
-            return Ok(hover_response);
+            return Err(hover_response);
         }
 
-        let vdoc = build_virtual_document(doc_content, &lang);
-        let mapper = PositionMapper::new(&vdoc.blocks);
+        let session = fence.session();
+        let fences = doc.fences();
+        let vdoc = doc.virtual_document(&fences, &lang, session.as_deref());
 
         // If no code blocks found for this language, provide helpful feedback
         info!(
@@ -302,24 +1033,7 @@ impl LiterateLsp {
         if vdoc.content.is_empty() {
             debug!("[Hover] Building helpful message for missing language");
             // Find what languages were actually in the document
-            let mut found_langs = std::collections::HashSet::new();
-            let lines: Vec<&str> = doc_content.lines().collect();
-            for line in lines {
-                if let Some(pos) = line.find("```") {
-                    let lang_start = pos + 3;
-                    if lang_start <= line.len() {
-                        let lang_part = &line[lang_start..];
-                        let found_lang = lang_part
-                            .split_whitespace()
-                            .next()
-                            .unwrap_or("")
-                            .to_string();
-                        if !found_lang.is_empty() {
-                            found_langs.insert(found_lang);
-                        }
-                    }
-                }
-            }
+            let found_langs = collect_languages(&fences, &doc.aliases);
 
             let message = if found_langs.is_empty() {
                 "No code blocks found in this document".to_string()
@@ -342,201 +1056,460 @@ impl LiterateLsp {
                 }
             });
             debug!("[Hover] Hover response: {}", hover_response);
-            return Ok(hover_response);
+            return Err(hover_response);
         }
 
-        // Write virtual document to disk
-        let project_root = self.project_root.read().await;
-        let output_dir = self.output_dir.read().await;
+        let servers = self.config.get_language_servers(&lang);
+        if servers.is_empty() {
+            warn!("[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}", lang, lang);
 
-        let markdown_filename = uri
-            .path_segments()
-            .and_then(|mut segs| segs.next_back())
-            .unwrap_or("document");
+            // Check if language exists in config
+            let lang_exists = self.config.language.iter().find(|l| l.name == lang);
 
-        let file_uri = match project_root.as_ref() {
-            Some(root) => {
-                match self.write_virtual_doc_to_disk(root, &output_dir, markdown_filename, &lang, vdoc.content.clone()) {
-                    Ok(uri) => uri,
-                    Err(e) => {
-                        warn!("Failed to write virtual document to disk: {}", e);
-                        return Ok(json!(null));
-                    }
+            let available_lsps = "Find available LSPs at: https://langserver.org";
+            let add_support = "To add IDE support, add this to `$root/.languages.toml`:";
+            let message = if let Some(lang) = lang_exists {
+                // Language exists but no LSP configured
+                let servers = lang.get_server_names();
+                let servers_array = servers
+                    .iter()
+                    .map(|s| format!("\"{}\"", s))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!(
+                    "**Language '{}' is configured but has no LSP server.**\n\n\
+                     {}\n\n\
+                     ```toml\n\
+                     [[language]]\n\
+                     name = \"{}\"\n\
+                     language-servers = [{}]\n\
+                     ```\n\n\
+                     {}",
+                    lang.name, add_support, lang.name, servers_array, available_lsps
+                )
+            } else {
+                // Language not configured at all
+                format!(
+                    "**Language '{}' is not configured.**\n\n\
+                     {}\n\n\
+                     ```toml\n\
+                     [[language]]\n\
+                     name = \"{}\"\n\
+                     language-servers = [\"lsp-name\"]\n\
+                     ```\n\n\
+                     Then find and configure an LSP for {} in the `[language-server]` section:\n\n\
+                     ```toml\n\
+                     [language-server.lsp-name]\n\
+                     command = \"lsp-binary\"\n\
+                     ```\n\n\
+                     {}",
+                    lang, add_support, lang, lang, available_lsps
+                )
+            };
+
+            let hover_response = json!({
+                "result": {
+                    "contents": message
+                }
+            });
+            info!(
+                "No LSP configured for language '{}'",
+                lang
+            );
+            return Err(hover_response);
+        }
+
+        let routing = Feature::of_method(method);
+        let mut children = Vec::new();
+        let mut file_uri = None;
+        for server in &servers {
+            let (child_lsp, opened_uri) = match self
+                .open_virtual_doc(uri, &doc, &lang, session.as_deref(), &vdoc.content, server)
+                .await
+            {
+                Some(opened) => opened,
+                None => continue,
+            };
+            file_uri = Some(opened_uri);
+            if let Some((feature, capability)) = routing {
+                if !server.serves(feature) || !features::advertises(child_lsp.get_capabilities().await.as_ref(), capability) {
+                    debug!("[LiterateLsp] '{}' does not serve {}", server.name, method);
+                    continue;
                 }
             }
-            None => {
-                warn!("Project root not detected, cannot write virtual document to disk");
-                return Ok(json!(null));
-            }
+            children.push(child_lsp);
+        }
+        let file_uri = match file_uri {
+            Some(file_uri) => file_uri,
+            None => return Err(json!(null)),
         };
 
+        Ok(RoutedRequest { children, file_uri, lang, vdoc })
+    }
+
+    /// Find the document a request on `uri` is served from
+    ///
+    /// A cell of a synced notebook is served from the notebook's markdown document: the
+    /// notebook URI and the position in it are returned, with the cell to map the response
+    /// back to. Any other document serves its own requests.
+    async fn resolve_cell(&self, uri: Url, position: Position) -> (Url, Position, Option<CellRequest>) {
+        let notebooks = self.notebooks.read().await;
+        let notebook = notebooks
+            .values()
+            .find(|notebook| notebook.cells.iter().any(|cell| cell.uri == uri));
+        if let Some(notebook) = notebook {
+            let layout = notebook.layout();
+            if let Some(position) = layout.to_notebook(&uri, position) {
+                return (notebook.uri.clone(), position, Some(CellRequest { cell: uri, layout }));
+            }
+        }
+        (uri, position, None)
+    }
+
+    /// Params of a position request for one routed child, in its virtual document coordinates
+    ///
+    /// `extra_params` are merged into the `textDocument`/`position` params.
+    fn position_params(
+        &self,
+        routed: &RoutedRequest,
+        child_lsp: &ChildLspManager,
+        position: Position,
+        extra_params: serde_json::Value,
+    ) -> serde_json::Value {
+        let mapper = routed.mapper(child_lsp, self.client_encoding());
+
         // Build the request parameters with real file URI
         let mut params = json!({
-            "textDocument": { "uri": file_uri.clone() },
+            "textDocument": { "uri": routed.file_uri },
             "position": { "line": position.line, "character": position.character }
         });
+        if let (Some(params), serde_json::Value::Object(extra)) = (params.as_object_mut(), extra_params) {
+            params.extend(extra);
+        }
 
         // Rewrite request positions to virtual document coordinates
         request_mapper::rewrite_positions(&mut params, &mapper, true);
+        params
+    }
 
-        let (binary_name, args) = match self.config.get_command_and_args(&lang) {
-            Some((cmd, args)) => (cmd, args),
-            None => {
-                warn!("[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}", lang, lang);
-
-                // Check if language exists in config
-                let lang_exists = self.config.language.iter().find(|l| l.name == lang);
-
-                let available_lsps = "Find available LSPs at: https://langserver.org";
-                let add_support = "To add IDE support, add this to `$root/.languages.toml`:";
-                let message = if let Some(lang) = lang_exists {
-                    // Language exists but no LSP configured
-                    let servers = lang.get_server_names();
-                    let servers_array = servers
-                        .iter()
-                        .map(|s| format!("\"{}\"", s))
-                        .collect::<Vec<_>>()
-                        .join(", ");
-                    format!(
-                        "**Language '{}' is configured but has no LSP server.**\n\n\
-                         {}\n\n\
-                         ```toml\n\
-                         [[language]]\n\
-                         name = \"{}\"\n\
-                         language-servers = [{}]\n\
-                         ```\n\n\
-                         {}",
-                        lang.name, add_support, lang.name, servers_array, available_lsps
-                    )
-                } else {
-                    // Language not configured at all
-                    format!(
-                        "**Language '{}' is not configured.**\n\n\
-                         {}\n\n\
-                         ```toml\n\
-                         [[language]]\n\
-                         name = \"{}\"\n\
-                         language-servers = [\"lsp-name\"]\n\
-                         ```\n\n\
-                         Then find and configure an LSP for {} in the `[language-server]` section:\n\n\
-                         ```toml\n\
-                         [language-server.lsp-name]\n\
-                         command = \"lsp-binary\"\n\
-                         ```\n\n\
-                         {}",
-                        lang, add_support, lang, lang, available_lsps
-                    )
-                };
+    /// Send a position request to a routed child LSP, in virtual document coordinates
+    ///
+    /// The raw response is returned with positions untouched, or `None` if the request failed.
+    async fn send_position_request(
+        &self,
+        routed: &RoutedRequest,
+        child_lsp: &ChildLspManager,
+        method: &str,
+        position: Position,
+        extra_params: serde_json::Value,
+    ) -> Option<serde_json::Value> {
+        let params = self.position_params(routed, child_lsp, position, extra_params);
 
-                let hover_response = json!({
-                    "result": {
-                        "contents": message
-                    }
-                });
-                info!(
-                    "No LSP configured for language '{}'",
-                    lang
-                );
-                return Ok(hover_response);
+        // Send request to child LSP
+        match child_lsp.send_request_raw(method, params).await {
+            Ok(resp) => Some(resp),
+            Err(e) => {
+                self.client
+                    .log_message(
+                        MessageType::ERROR,
+                        format!("Child LSP request failed: {}", e),
+                    )
+                    .await;
+                None
             }
-        };
-
-        let mut child_lsps = self.child_lsps.write().await;
+        }
+    }
 
-        if !child_lsps.contains_key(&lang) {
-            // Get project root for initialization
-            let project_root = self.project_root.read().await;
-            let output_dir = self.output_dir.read().await;
-            let root_uri = match project_root.as_ref() {
-                Some(root) => self.get_lsp_root_uri(root, &output_dir),
-                None => {
-                    warn!("Project root not available for child LSP initialization");
-                    return Ok(json!(null));
-                }
-            };
+    /// Generic handler for position-based LSP requests
+    ///
+    /// The request goes to the servers of the block's language that serve it, all at once.
+    /// Their results are combined as the request's feature says: merged, the first with
+    /// content, or only the first server's. A server's error is logged and left out; it is
+    /// the response only when no server returned a result.
+    async fn handle_position_request(
+        &self,
+        method: &str,
+        position: Position,
+        uri: Url,
+    ) -> JsonrpcResult<serde_json::Value> {
+        info!(
+            "Request: {} at line:{} char:{}",
+            method, position.line, position.character
+        );
+        let (uri, position, cell) = self.resolve_cell(uri, position).await;
 
-            let init_params = ChildLspInitParams {
-                lang: lang.to_string(),
-                binary_name,
-                args,
-                root_uri,
-                file_uri: file_uri.clone(),
-                file_content: vdoc.content.clone(),
-                init_options: self.config.get_init_options(&lang),
-            };
+        let routed = match self.route_position_request(method, position, &uri).await {
+            Ok(routed) => routed,
+            Err(response) => return Ok(response),
+        };
+        let virtual_uri = match Url::parse(&routed.file_uri) {
+            Ok(virtual_uri) => virtual_uri,
+            Err(_) => return Ok(json!(null)),
+        };
+        let feature = Feature::of_method(method).map(|(feature, _)| feature);
+        let children = match feature.map(Feature::combine) {
+            Some(Combine::Merge) | Some(Combine::FirstNonEmpty) => &routed.children[..],
+            Some(Combine::First) | None => &routed.children[..routed.children.len().min(1)],
+        };
 
-            match ChildLspInitializer::initialize_child_lsp(init_params).await {
-                Ok(result) => {
-                    // Cache completion triggers after successful initialization
-                    self.cache_completion_triggers(&result.lang, &result.lsp).await;
-                    child_lsps.insert(result.lang, result.lsp);
-                }
-                Err(error_msg) => {
+        let mut requests = tokio::task::JoinSet::new();
+        for (index, child_lsp) in children.iter().enumerate() {
+            let params = self.position_params(&routed, child_lsp, position, json!({}));
+            let (child_lsp, method) = (Arc::clone(child_lsp), method.to_string());
+            requests.spawn(async move { (index, child_lsp.send_request_raw(&method, params).await) });
+        }
+        let mut responses = Vec::new();
+        let mut first_error = None;
+        while let Some(joined) = requests.join_next().await {
+            match joined {
+                Ok((index, Ok(response))) => match child_error(&response) {
+                    // One server's error must not hide what the others found
+                    Some(error) => {
+                        let message = format!("{} LSP {} failed '{}': {}", routed.lang, index + 1, method, error.message);
+                        warn!("{}", message);
+                        self.client.log_message(MessageType::WARNING, message).await;
+                        first_error.get_or_insert(error);
+                    }
+                    None => responses.push((index, response)),
+                },
+                Ok((_, Err(e))) => {
                     self.client
-                        .log_message(MessageType::ERROR, error_msg)
+                        .log_message(MessageType::ERROR, format!("Child LSP request failed: {}", e))
                         .await;
-                    return Ok(json!(null));
                 }
+                Err(e) => warn!("Child LSP request '{}' panicked: {}", method, e),
             }
         }
+        if let (true, Some(error)) = (responses.is_empty(), first_error) {
+            return Err(error);
+        }
+        responses.sort_by_key(|(index, _)| *index);
+
+        // Map each child's result back to markdown, with that child's position encoding
+        let markdown_filename = extract_filename(&uri);
+        let results: Vec<serde_json::Value> = responses
+            .into_iter()
+            .map(|(index, mut response)| {
+                let mapper = routed.mapper(&children[index], self.client_encoding());
+                request_mapper::rewrite_response(&mut response, &mapper, &virtual_uri, &uri);
+                let mut result = response.get_mut("result").map(serde_json::Value::take).unwrap_or_default();
+                Self::map_virtual_refs_in_value(&mut result, &routed.lang, &mapper, markdown_filename);
+                result
+            })
+            .collect();
+        let result = match feature {
+            Some(feature) => features::combine_results(feature, results),
+            None => results.into_iter().next().unwrap_or_default(),
+        };
+
+        let mut response = json!({ "result": result });
+        if let Some(cell) = &cell {
+            cell.layout.rewrite_response(&mut response, &cell.cell);
+        }
+        Ok(response)
+    }
 
-        let child_lsp = match child_lsps.get(&lang) {
-            Some(lsp) => lsp,
+    /// Generic handler for document-wide requests (symbols, formatting)
+    ///
+    /// Forwards the request for the language and session of the code block at the top of the
+    /// document. On a notebook cell, that is the cell, and only results in the cell are kept.
+    async fn handle_document_request(
+        &self,
+        method: &str,
+        uri: Url,
+        mut params: serde_json::Value,
+    ) -> JsonrpcResult<serde_json::Value> {
+        let (uri, top, cell) = self.resolve_cell(uri, Position::new(0, 0)).await;
+        let doc = match self.documents.read().await.get(&uri).cloned() {
+            Some(doc) => doc,
             None => return Ok(json!(null)),
         };
 
-        // Send request to child LSP
-        let mut response = match child_lsp.send_request_raw(method, params).await {
-            Ok(resp) => resp,
-            Err(e) => {
-                self.client
-                    .log_message(
-                        MessageType::ERROR,
-                        format!("Child LSP request failed: {}", e),
-                    )
-                    .await;
-                return Ok(json!(null));
+        let fence = match find_fence_at_line(&doc.text, doc.format, top.line as usize) {
+            Some(fence) => fence,
+            None => return Ok(json!(null)),
+        };
+        let lang = doc.aliases.resolve(fence.lang()).to_string();
+        let session = fence.session();
+        let vdoc = doc.virtual_document(&doc.fences(), &lang, session.as_deref());
+
+        let servers = self.config.get_language_servers(&lang);
+        if servers.is_empty() {
+            warn!("[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}", lang, lang);
+            return Ok(json!(null));
+        }
+
+        // Edits or symbols from two servers would clash: the first server serving the request answers
+        let routing = Feature::of_method(method);
+        let mut serving = None;
+        for server in &servers {
+            let opened = self
+                .open_virtual_doc(&uri, &doc, &lang, session.as_deref(), &vdoc.content, server)
+                .await;
+            let (child_lsp, file_uri) = match opened {
+                Some(opened) => opened,
+                None => continue,
+            };
+            let serves = match routing {
+                Some((feature, capability)) => {
+                    server.serves(feature)
+                        && features::advertises(child_lsp.get_capabilities().await.as_ref(), capability)
+                }
+                None => true,
+            };
+            if serves && serving.is_none() {
+                serving = Some((child_lsp, file_uri));
             }
+        }
+        let (child_lsp, file_uri) = match serving {
+            Some(serving) => serving,
+            None => return Ok(json!(null)),
         };
 
-        // Rewrite response positions back to markdown coordinates
-        request_mapper::rewrite_positions(&mut response, &mapper, false);
+        let mapper = PositionMapper::new(&vdoc.blocks)
+            .with_encodings(self.client_encoding(), child_lsp.position_encoding());
+        params["textDocument"] = json!({ "uri": file_uri });
+        request_mapper::rewrite_positions(&mut params, &mapper, true);
 
-        // Map virtual document file references in text content back to markdown
-        if let Some(result) = response.get_mut("result") {
-            Self::map_virtual_refs_in_value(result, &lang, &mapper, markdown_filename);
-        }
+        let virtual_uri = match Url::parse(&file_uri) {
+            Ok(virtual_uri) => virtual_uri,
+            Err(_) => return Ok(json!(null)),
+        };
 
-        // Replace virtual document URI with original markdown URI in Location responses
-        if let Some(result) = response.get_mut("result") {
-            match result {
-                serde_json::Value::Object(loc) => {
-                    // Single location - only update if it has a "uri" field
-                    if loc.contains_key("uri") {
-                        loc["uri"] = json!(uri.to_string());
-                    }
-                }
-                serde_json::Value::Array(locs) => {
-                    // Array of locations
-                    for loc in locs.iter_mut() {
-                        if let serde_json::Value::Object(loc_obj) = loc {
-                            if loc_obj.contains_key("uri") {
-                                loc_obj["uri"] = json!(uri.to_string());
-                            }
-                        }
+        match child_lsp.send_request_raw(method, params).await {
+            Ok(mut response) => {
+                request_mapper::rewrite_response(&mut response, &mapper, &virtual_uri, &uri);
+                if let Some(cell) = &cell {
+                    if let Some(result) = response.get_mut("result") {
+                        cell.layout.retain_cell(result, &cell.cell);
                     }
+                    cell.layout.rewrite_response(&mut response, &cell.cell);
                 }
-                _ => {}
+                Ok(response)
+            }
+            Err(e) => {
+                warn!("Child LSP request '{}' failed: {}", method, e);
+                Ok(json!(null))
             }
         }
+    }
+}
 
-        Ok(response)
+/// `notebookDocument/*` notifications, registered on the service with `custom_method`
+impl LiterateLsp {
+    /// Serve a notebook's code cells as one markdown document
+    pub async fn notebook_did_open(&self, params: DidOpenNotebookDocumentParams) {
+        let notebook = Notebook::open(&params);
+        let uri = notebook.uri.clone();
+
+        let mut document = Document::new(&uri, notebook.markdown(), notebook.version, &self.aliases);
+        document.format = DocumentFormat::Markdown;
+        self.notebooks.write().await.insert(uri.clone(), notebook);
+        self.documents.write().await.open(uri.clone(), document.clone());
+        // Opening a document is the user's cue to try servers that kept crashing again
+        self.restarts.write().await.forgive();
+
+        let fences = document.fences();
+        self.start_child_lsps(&uri, &document, &fences).await;
+        self.publish_chunk_diagnostics(&uri, &document, &fences).await;
+    }
+
+    /// Apply cell changes and update the child LSPs of the languages they touched
+    pub async fn notebook_did_change(&self, params: DidChangeNotebookDocumentParams) {
+        let uri = params.notebook_document.uri.clone();
+        let (text, removed) = {
+            let mut notebooks = self.notebooks.write().await;
+            let notebook = match notebooks.get_mut(&uri) {
+                Some(notebook) => notebook,
+                None => {
+                    warn!("Change for unknown notebook {}", uri);
+                    return;
+                }
+            };
+            let removed = notebook.apply_change(&params, self.client_encoding());
+            (notebook.markdown(), removed)
+        };
+        // Removed cells are closed documents; their diagnostics go with them
+        for cell in removed {
+            self.client.publish_diagnostics(cell, Vec::new(), None).await;
+        }
+
+        let (old_text, document) = {
+            let mut documents = self.documents.write().await;
+            let mut document = match documents.get(&uri).cloned() {
+                Some(doc) => doc,
+                None => return,
+            };
+            let old_text = std::mem::replace(&mut document.text, text);
+            document.version = params.notebook_document.version;
+            documents.open(uri.clone(), document.clone());
+            (old_text, document)
+        };
+
+        // The blocks are scanned once and shared by every step of the update
+        let fences = document.fences();
+        let langs = sync::affected_languages(&old_text, &document.text, &fences, document.format, &document.aliases);
+        debug!("[LiterateLsp] Notebook change affects languages: {:?}", langs);
+        if !langs.is_empty() {
+            self.update_child_lsps(&uri, &document, &fences, &langs).await;
+        }
+        // Edits anywhere move the diagnostics of the blocks below them
+        self.remap_diagnostics(&uri, &document, &fences).await;
+        self.publish_chunk_diagnostics(&uri, &document, &fences).await;
     }
+
+    /// Close a notebook's virtual documents and clear the diagnostics of its cells
+    pub async fn notebook_did_close(&self, params: DidCloseNotebookDocumentParams) {
+        let uri = params.notebook_document.uri;
+        let notebook = self.notebooks.write().await.remove(&uri);
+
+        self.documents.write().await.close(&uri);
+        self.close_virtual_docs(&uri).await;
+        self.diagnostics.write().await.remove(&uri);
+        for cell in notebook.iter().flat_map(|notebook| &notebook.cells) {
+            self.client.publish_diagnostics(cell.uri.clone(), Vec::new(), None).await;
+        }
+    }
+}
+
+/// Build an error for the editor from a message
+fn request_failed(message: impl Into<String>) -> JsonrpcError {
+    JsonrpcError {
+        code: ErrorCode::InvalidRequest,
+        message: message.into().into(),
+        data: None,
+    }
+}
+
+/// Pass a child LSP error response through to the editor
+fn child_error(response: &serde_json::Value) -> Option<JsonrpcError> {
+    let error = response.get("error")?;
+    Some(JsonrpcError {
+        code: ErrorCode::from(error.get("code").and_then(|c| c.as_i64()).unwrap_or(-32603)),
+        message: error
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or("Child LSP request failed")
+            .to_string()
+            .into(),
+        data: error.get("data").cloned(),
+    })
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for LiterateLsp {
-    async fn initialize(&self, _params: InitializeParams) -> JsonrpcResult<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> JsonrpcResult<InitializeResult> {
+        // Positions are converted per child, so the editor can use whichever encoding it prefers
+        let offered = params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|general| general.position_encodings.as_deref());
+        let encoding = PositionEncoding::negotiate(offered);
+        let _ = self.position_encoding.set(encoding);
+        self.documents.write().await.set_position_encoding(encoding);
+        info!("[LiterateLsp] Negotiated position encoding: {:?}", encoding);
+
         // Declare capabilities for all position-based LSP methods
         // These are supported as long as the underlying language has an LSP available
 
@@ -556,14 +1529,19 @@ impl LanguageServer for LiterateLsp {
 
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
-                // Text sync is required - we use FULL sync to update virtual documents
+                position_encoding: Some(encoding.kind()),
+                // Text sync is required - INCREMENTAL sync lets us rebuild only the touched blocks
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
                 // Position-based methods - forwarded to child LSPs for supported languages
                 definition_provider: Some(OneOf::Left(true)),
                 hover_provider: Some(HoverProviderCapability::Simple(true)),
                 references_provider: Some(OneOf::Left(true)),
+                rename_provider: Some(OneOf::Right(RenameOptions {
+                    prepare_provider: Some(true),
+                    work_done_progress_options: Default::default(),
+                })),
                 document_symbol_provider: Some(OneOf::Left(true)),
                 workspace_symbol_provider: Some(OneOf::Left(true)),
                 code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
@@ -586,54 +1564,78 @@ impl LanguageServer for LiterateLsp {
         self.client
             .log_message(MessageType::INFO, "LiterateMD LSP initialized")
             .await;
+
+        // lsp-types has no static notebook capability, so notebook sync is registered here
+        if self.notebook_registration.get() != Some(&true) {
+            debug!("[LiterateLsp] Editor cannot register notebook sync, notebooks are served as text");
+            return;
+        }
+        let registration = Registration {
+            id: "literate-lsp-notebook-sync".to_string(),
+            method: "notebookDocument/sync".to_string(),
+            register_options: Some(notebook_sync::registration_options()),
+        };
+        if let Err(e) = self.client.register_capability(vec![registration]).await {
+            warn!("[LiterateLsp] Failed to register notebook sync: {}", e);
+        }
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let uri = params.text_document.uri.clone();
+        let uri = params.text_document.uri;
 
-        let mut doc = self.document.write().await;
-        *doc = Some(params.text_document.text.clone());
+        // Detect project root and load configuration for this document
+        let document = Document::new(&uri, params.text_document.text, params.text_document.version, &self.aliases);
+        self.documents.write().await.open(uri.clone(), document.clone());
+        // Opening a document is the user's cue to try servers that kept crashing again
+        self.restarts.write().await.forgive();
 
-        let mut uri_lock = self.document_uri.write().await;
-        *uri_lock = Some(uri.clone());
-
-        let mut version = self.document_version.write().await;
-        *version = params.text_document.version;
-
-        // Detect project root and load configuration
-        if let Ok(path) = uri.to_file_path() {
-            let project_root = crate::config::find_project_root(&path);
-            let literate_config = crate::config::load_literate_config(&project_root);
-
-            let mut root_lock = self.project_root.write().await;
-            *root_lock = Some(project_root);
-
-            let mut output_lock = self.output_dir.write().await;
-            *output_lock = literate_config.output_dir;
-        }
+        let fences = document.fences();
+        self.start_child_lsps(&uri, &document, &fences).await;
+        self.publish_chunk_diagnostics(&uri, &document, &fences).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        // Handle full document changes - for FULL sync, there's typically just one change
-        if let Ok(change_json) = serde_json::to_value(&params.content_changes) {
-            if let Some(changes_array) = change_json.as_array() {
-                if let Some(change) = changes_array.first() {
-                    if let Some(text) = change.get("text").and_then(|t| t.as_str()) {
-                        let mut doc = self.document.write().await;
-                        *doc = Some(text.to_string());
-                        let mut version = self.document_version.write().await;
-                        *version = params.text_document.version;
-                        drop(version);
-
-                        // Update all child LSPs with new virtual documents
-                        if let Some(content) = doc.as_ref() {
-                            self.update_child_lsps(content, params.text_document.version)
-                                .await;
-                        }
-                    }
+        let uri = params.text_document.uri;
+
+        // Apply the range edits, remembering the previous text to find the touched blocks
+        let (old_text, document) = {
+            let mut documents = self.documents.write().await;
+            let old_text = match documents.get(&uri) {
+                Some(doc) => doc.text.clone(),
+                None => {
+                    warn!("Change for unknown document {}", uri);
+                    return;
                 }
+            };
+            documents.apply_changes(&uri, &params.content_changes, params.text_document.version);
+            match documents.get(&uri).cloned() {
+                Some(doc) => (old_text, doc),
+                None => return,
             }
+        };
+
+        // Update the child LSPs of the languages whose blocks were touched
+        // The blocks are scanned once and shared by every step of the update
+        let fences = document.fences();
+        let langs = sync::affected_languages(&old_text, &document.text, &fences, document.format, &document.aliases);
+        debug!("[LiterateLsp] Change affects languages: {:?}", langs);
+        if !langs.is_empty() {
+            self.update_child_lsps(&uri, &document, &fences, &langs).await;
         }
+        // Edits anywhere move the diagnostics of the blocks below them
+        self.remap_diagnostics(&uri, &document, &fences).await;
+        self.publish_chunk_diagnostics(&uri, &document, &fences).await;
+    }
+
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+
+        self.documents.write().await.close(&uri);
+        self.close_virtual_docs(&uri).await;
+
+        // Clear the closed document's diagnostics in the editor
+        self.diagnostics.write().await.remove(&uri);
+        self.publish(uri, Vec::new()).await;
     }
 
     async fn goto_definition(
@@ -668,97 +1670,171 @@ impl LanguageServer for LiterateLsp {
         }
     }
 
-    async fn document_symbol(
-        &self,
-        params: DocumentSymbolParams,
-    ) -> JsonrpcResult<Option<DocumentSymbolResponse>> {
-        let doc = self.document.read().await;
-        let doc_content = match doc.as_ref() {
-            Some(c) => c.clone(),
+    async fn references(&self, params: ReferenceParams) -> JsonrpcResult<Option<Vec<Location>>> {
+        let position = params.text_document_position.position;
+        let uri = params.text_document_position.text_document.uri;
+        let (uri, position, cell) = self.resolve_cell(uri, position).await;
+
+        let routed = match self.route_position_request("textDocument/references", position, &uri).await {
+            Ok(routed) => routed,
+            Err(_) => return Ok(None),
+        };
+        let child_lsp = match routed.children.first() {
+            Some(child_lsp) => child_lsp,
+            None => return Ok(None),
+        };
+        let context = json!({ "context": params.context });
+        let response = match self
+            .send_position_request(&routed, child_lsp, "textDocument/references", position, context)
+            .await
+        {
+            Some(response) => response,
             None => return Ok(None),
         };
 
-        // For document symbols, we return symbols from all code blocks
-        // Request position 0,0 to find the first language
-        if let Some((lang, _, _)) = find_code_block_at_line(&doc_content, 0) {
-            let vdoc = build_virtual_document(&doc_content, &lang);
-            let mapper = PositionMapper::new(&vdoc.blocks);
+        let locations: Vec<Location> = match response.get("result").cloned().map(serde_json::from_value) {
+            Some(Ok(Some(locations))) => locations,
+            _ => return Ok(None),
+        };
+        let virtual_uri = match Url::parse(&routed.file_uri) {
+            Ok(virtual_uri) => virtual_uri,
+            Err(_) => return Ok(None),
+        };
 
-            let (binary_name, args) = match self.config.get_command_and_args(&lang) {
-                Some((cmd, args)) => (cmd, args),
-                None => {
-                    warn!("[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}", lang, lang);
-                    return Ok(None);
-                }
-            };
+        // Only locations in the virtual document move to markdown; real files are kept as-is
+        let mapper = routed.mapper(child_lsp, self.client_encoding());
+        let locations = mapper.map_locations(locations, &virtual_uri, &uri);
+        Ok(Some(cell_result(&cell, locations)))
+    }
 
-            // Write virtual doc to disk
-            let project_root = self.project_root.read().await;
-            let output_dir = self.output_dir.read().await;
-
-            let markdown_filename = params.text_document.uri
-                .path_segments()
-                .and_then(|mut segs| segs.next_back())
-                .unwrap_or("document");
-
-            let file_uri = match project_root.as_ref() {
-                Some(root) => {
-                    match self.write_virtual_doc_to_disk(root, &output_dir, markdown_filename, &lang, vdoc.content.clone()) {
-                        Ok(uri) => uri,
-                        Err(e) => {
-                            warn!("Failed to write virtual doc for document_symbol: {}", e);
-                            return Ok(None);
-                        }
-                    }
-                }
-                None => {
-                    warn!("Project root not available for document_symbol");
-                    return Ok(None);
-                }
-            };
+    async fn prepare_rename(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> JsonrpcResult<Option<PrepareRenameResponse>> {
+        let position = params.position;
+        let uri = params.text_document.uri;
+        let (uri, position, cell) = self.resolve_cell(uri, position).await;
+
+        let routed = match self.route_position_request("textDocument/prepareRename", position, &uri).await {
+            Ok(routed) => routed,
+            Err(_) => return Ok(None),
+        };
+        let child_lsp = match routed.children.first() {
+            Some(child_lsp) => child_lsp,
+            None => {
+                return Err(request_failed(format!(
+                    "The '{}' language server does not support rename",
+                    routed.lang
+                )));
+            }
+        };
 
-            let mut req_params =
-                json!({"textDocument": {"uri": file_uri.clone()}});
-            request_mapper::rewrite_positions(&mut req_params, &mapper, true);
+        // Children without prepareRename support still rename the word under the cursor
+        let rename_provider = child_lsp
+            .get_capabilities()
+            .await
+            .and_then(|caps| caps.get("renameProvider").cloned());
+        match rename_provider {
+            Some(serde_json::Value::Object(options)) if options.get("prepareProvider") == Some(&json!(true)) => {}
+            Some(serde_json::Value::Bool(true)) | Some(serde_json::Value::Object(_)) => {
+                return Ok(Some(PrepareRenameResponse::DefaultBehavior { default_behavior: true }));
+            }
+            _ => {
+                return Err(request_failed(format!(
+                    "The '{}' language server does not support rename",
+                    routed.lang
+                )));
+            }
+        }
 
-            let mut child_lsps = self.child_lsps.write().await;
+        let response = match self
+            .send_position_request(&routed, child_lsp, "textDocument/prepareRename", position, json!({}))
+            .await
+        {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        if let Some(error) = child_error(&response) {
+            return Err(error);
+        }
 
-            if !child_lsps.contains_key(&lang) {
-                let root_uri = match project_root.as_ref() {
-                    Some(root) => self.get_lsp_root_uri(root, &output_dir),
-                    None => {
-                        warn!("Project root not available for child LSP initialization");
-                        return Ok(None);
-                    }
-                };
+        let prepared: PrepareRenameResponse = match response.get("result").cloned().map(serde_json::from_value) {
+            Some(Ok(Some(prepared))) => prepared,
+            _ => return Ok(None),
+        };
+        let mapper = routed.mapper(child_lsp, self.client_encoding());
+        let map_range = |range: Range| {
+            mapper
+                .virtual_range_to_markdown(range)
+                .ok_or_else(|| request_failed("The symbol to rename is outside the code blocks"))
+        };
+        let prepared = match prepared {
+            PrepareRenameResponse::Range(range) => PrepareRenameResponse::Range(map_range(range)?),
+            PrepareRenameResponse::RangeWithPlaceholder { range, placeholder } => {
+                PrepareRenameResponse::RangeWithPlaceholder { range: map_range(range)?, placeholder }
+            }
+            default => default,
+        };
+        Ok(Some(cell_result(&cell, prepared)))
+    }
 
-                let init_params = ChildLspInitParams {
-                    lang: lang.to_string(),
-                    binary_name,
-                    args,
-                    root_uri,
-                    file_uri: file_uri.clone(),
-                    file_content: vdoc.content.clone(),
-                    init_options: self.config.get_init_options(&lang),
-                };
+    async fn rename(&self, params: RenameParams) -> JsonrpcResult<Option<WorkspaceEdit>> {
+        let position = params.text_document_position.position;
+        let uri = params.text_document_position.text_document.uri;
+        let (uri, position, cell) = self.resolve_cell(uri, position).await;
 
-                if let Ok(result) = ChildLspInitializer::initialize_child_lsp(init_params).await {
-                    self.cache_completion_triggers(&result.lang, &result.lsp).await;
-                    child_lsps.insert(result.lang, result.lsp);
-                }
-            }
+        let routed = match self.route_position_request("textDocument/rename", position, &uri).await {
+            Ok(routed) => routed,
+            Err(_) => return Ok(None),
+        };
+        let child_lsp = match routed.children.first() {
+            Some(child_lsp) => child_lsp,
+            None => return Ok(None),
+        };
+        let new_name = json!({ "newName": params.new_name });
+        let response = match self
+            .send_position_request(&routed, child_lsp, "textDocument/rename", position, new_name)
+            .await
+        {
+            Some(response) => response,
+            None => return Ok(None),
+        };
+        if let Some(error) = child_error(&response) {
+            return Err(error);
+        }
 
-            if let Some(child_lsp) = child_lsps.get(&lang) {
-                if let Ok(mut response) = child_lsp
-                    .send_request_raw("textDocument/documentSymbol", req_params)
-                    .await
-                {
-                    request_mapper::rewrite_positions(&mut response, &mapper, false);
-                    return Ok(serde_json::from_value(response).ok());
-                }
-            }
+        let edit: WorkspaceEdit = match response.get("result").cloned().map(serde_json::from_value) {
+            Some(Ok(Some(edit))) => edit,
+            _ => return Ok(None),
+        };
+        let virtual_uri = match Url::parse(&routed.file_uri) {
+            Ok(virtual_uri) => virtual_uri,
+            Err(_) => return Ok(None),
+        };
+        let version = match self.documents.read().await.get(&uri) {
+            Some(doc) => doc.version,
+            None => return Ok(None),
+        };
+
+        let mapper = routed.mapper(child_lsp, self.client_encoding());
+        workspace_edit::map_workspace_edit(edit, &mapper, &virtual_uri, &uri, version)
+            .map(|edit| Some(cell_result(&cell, edit)))
+            .map_err(request_failed)
+    }
+
+    async fn document_symbol(
+        &self,
+        params: DocumentSymbolParams,
+    ) -> JsonrpcResult<Option<DocumentSymbolResponse>> {
+        // For document symbols, we return symbols from the first language's code blocks
+        let response = self
+            .handle_document_request("textDocument/documentSymbol", params.text_document.uri, json!({}))
+            .await?;
+        if let Some(result) = response.get("result") {
+            Ok(serde_json::from_value(result.clone()).ok())
+        } else {
+            Ok(None)
         }
-        Ok(None)
     }
 
     async fn code_action(
@@ -782,92 +1858,19 @@ impl LanguageServer for LiterateLsp {
         &self,
         params: DocumentFormattingParams,
     ) -> JsonrpcResult<Option<Vec<TextEdit>>> {
-        let doc = self.document.read().await;
-        let doc_content = match doc.as_ref() {
-            Some(c) => c.clone(),
-            None => return Ok(None),
-        };
-
-        // For formatting, we need to format all code blocks
-        if let Some((lang, _, _)) = find_code_block_at_line(&doc_content, 0) {
-            let vdoc = build_virtual_document(&doc_content, &lang);
-            let mapper = PositionMapper::new(&vdoc.blocks);
-
-            let (binary_name, args) = match self.config.get_command_and_args(&lang) {
-                Some((cmd, args)) => (cmd, args),
-                None => {
-                    warn!("[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}", lang, lang);
-                    return Ok(None);
-                }
-            };
-
-            // Write virtual doc to disk
-            let project_root = self.project_root.read().await;
-            let output_dir = self.output_dir.read().await;
-
-            let markdown_filename = params.text_document.uri
-                .path_segments()
-                .and_then(|mut segs| segs.next_back())
-                .unwrap_or("document");
-
-            let file_uri = match project_root.as_ref() {
-                Some(root) => {
-                    match self.write_virtual_doc_to_disk(root, &output_dir, markdown_filename, &lang, vdoc.content.clone()) {
-                        Ok(uri) => uri,
-                        Err(e) => {
-                            warn!("Failed to write virtual doc for formatting: {}", e);
-                            return Ok(None);
-                        }
-                    }
-                }
-                None => {
-                    warn!("Project root not available for formatting");
-                    return Ok(None);
-                }
-            };
-
-            let mut req_params =
-                json!({"textDocument": {"uri": file_uri.clone()}});
-            request_mapper::rewrite_positions(&mut req_params, &mapper, true);
-
-            let mut child_lsps = self.child_lsps.write().await;
-
-            if !child_lsps.contains_key(&lang) {
-                let root_uri = match project_root.as_ref() {
-                    Some(root) => self.get_lsp_root_uri(root, &output_dir),
-                    None => {
-                        warn!("Project root not available for child LSP initialization");
-                        return Ok(None);
-                    }
-                };
-
-                let init_params = ChildLspInitParams {
-                    lang: lang.to_string(),
-                    binary_name,
-                    args,
-                    root_uri,
-                    file_uri: file_uri.clone(),
-                    file_content: vdoc.content.clone(),
-                    init_options: self.config.get_init_options(&lang),
-                };
-
-                if let Ok(result) = ChildLspInitializer::initialize_child_lsp(init_params).await {
-                    self.cache_completion_triggers(&result.lang, &result.lsp).await;
-                    child_lsps.insert(result.lang, result.lsp);
-                }
-            }
-
-            if let Some(child_lsp) = child_lsps.get(&lang) {
-                if let Ok(mut response) = child_lsp
-                    .send_request_raw("textDocument/formatting", req_params)
-                    .await
-                {
-                    request_mapper::rewrite_positions(&mut response, &mapper, false);
-                    return Ok(serde_json::from_value(response).ok());
-                }
-            }
+        // For formatting, we format the first language's code blocks
+        let response = self
+            .handle_document_request(
+                "textDocument/formatting",
+                params.text_document.uri,
+                json!({ "options": params.options }),
+            )
+            .await?;
+        if let Some(result) = response.get("result") {
+            Ok(serde_json::from_value(result.clone()).ok())
+        } else {
+            Ok(None)
         }
-        Ok(None)
     }
 
     async fn range_formatting(
@@ -906,11 +1909,98 @@ impl LanguageServer for LiterateLsp {
 
     async fn shutdown(&self) -> JsonrpcResult<()> {
         info!("[LiterateLsp] Shutdown requested");
-        // Just clear the child LSPs - Drop impl will kill processes
-        // Don't try to gracefully shutdown as it can hang
-        let mut child_lsps = self.child_lsps.write().await;
-        child_lsps.clear();
+        // Unregister the children first so their supervisors don't restart them
+        let children: Vec<(ChildKey, Arc<ChildLspManager>)> =
+            self.child_lsps.write().await.drain().collect();
+        self.virtual_docs.write().await.clear();
+
+        // Every child gets a bounded graceful shutdown, all in parallel
+        let mut shutdowns = tokio::task::JoinSet::new();
+        for ((_, lang, server), child_lsp) in children {
+            shutdowns.spawn(async move {
+                if let Err(e) = child_lsp.shutdown().await {
+                    warn!("[LiterateLsp] Failed to shut down '{}' child LSP for '{}': {}", server, lang, e);
+                }
+            });
+        }
+        while shutdowns.join_next().await.is_some() {}
+
         info!("[LiterateLsp] Child LSPs cleaned up");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_crashing_child_hits_restart_cap() {
+        let mut restarts = Restarts::default();
+        let child_key: ChildKey = (PathBuf::from("/book"), "rust".to_string(), "rust-analyzer".to_string());
+        let crash = Duration::from_millis(200);
+
+        for expected in 1..=constants::CHILD_LSP_MAX_RESTARTS {
+            assert_eq!(restarts.crashed(&child_key, crash), Some(expected));
+            // Requests arriving during the backoff must not spawn the child
+            assert!(!restarts.may_spawn(&child_key));
+            restarts.restarting(&child_key);
+            assert!(restarts.may_spawn(&child_key));
+        }
+        assert_eq!(restarts.crashed(&child_key, crash), None);
+        assert!(!restarts.may_spawn(&child_key));
+
+        restarts.forgive();
+        assert!(restarts.may_spawn(&child_key));
+        assert_eq!(restarts.crashed(&child_key, crash), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_failed_start_is_held_off() {
+        let project = tempfile::TempDir::new().unwrap();
+        std::fs::write(project.path().join(".literate.toml"), "[literate]\noutput_dir = \"./out\"\n").unwrap();
+        let uri = Url::from_file_path(project.path().join("book.md")).unwrap();
+        let (service, socket) = tower_lsp::LspService::new(|client| LiterateLsp::new(client, Config::default()));
+        // Messages to the editor fail instead of waiting for a reader
+        drop(socket);
+        let lsp = service.inner();
+        let doc = Document::new(&uri, "```rust\nfn main() {}\n```\n".to_string(), 1, &lsp.aliases);
+        // Exits before answering `initialize`
+        let server = LanguageServerCommand {
+            name: "exits".to_string(),
+            command: "sh".to_string(),
+            args: vec!["-c".to_string(), "exit 1".to_string()],
+            only_features: Vec::new(),
+            except_features: Vec::new(),
+        };
+        let child_key: ChildKey = (doc.project_root.clone().unwrap(), "rust".to_string(), "exits".to_string());
+
+        assert!(lsp.open_virtual_doc(&uri, &doc, "rust", None, "fn main() {}\n", &server).await.is_none());
+        assert_eq!(lsp.restarts.read().await.0.get(&child_key), Some(&RestartState::Restarting { attempt: 1 }));
+
+        // Requests during the backoff do not spawn it again, nor count as another failure
+        assert!(lsp.open_virtual_doc(&uri, &doc, "rust", None, "fn main() {}\n", &server).await.is_none());
+        assert_eq!(lsp.restarts.read().await.0.get(&child_key), Some(&RestartState::Restarting { attempt: 1 }));
+
+        let backoff = Duration::from_millis(constants::CHILD_LSP_RESTART_BACKOFF_MS);
+        tokio::time::sleep(backoff + Duration::from_millis(100)).await;
+        assert!(lsp.restarts.read().await.may_spawn(&child_key));
+        assert!(lsp.open_virtual_doc(&uri, &doc, "rust", None, "fn main() {}\n", &server).await.is_none());
+        assert_eq!(lsp.restarts.read().await.0.get(&child_key), Some(&RestartState::Restarting { attempt: 2 }));
+        assert!(lsp.child_lsps.read().await.is_empty());
+    }
+
+    #[test]
+    fn test_stable_child_gets_fresh_restart_budget() {
+        let mut restarts = Restarts::default();
+        let child_key: ChildKey = (PathBuf::from("/book"), "python".to_string(), "pyright".to_string());
+
+        assert_eq!(restarts.crashed(&child_key, Duration::from_millis(10)), Some(1));
+        restarts.restarting(&child_key);
+        assert_eq!(restarts.crashed(&child_key, Duration::from_millis(10)), Some(2));
+        restarts.restarting(&child_key);
+        let stable = Duration::from_secs(constants::CHILD_LSP_STABLE_SECS);
+        assert_eq!(restarts.crashed(&child_key, stable), Some(1));
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::LanguageAliases;
+use crate::fence::Fence;
+use crate::format::DocumentFormat;
+use crate::position::PositionEncoding;
+use serde_json::{json, Value};
+
+/// The span of lines that differ between two versions of a text
+///
+/// Lines `start..old_end` of the old text were replaced by lines `start..new_end`
+/// of the new text; everything before and after is identical.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineDiff {
+    pub start: usize,
+    pub old_end: usize,
+    pub new_end: usize,
+}
+
+/// Find the changed line span between two texts by trimming common leading and trailing lines
+pub fn changed_lines(old: &str, new: &str) -> Option<LineDiff> {
+    if old == new {
+        return None;
+    }
+
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+
+    let prefix = old_lines
+        .iter()
+        .zip(&new_lines)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = old_lines[prefix..]
+        .iter()
+        .rev()
+        .zip(new_lines[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    Some(LineDiff {
+        start: prefix,
+        old_end: old_lines.len() - suffix,
+        new_end: new_lines.len() - suffix,
+    })
+}
+
+/// Whether a fence overlaps the line span `start..end` (an empty span still touches its line)
+fn touches(fence: &Fence, start: usize, end: usize) -> bool {
+    let end = end.max(start + 1);
+    fence.start_line < end && start <= fence.end_line
+}
+
+/// Languages whose code blocks are affected by an edit of a document
+///
+/// Edits in prose between blocks affect nothing. Edits that add, remove or retag a
+/// fence, or move blocks between sessions, change how blocks are grouped, so every
+/// language in either version is affected. `new_fences` are the blocks of `new`, already
+/// scanned for the rest of the update.
+pub fn affected_languages(
+    old: &str,
+    new: &str,
+    new_fences: &[Fence],
+    format: DocumentFormat,
+    aliases: &LanguageAliases,
+) -> Vec<String> {
+    let diff = match changed_lines(old, new) {
+        Some(diff) => diff,
+        None => return Vec::new(),
+    };
+
+    let old_fences = format.scan(old);
+    let mut langs: Vec<String> = Vec::new();
+    let mut add = |lang: &str| {
+        let lang = aliases.resolve(lang);
+        if !lang.is_empty() && !langs.iter().any(|l| l == lang) {
+            langs.push(lang.to_string());
+        }
+    };
+
+    let same_structure = old_fences.len() == new_fences.len()
+        && old_fences
+            .iter()
+            .zip(new_fences)
+            .all(|(a, b)| a.info == b.info && a.scope_session == b.scope_session);
+
+    if same_structure {
+        for fence in old_fences.iter().filter(|f| touches(f, diff.start, diff.old_end)) {
+            add(fence.lang());
+        }
+        for fence in new_fences.iter().filter(|f| touches(f, diff.start, diff.new_end)) {
+            add(fence.lang());
+        }
+    } else {
+        for fence in old_fences.iter().chain(new_fences) {
+            add(fence.lang());
+        }
+    }
+
+    langs
+}
+
+/// Build a single incremental `contentChanges` entry turning `old` into `new`
+///
+/// The change replaces whole lines, so `encoding` (the child's) only matters when the
+/// range ends inside an unterminated last line.
+pub fn incremental_change(old: &str, new: &str, encoding: PositionEncoding) -> Option<Value> {
+    let diff = changed_lines(old, new)?;
+    let old_lines: Vec<&str> = old.split_inclusive('\n').collect();
+    let new_lines: Vec<&str> = new.split_inclusive('\n').collect();
+
+    // Replacing through the end of an unterminated last line must end inside that line
+    let end = match old_lines.last() {
+        Some(last) if diff.old_end == old_lines.len() && !last.ends_with('\n') => {
+            let last = last.trim_end_matches('\r');
+            json!({ "line": old_lines.len() - 1, "character": encoding.text_len(last) })
+        }
+        _ => json!({ "line": diff.old_end, "character": 0 }),
+    };
+
+    Some(json!({
+        "range": {
+            "start": { "line": diff.start, "character": 0 },
+            "end": end
+        },
+        "text": new_lines[diff.start..diff.new_end].concat()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOK: &str = "# Book\n\n```rust\nfn a() {}\n```\n\nProse\n\n```python\nx = 1\n```\n";
+
+    #[test]
+    fn test_changed_lines() {
+        let new = BOOK.replace("x = 1", "x = 2\ny = 3");
+        let diff = changed_lines(BOOK, &new).unwrap();
+        assert_eq!(diff, LineDiff { start: 9, old_end: 10, new_end: 11 });
+        assert!(changed_lines(BOOK, BOOK).is_none());
+    }
+
+    #[test]
+    fn test_affected_languages() {
+        // Edit inside the python block
+        let new = BOOK.replace("x = 1", "x = 2");
+        assert_eq!(affected_languages(BOOK, &new, &DocumentFormat::Markdown.scan(&new), DocumentFormat::Markdown, &LanguageAliases::default()), vec!["python"]);
+
+        // Edit in prose touches no block
+        let new = BOOK.replace("Prose", "More prose");
+        assert!(affected_languages(BOOK, &new, &DocumentFormat::Markdown.scan(&new), DocumentFormat::Markdown, &LanguageAliases::default()).is_empty());
+
+        // Retagging a fence affects every language
+        let new = BOOK.replace("```python", "```go");
+        assert_eq!(affected_languages(BOOK, &new, &DocumentFormat::Markdown.scan(&new), DocumentFormat::Markdown, &LanguageAliases::default()), vec!["rust", "python", "go"]);
+
+        // So does a session directive in prose
+        let new = BOOK.replace("Prose", "<!-- literate: session=other -->");
+        assert_eq!(affected_languages(BOOK, &new, &DocumentFormat::Markdown.scan(&new), DocumentFormat::Markdown, &LanguageAliases::default()), vec!["rust", "python"]);
+    }
+
+    #[test]
+    fn test_incremental_change() {
+        let old = "fn a() {}\n\nfn b() {}\n";
+        let new = "fn a() {}\n\nfn b() { a() }\nfn c() {}\n";
+        let change = incremental_change(old, new, PositionEncoding::Utf16).unwrap();
+        assert_eq!(change["range"]["start"], json!({"line": 2, "character": 0}));
+        assert_eq!(change["range"]["end"], json!({"line": 3, "character": 0}));
+        assert_eq!(change["text"], "fn b() { a() }\nfn c() {}\n");
+
+        // Unterminated last line, measured in the child's encoding
+        let change = incremental_change("a\nbé", "a\nc", PositionEncoding::Utf16).unwrap();
+        assert_eq!(change["range"]["end"], json!({"line": 1, "character": 2}));
+        assert_eq!(change["text"], "c");
+        let change = incremental_change("a\nbé", "a\nc", PositionEncoding::Utf8).unwrap();
+        assert_eq!(change["range"]["end"], json!({"line": 1, "character": 3}));
+    }
+}
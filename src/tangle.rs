@@ -0,0 +1,404 @@
+use crate::config::{Config, LanguageAliases};
+use crate::disk_vdoc::{document_filename, session_filename, DiskVirtualDoc};
+use crate::document_store::Document;
+use crate::format::get_document_language;
+use crate::utils::constants::{
+    ASCIIDOC_EXTENSIONS, LATEX_EXTENSIONS, MARKDOWN_EXTENSIONS, NOTEBOOK_EXTENSIONS, ORG_EXTENSIONS, RST_EXTENSIONS,
+    TYPST_EXTENSIONS,
+};
+use crate::virtual_doc::{collect_languages, collect_sessions};
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tower_lsp::lsp_types::Url;
+
+/// A virtual document to write, and the literate document it came from
+#[derive(Debug, Clone)]
+pub struct TangledFile {
+    pub path: PathBuf,
+    pub source: PathBuf,
+    pub lang: String,
+    pub session: Option<String>,
+    pub blocks: usize,
+    pub content: String,
+}
+
+/// Outcome of a tangle run
+#[derive(Debug, Default)]
+pub struct TangleReport {
+    /// Files written
+    pub written: Vec<TangledFile>,
+    /// Outputs claimed by several documents with different content; none of them is written
+    pub conflicts: Vec<Vec<TangledFile>>,
+    /// Inputs that could not be read and files that could not be written
+    pub errors: Vec<String>,
+}
+
+impl TangleReport {
+    /// Process exit code: non-zero when anything was left unwritten
+    pub fn exit_code(&self) -> i32 {
+        if self.conflicts.is_empty() && self.errors.is_empty() { 0 } else { 1 }
+    }
+}
+
+impl fmt::Display for TangleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.written {
+            writeln!(f, "wrote {} ({}) from {}", display_path(&file.path), describe(file), display_path(&file.source))?;
+        }
+        for claims in &self.conflicts {
+            writeln!(f, "conflict: {} would be written by:", display_path(&claims[0].path))?;
+            for file in claims {
+                writeln!(f, "    {} ({})", display_path(&file.source), describe(file))?;
+            }
+        }
+        for error in &self.errors {
+            writeln!(f, "error: {}", error)?;
+        }
+        write!(
+            f,
+            "Tangled {} file(s), {} conflict(s), {} error(s)",
+            self.written.len(),
+            self.conflicts.len(),
+            self.errors.len()
+        )
+    }
+}
+
+/// Language, session and block count of a tangled file, for the report
+fn describe(file: &TangledFile) -> String {
+    let session = match &file.session {
+        Some(session) => format!(", session {}", session),
+        None => String::new(),
+    };
+    format!("{}{}, {} block(s)", file.lang, session, file.blocks)
+}
+
+/// A path relative to the current directory when it is inside it
+pub(crate) fn display_path(path: &Path) -> String {
+    let relative = std::env::current_dir()
+        .ok()
+        .and_then(|cwd| path.strip_prefix(cwd).ok().map(Path::to_path_buf));
+    relative.unwrap_or_else(|| path.to_path_buf()).display().to_string()
+}
+
+/// Whether a file is a literate document literate-lsp extracts code from
+fn is_literate(path: &Path) -> bool {
+    let ext = match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => ext.to_lowercase(),
+        None => return false,
+    };
+    [
+        MARKDOWN_EXTENSIONS,
+        TYPST_EXTENSIONS,
+        ORG_EXTENSIONS,
+        RST_EXTENSIONS,
+        ASCIIDOC_EXTENSIONS,
+        LATEX_EXTENSIONS,
+        NOTEBOOK_EXTENSIONS,
+    ]
+    .iter()
+    .any(|extensions| extensions.contains(&ext.as_str()))
+}
+
+/// Every file under a directory, skipping hidden entries
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if path.is_dir() {
+            walk(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Translate a glob (`*`, `?`, `**`) into an anchored regex over `/`-separated paths
+fn glob_regex(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(?:.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    Regex::new(&pattern).ok()
+}
+
+/// Expand one input into the literate documents it names
+///
+/// An input is a file, a directory (searched recursively) or a glob such as `docs/**/*.md`.
+fn expand_input(input: &str) -> Result<Vec<PathBuf>, String> {
+    if !input.contains(['*', '?']) {
+        let path = PathBuf::from(input);
+        if path.is_dir() {
+            let mut files = Vec::new();
+            walk(&path, &mut files);
+            files.retain(|file| is_literate(file));
+            files.sort();
+            return Ok(files);
+        }
+        if path.is_file() {
+            return Ok(vec![path]);
+        }
+        return Err(format!("{}: no such file or directory", input));
+    }
+
+    // Walk from the directory before the first wildcard and match the rest
+    let components: Vec<&str> = input.split('/').collect();
+    let literal = components.iter().take_while(|c| !c.contains(['*', '?'])).count();
+    let base = match components[..literal].join("/") {
+        base if base.is_empty() && input.starts_with('/') => "/".to_string(),
+        base if base.is_empty() => ".".to_string(),
+        base => base,
+    };
+    let regex = glob_regex(&components[literal..].join("/")).ok_or_else(|| format!("{}: invalid pattern", input))?;
+
+    let base = PathBuf::from(base);
+    let mut files = Vec::new();
+    walk(&base, &mut files);
+    files.retain(|file| {
+        let relative = file.strip_prefix(&base).unwrap_or(file);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        is_literate(file) && regex.is_match(&relative)
+    });
+    files.sort();
+    Ok(files)
+}
+
+/// Absolute paths of the literate documents named by `inputs`, defaulting to the current directory
+///
+/// The same document named twice (e.g. by a directory and a glob) is listed once.
+/// Inputs that name nothing are reported in `errors`.
+pub(crate) fn collect_sources(inputs: &[String], errors: &mut Vec<String>) -> Vec<PathBuf> {
+    let inputs = if inputs.is_empty() { vec![".".to_string()] } else { inputs.to_vec() };
+
+    let mut sources: Vec<PathBuf> = Vec::new();
+    for input in &inputs {
+        let files = match expand_input(input) {
+            Ok(files) => files,
+            Err(error) => {
+                errors.push(error);
+                continue;
+            }
+        };
+        for file in files {
+            match std::fs::canonicalize(&file) {
+                Ok(source) if !sources.contains(&source) => sources.push(source),
+                Ok(_) => {}
+                Err(e) => errors.push(format!("{}: {}", file.display(), e)),
+            }
+        }
+    }
+    sources
+}
+
+/// Build every virtual document of one literate document, without writing them
+fn tangle_document(config: &Config, aliases: &Arc<LanguageAliases>, source: &Path) -> Result<Vec<TangledFile>, String> {
+    let text = std::fs::read_to_string(source).map_err(|e| format!("{}: {}", source.display(), e))?;
+    let uri = Url::from_file_path(source).map_err(|_| format!("{}: not a local file", source.display()))?;
+    let doc = Document::new(&uri, text, 0, aliases);
+    let project_root = doc.project_root.clone().unwrap_or_else(|| PathBuf::from("."));
+    let filename = document_filename(&project_root, source);
+
+    let mut files = Vec::new();
+    let doc_lang = get_document_language(&uri);
+    let fences = doc.fences();
+    for lang in collect_languages(&fences, &doc.aliases) {
+        // Blocks the language server leaves out are examples, not code
+        if config.skips_language(doc_lang.as_deref(), &lang) {
+            continue;
+        }
+        let extension = config.get_extension_for_language(&lang).unwrap_or_else(|| lang.clone());
+        for session in collect_sessions(&fences, &doc.aliases, &lang) {
+            let vdoc = doc.virtual_document(&fences, &lang, session.as_deref());
+            if vdoc.blocks.is_empty() {
+                continue;
+            }
+            let markdown_filename = session_filename(&filename, session.as_deref());
+            files.push(TangledFile {
+                path: DiskVirtualDoc::output_path(&project_root, &doc.output_dir, &markdown_filename, &extension),
+                source: source.to_path_buf(),
+                lang: lang.clone(),
+                session,
+                blocks: vdoc.blocks.len(),
+                content: vdoc.content,
+            });
+        }
+    }
+    Ok(files)
+}
+
+/// Write the virtual documents of every literate document named by `inputs`
+///
+/// Inputs default to the current directory. Each document's `.literate.toml` decides
+/// where its files go. An output claimed by two documents with different content is a
+/// conflict and is left unwritten.
+pub fn tangle(config: &Config, inputs: &[String]) -> TangleReport {
+    let mut report = TangleReport::default();
+    let sources = collect_sources(inputs, &mut report.errors);
+
+    let aliases = Arc::new(config.language_aliases());
+    let mut outputs: BTreeMap<PathBuf, Vec<TangledFile>> = BTreeMap::new();
+    for source in sources {
+        match tangle_document(config, &aliases, &source) {
+            Ok(files) => {
+                for file in files {
+                    outputs.entry(file.path.clone()).or_default().push(file);
+                }
+            }
+            Err(error) => report.errors.push(error),
+        }
+    }
+
+    for (path, claims) in outputs {
+        if claims.iter().any(|file| file.content != claims[0].content) {
+            report.conflicts.push(claims);
+            continue;
+        }
+        let file = claims.into_iter().next().expect("outputs have at least one claim");
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, &file.content));
+        match written {
+            Ok(()) => report.written.push(file),
+            Err(e) => report.errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+[language-server]
+
+[[language]]
+name = "rust"
+file-types = ["rs"]
+
+[[language]]
+name = "python"
+file-types = ["py"]
+"#,
+        )
+        .unwrap()
+    }
+
+    fn project() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        std::fs::write(dir.path().join(".literate.toml"), "[literate]\noutput_dir = \"./out\"\n").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_glob_regex() {
+        let regex = glob_regex("**/*.md").unwrap();
+        assert!(regex.is_match("book.md"));
+        assert!(regex.is_match("docs/guide/book.md"));
+        assert!(!regex.is_match("book.mdx"));
+
+        let regex = glob_regex("ch?/*.typ").unwrap();
+        assert!(regex.is_match("ch1/intro.typ"));
+        assert!(!regex.is_match("ch1/nested/intro.typ"));
+    }
+
+    #[test]
+    fn test_tangle_writes_every_language_and_session() {
+        let dir = project();
+        let book = "# Book\n\n```rust\nfn main() {}\n```\n\n```python {session=tools}\nprint(1)\n```\n\n```markdown\n# example\n```\n";
+        std::fs::write(dir.path().join("book.md"), book).unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "```rust\nignored\n```\n").unwrap();
+
+        let input = dir.path().display().to_string();
+        let report = tangle(&config(), &[input]);
+        assert_eq!(report.exit_code(), 0, "{}", report);
+        assert_eq!(report.written.len(), 2);
+
+        let out = dir.path().join("out");
+        assert_eq!(std::fs::read_to_string(out.join("book.rs")).unwrap(), "fn main() {}\n");
+        assert_eq!(std::fs::read_to_string(out.join("book.tools.py")).unwrap(), "print(1)\n");
+        assert!(!out.join("book.md").exists());
+    }
+
+    #[test]
+    fn test_blocks_the_server_skips_are_not_written() {
+        let dir = project();
+        let book = "= Book\n\n```typst\n#let x = 1\n```\n\n```markdown\n# example\n```\n\n```python\nprint(1)\n```\n";
+        std::fs::write(dir.path().join("book.typ"), book).unwrap();
+
+        // The same rule the language server uses for the document
+        let config = config();
+        assert!(config.skips_language(Some("typst"), "typst"));
+        assert!(config.skips_language(Some("typst"), "markdown"));
+        let input = dir.path().display().to_string();
+        let report = tangle(&config, &[input]);
+        assert_eq!(report.exit_code(), 0, "{}", report);
+        let written: Vec<_> = report.written.iter().map(|file| file.lang.as_str()).collect();
+        assert_eq!(written, vec!["python"]);
+        assert!(!dir.path().join("out/book.typ").exists());
+        assert!(!dir.path().join("out/book.md").exists());
+    }
+
+    #[test]
+    fn test_conflicting_outputs_are_not_written() {
+        let dir = project();
+        std::fs::create_dir_all(dir.path().join("a")).unwrap();
+        std::fs::create_dir_all(dir.path().join("b")).unwrap();
+        std::fs::write(dir.path().join("a/book.md"), "```rust\nfn a() {}\n```\n").unwrap();
+        std::fs::write(dir.path().join("a/book.typ"), "```rust\nfn b() {}\n```\n").unwrap();
+        std::fs::write(dir.path().join("c.md"), "```rust\nfn c() {}\n```\n").unwrap();
+
+        let input = dir.path().display().to_string();
+        let report = tangle(&config(), &[input]);
+        assert_eq!(report.exit_code(), 1);
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].len(), 2);
+        assert!(!dir.path().join("out/a/book.rs").exists());
+        assert!(dir.path().join("out/c.rs").exists());
+        assert!(report.to_string().contains("conflict:"));
+    }
+
+    #[test]
+    fn test_same_named_documents_in_other_directories() {
+        let dir = project();
+        std::fs::create_dir_all(dir.path().join("docs/a")).unwrap();
+        std::fs::create_dir_all(dir.path().join("docs/b")).unwrap();
+        std::fs::write(dir.path().join("docs/a/README.md"), "```rust\nfn a() {}\n```\n").unwrap();
+        std::fs::write(dir.path().join("docs/b/README.md"), "```rust\nfn b() {}\n```\n").unwrap();
+
+        let pattern = format!("{}/**/*.md", dir.path().display());
+        let report = tangle(&config(), &[pattern]);
+        assert_eq!(report.exit_code(), 0, "{}", report);
+        let out = dir.path().join("out/docs");
+        assert_eq!(std::fs::read_to_string(out.join("a/README.rs")).unwrap(), "fn a() {}\n");
+        assert_eq!(std::fs::read_to_string(out.join("b/README.rs")).unwrap(), "fn b() {}\n");
+    }
+}
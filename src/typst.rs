@@ -0,0 +1,498 @@
+use crate::fence::{indent_of, line_of, line_starts, Fence, LineSource};
+use crate::format::BlockExtractor;
+
+/// Finds the raw blocks and `#raw` calls of Typst documents
+pub struct TypstExtractor;
+
+impl BlockExtractor for TypstExtractor {
+    fn extract(&self, text: &str) -> Vec<Fence> {
+        scan_raw_blocks(text)
+    }
+}
+
+/// Text of a line, without its line ending
+fn line_text<'a>(text: &'a str, starts: &[usize], line: usize) -> &'a str {
+    let rest = &text[starts[line]..];
+    rest[..rest.find('\n').unwrap_or(rest.len())].trim_end_matches('\r')
+}
+
+/// Add a run boundary, unless the previous run ended at the same place
+fn push_anchor(anchors: &mut Vec<(usize, usize)>, anchor: (usize, usize)) {
+    if anchors.last() != Some(&anchor) {
+        anchors.push(anchor);
+    }
+}
+
+fn skip_whitespace(bytes: &[u8], mut at: usize) -> usize {
+    while bytes.get(at).is_some_and(u8::is_ascii_whitespace) {
+        at += 1;
+    }
+    at
+}
+
+/// End of a `/* ... */` comment starting at `at`; Typst block comments nest
+fn skip_block_comment(bytes: &[u8], mut at: usize) -> usize {
+    let mut depth = 0;
+    while at < bytes.len() {
+        if bytes[at..].starts_with(b"/*") {
+            depth += 1;
+            at += 2;
+        } else if bytes[at..].starts_with(b"*/") {
+            depth -= 1;
+            at += 2;
+            if depth == 0 {
+                return at;
+            }
+        } else {
+            at += 1;
+        }
+    }
+    bytes.len()
+}
+
+/// Start of the first run of `count` backticks from `from` on, which closes a raw block
+fn find_closing(bytes: &[u8], from: usize, count: usize) -> Option<usize> {
+    let mut found = 0;
+    for (at, &byte) in bytes.iter().enumerate().skip(from) {
+        if byte != b'`' {
+            found = 0;
+            continue;
+        }
+        found += 1;
+        if found == count {
+            return Some(at + 1 - count);
+        }
+    }
+    None
+}
+
+/// Build the block for a raw block opened by `fence_len` backticks at `open` and closed at `close`
+///
+/// As in Typst, code after the language tag on the opening line and before the closing
+/// backticks on the last line belongs to the block unless it is blank, and the other
+/// lines lose their common indentation.
+fn raw_block(text: &str, starts: &[usize], open: usize, fence_len: usize, close: usize) -> Fence {
+    let start_line = line_of(starts, open);
+    let end_line = line_of(starts, close);
+    let after = open + fence_len;
+    let tag_len = text[after..close].find(char::is_whitespace).unwrap_or(close - after);
+    let body_start = after + tag_len;
+
+    // (offset in the document, text) of each line of the body
+    let mut pieces: Vec<(usize, &str)> = Vec::new();
+    let mut offset = body_start;
+    for piece in text[body_start..close].split('\n') {
+        pieces.push((offset, piece.trim_end_matches('\r')));
+        offset += piece.len() + 1;
+    }
+
+    let (first_offset, first) = pieces.remove(0);
+    // The last piece shares the closing line and is dropped when blank
+    let last_kept = match pieces.last() {
+        Some((_, last)) if last.trim().is_empty() => {
+            pieces.pop();
+            false
+        }
+        Some(_) => true,
+        None => false,
+    };
+    let dedent = pieces
+        .iter()
+        .filter(|(_, piece)| !piece.trim().is_empty())
+        .map(|(_, piece)| indent_of(piece))
+        .min()
+        .unwrap_or(0);
+
+    // (offset in the document, code) of each content line
+    let mut code: Vec<(usize, &str)> = Vec::new();
+    let first_kept = !first.trim().is_empty();
+    if first_kept {
+        let trimmed = first.trim_start();
+        code.push((first_offset + first.len() - trimmed.len(), trimmed));
+    }
+    for (offset, piece) in &pieces {
+        let width = if piece.trim().is_empty() { piece.len() } else { dedent.min(indent_of(piece)) };
+        code.push((offset + width, &piece[width..]));
+    }
+
+    // Code sharing the opening or closing line is not simply the lines in between
+    let shares_line = first_kept || last_kept;
+    let mut fence = Fence {
+        fence_char: '`',
+        fence_len,
+        indent: open - starts[start_line],
+        info: text[after..body_start].to_string(),
+        start_line,
+        end_line,
+        lines: Vec::new(),
+        prefixes: Vec::new(),
+        sources: Vec::new(),
+        scope_session: None,
+        options: Vec::new(),
+    };
+    for (offset, line) in code {
+        let number = line_of(starts, offset);
+        let prefix = offset - starts[number];
+        fence.lines.push(line.to_string());
+        fence.prefixes.push(prefix);
+        if shares_line {
+            fence.sources.push(LineSource::new(number, line_text(text, starts, number), prefix));
+        }
+    }
+    fence
+}
+
+/// Read a string literal starting at the quote at `open`, split into lines
+///
+/// Lines end at newlines in the source and at `\n` escapes. Returns each line's code
+/// and where it sits, and the offset just past the closing quote.
+fn string_literal(text: &str, starts: &[usize], open: usize) -> Option<(Vec<(String, LineSource)>, usize)> {
+    let mut lines = Vec::new();
+    let mut line = line_of(starts, open);
+    let mut code = String::new();
+    let mut anchors = vec![(0, open + 1 - starts[line])];
+    let mut finish = |code: &mut String, anchors: &mut Vec<(usize, usize)>, line: usize, end: usize| {
+        push_anchor(anchors, (code.len(), end));
+        let source = LineSource {
+            line,
+            text: line_text(text, starts, line).to_string(),
+            anchors: std::mem::take(anchors),
+        };
+        lines.push((std::mem::take(code), source));
+    };
+
+    let mut at = open + 1;
+    loop {
+        let ch = text[at..].chars().next()?;
+        let column = at - starts[line];
+        match ch {
+            '"' => {
+                finish(&mut code, &mut anchors, line, column);
+                return Some((lines, at + 1));
+            }
+            '\n' => {
+                let crlf = text[..at].ends_with('\r');
+                if crlf {
+                    code.pop();
+                }
+                finish(&mut code, &mut anchors, line, column - usize::from(crlf));
+                line += 1;
+                anchors.push((0, 0));
+                at += 1;
+            }
+            '\\' => {
+                let (decoded, len) = match text[at + 1..].chars().next()? {
+                    'n' => {
+                        finish(&mut code, &mut anchors, line, column);
+                        anchors.push((0, column + 2));
+                        at += 2;
+                        continue;
+                    }
+                    'r' => ('\r', 2),
+                    't' => ('\t', 2),
+                    '\\' => ('\\', 2),
+                    '"' => ('"', 2),
+                    'u' => {
+                        let hex = text[at + 2..].strip_prefix('{')?;
+                        let hex = &hex[..hex.find('}')?];
+                        let decoded = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+                        (decoded, hex.len() + 4)
+                    }
+                    // Not an escape: the backslash is literal
+                    _ => {
+                        code.push('\\');
+                        at += 1;
+                        continue;
+                    }
+                };
+                push_anchor(&mut anchors, (code.len(), column));
+                code.push(decoded);
+                push_anchor(&mut anchors, (code.len(), column + len));
+                at += len;
+            }
+            _ => {
+                code.push(ch);
+                at += ch.len_utf8();
+            }
+        }
+    }
+}
+
+/// Read a `#raw(...)` call at `at`, returning its block and the offset just past the call
+///
+/// Only calls whose text is a string literal are code blocks; the language comes from
+/// the `lang` argument.
+fn raw_call(text: &str, starts: &[usize], at: usize) -> Option<(Fence, usize)> {
+    let bytes = text.as_bytes();
+    let mut lang = String::new();
+    let mut code = None;
+    let mut i = at + "#raw(".len();
+    loop {
+        i = skip_whitespace(bytes, i);
+        if bytes.get(i) == Some(&b')') {
+            break;
+        }
+
+        let ident = bytes[i..]
+            .iter()
+            .take_while(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+            .count();
+        let colon = skip_whitespace(bytes, i + ident);
+        let name = if ident > 0 && bytes.get(colon) == Some(&b':') {
+            let name = &text[i..i + ident];
+            i = skip_whitespace(bytes, colon + 1);
+            Some(name)
+        } else {
+            None
+        };
+
+        if bytes.get(i) == Some(&b'"') {
+            let (lines, end) = string_literal(text, starts, i)?;
+            match name {
+                None => code = Some(lines),
+                Some("lang") => lang = lines.into_iter().map(|(code, _)| code).collect(),
+                Some(_) => {}
+            }
+            i = end;
+        } else {
+            // Other arguments (`block: true`, `tab-size: 4`) are single tokens
+            let len = bytes[i..]
+                .iter()
+                .take_while(|b| !b.is_ascii_whitespace() && !matches!(b, b',' | b')'))
+                .count();
+            if len == 0 || bytes[i..i + len].iter().any(|b| matches!(b, b'(' | b'[' | b'{' | b'"')) {
+                return None;
+            }
+            i += len;
+        }
+
+        i = skip_whitespace(bytes, i);
+        match bytes.get(i)? {
+            b',' => i += 1,
+            b')' => break,
+            _ => return None,
+        }
+    }
+
+    let (lines, sources): (Vec<String>, Vec<LineSource>) = code?.into_iter().unzip();
+    let start_line = line_of(starts, at);
+    let fence = Fence {
+        fence_char: '#',
+        fence_len: 1,
+        indent: at - starts[start_line],
+        info: lang,
+        start_line,
+        end_line: line_of(starts, i),
+        lines,
+        prefixes: sources.iter().map(|source| source.anchors[0].1).collect(),
+        sources,
+        scope_session: None,
+        options: Vec::new(),
+    };
+    Some((fence, i + 1))
+}
+
+/// Keywords whose `#` expression runs to the end of the line instead of the first space
+const LINE_KEYWORDS: &[&str] = &["let", "set", "show", "import", "include", "return", "if", "for", "while", "context"];
+
+/// Offset just past a code-mode string literal starting at the quote at `open`
+fn skip_string(bytes: &[u8], open: usize) -> usize {
+    let mut at = open + 1;
+    while at < bytes.len() {
+        match bytes[at] {
+            b'\\' => at += 2,
+            b'"' => return at + 1,
+            _ => at += 1,
+        }
+    }
+    bytes.len()
+}
+
+/// Find the code blocks of a Typst document
+///
+/// Raw blocks open with three or more backticks and the language tag, and close at the
+/// next run of as many backticks. `#raw(lang: "...", "...")` calls are read with their
+/// string escapes. Inline raw text (one backtick) has no language and is skipped, as is
+/// anything in a comment, in a string of a `#` expression or escaped with a backslash.
+pub fn scan_raw_blocks(text: &str) -> Vec<Fence> {
+    let starts = line_starts(text);
+    let bytes = text.as_bytes();
+    let mut blocks = Vec::new();
+    // Open `#` expressions and their brackets, innermost last: `#` ends at the first space,
+    // `l` at the end of the line, `(` and `{` are code, `[` is markup again
+    let mut nesting: Vec<u8> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let top = nesting.last().copied();
+        if matches!(top, Some(b'#' | b'l' | b'(' | b'{')) {
+            match bytes[i] {
+                b'"' => {
+                    i = skip_string(bytes, i);
+                    continue;
+                }
+                b'(' | b'{' | b'[' => {
+                    nesting.push(bytes[i]);
+                    i += 1;
+                    continue;
+                }
+                b')' | b'}' => {
+                    if matches!(top, Some(b'(' | b'{')) {
+                        nesting.pop();
+                    }
+                    i += 1;
+                    continue;
+                }
+                b'\n' | b';' if matches!(top, Some(b'#' | b'l')) => {
+                    nesting.pop();
+                    i += 1;
+                    continue;
+                }
+                b'/' | b'`' => {}
+                byte if top == Some(b'#') && !(byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'-' | b'.')) => {
+                    nesting.pop();
+                    continue;
+                }
+                _ => {}
+            }
+        } else if top == Some(b'[') {
+            match bytes[i] {
+                b'[' => nesting.push(b'['),
+                b']' => {
+                    nesting.pop();
+                }
+                _ => {}
+            }
+        }
+
+        match bytes[i] {
+            b'\\' => i += 1 + text[i + 1..].chars().next().map_or(0, char::len_utf8),
+            // `//` starts a comment, except in a URL
+            b'/' if bytes.get(i + 1) == Some(&b'/') && (i == 0 || bytes[i - 1] != b':') => {
+                i = text[i..].find('\n').map_or(text.len(), |end| i + end);
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_block_comment(bytes, i),
+            b'`' => {
+                let fence_len = bytes[i..].iter().take_while(|&&b| b == b'`').count();
+                // Two backticks are empty raw text
+                if fence_len == 2 {
+                    i += 2;
+                    continue;
+                }
+                // An unclosed raw block runs to the end of the document and is not code
+                let close = match find_closing(bytes, i + fence_len, fence_len) {
+                    Some(close) => close,
+                    None => break,
+                };
+                if fence_len >= 3 {
+                    blocks.push(raw_block(text, &starts, i, fence_len, close));
+                }
+                i = close + fence_len;
+            }
+            b'#' if text[i..].starts_with("#raw(") => match raw_call(text, &starts, i) {
+                Some((block, end)) => {
+                    blocks.push(block);
+                    i = end;
+                }
+                None => {
+                    nesting.push(b'#');
+                    i += 1;
+                }
+            },
+            // An identifier, `(` or `{` after `#` starts a code expression
+            b'#' if bytes.get(i + 1).is_some_and(|&b| b.is_ascii_alphabetic() || matches!(b, b'_' | b'(' | b'{')) => {
+                let ident: String = text[i + 1..]
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric() || *c == '_')
+                    .collect();
+                nesting.push(if LINE_KEYWORDS.contains(&ident.as_str()) { b'l' } else { b'#' });
+                i += 1 + ident.len();
+            }
+            _ => i += 1,
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_raw_blocks() {
+        let text = "= Intro\n\n```rust\nfn main() {}\n```\n\n  ````python\n  s = \"```\"\n\n    print(s)\n  ````\n";
+        let blocks = scan_raw_blocks(text);
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].lang(), "rust");
+        assert_eq!((blocks[0].start_line, blocks[0].end_line), (2, 4));
+        assert_eq!(blocks[0].lines, vec!["fn main() {}"]);
+        assert!(blocks[0].sources.is_empty());
+
+        // Four backticks wrap three, and the common indentation is a prefix
+        assert_eq!(blocks[1].lang(), "python");
+        assert_eq!(blocks[1].fence_len, 4);
+        assert_eq!(blocks[1].lines, vec!["s = \"```\"", "", "  print(s)"]);
+        assert_eq!(blocks[1].prefixes, vec![2, 0, 2]);
+        assert_eq!(blocks[1].content_start(), 7);
+        assert_eq!(blocks[1].content_end(), 9);
+    }
+
+    #[test]
+    fn test_code_on_the_fence_lines() {
+        let text = "Voilà ```rust let x = 1;```\n\n```go fmt.Println(1)\n  return```\n";
+        let blocks = scan_raw_blocks(text);
+        assert_eq!(blocks.len(), 2);
+
+        assert_eq!(blocks[0].lines, vec!["let x = 1;"]);
+        assert_eq!((blocks[0].content_start(), blocks[0].content_end()), (0, 0));
+        assert_eq!(blocks[0].sources[0].anchors, vec![(0, 15)]);
+
+        assert_eq!(blocks[1].lines, vec!["fmt.Println(1)", "return"]);
+        assert_eq!((blocks[1].content_start(), blocks[1].content_end()), (2, 3));
+        assert_eq!(blocks[1].prefixes, vec![6, 2]);
+    }
+
+    #[test]
+    fn test_comments_and_escapes_hide_backticks() {
+        let text = "// ```rust\n/* ```rust\n/* nested */ ``` */\n\\```rust not a block\n``\n`inline`\nhttps://example.com ```sh\nls\n```\n";
+        let blocks = scan_raw_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].lang(), "sh");
+        assert_eq!(blocks[0].lines, vec!["ls"]);
+    }
+
+    #[test]
+    fn test_strings_in_code_hide_backticks() {
+        let text = "#let s = \"```\"\n#let t = \"a \\\"```\\\" b\"; #f(x: \"```\")[```rust\nfn f() {}\n```]\n\n```rust\nfn main() {}\n```\n";
+        let blocks = scan_raw_blocks(text);
+        assert_eq!(blocks.len(), 2);
+        // A content block is markup again
+        assert_eq!(blocks[0].lang(), "rust");
+        assert_eq!(blocks[0].lines, vec!["fn f() {}"]);
+        assert_eq!(blocks[1].lang(), "rust");
+        assert_eq!(blocks[1].lines, vec!["fn main() {}"]);
+        assert_eq!(blocks[1].start_line, 5);
+    }
+
+    #[test]
+    fn test_raw_calls() {
+        let text = "#raw(lang: \"rust\", block: true, \"let s = \\\"é\\\";\\nlet t = s;\")\n";
+        let blocks = scan_raw_blocks(text);
+        assert_eq!(blocks.len(), 1);
+        let block = &blocks[0];
+        assert_eq!(block.lang(), "rust");
+        assert_eq!(block.lines, vec!["let s = \"é\";", "let t = s;"]);
+        assert_eq!((block.start_line, block.end_line), (0, 0));
+
+        // Both lines sit on line 0; columns skip the escapes
+        assert_eq!(block.content_line(1), 0);
+        assert_eq!(block.sources[0].source_byte(9), 43);
+        assert_eq!(block.sources[0].code_byte(43), 9);
+        assert_eq!(block.sources[0].code_byte(42), 8);
+        assert_eq!(block.sources[0].source_byte(11), 45);
+        assert_eq!(block.sources[1].source_byte(4), 54);
+
+        // Text that is not a string literal is not a block
+        assert!(scan_raw_blocks("#raw(read(\"a.rs\"), lang: \"rust\")").is_empty());
+    }
+}
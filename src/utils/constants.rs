@@ -7,6 +7,27 @@ pub const VIRTUAL_DOC_DEBUG_DIR: &str = "/tmp";
 /// LSP response timeout in seconds
 pub const LSP_RESPONSE_TIMEOUT_SECS: u64 = 5;
 
+/// Number of stderr lines kept from a child LSP for crash reports
+pub const CHILD_LSP_STDERR_TAIL_LINES: usize = 20;
+
+/// Restarts attempted for a crashing child LSP before giving up
+pub const CHILD_LSP_MAX_RESTARTS: u32 = 5;
+
+/// Delay before the first restart of a crashed child LSP; doubled for each further attempt
+pub const CHILD_LSP_RESTART_BACKOFF_MS: u64 = 500;
+
+/// Time a child LSP gets to answer `shutdown` and then to exit before it is killed
+pub const CHILD_LSP_SHUTDOWN_TIMEOUT_SECS: u64 = 2;
+
+/// A child LSP that ran this long before crashing gets a fresh restart budget
+pub const CHILD_LSP_STABLE_SECS: u64 = 60;
+
+/// `literate-lsp check`: quiet period after which a child's diagnostics are considered settled
+pub const CHECK_SETTLE_MS: u64 = 2000;
+
+/// `literate-lsp check`: default limit on waiting for diagnostics
+pub const CHECK_TIMEOUT_SECS: u64 = 60;
+
 /// Error message for missing LSP configuration
 pub const ERROR_NO_LSP_FOUND: &str =
     "**Language '{}' is not configured.**\n\n\
@@ -55,7 +76,12 @@ pub const LOG_NO_LSP_FOUND: &str =
     "[LiterateLsp] No LSP found for language '{}'. Check: literate-lsp --health {}";
 
 /// Hardcoded file extensions
-pub const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkdn", "mdx", "mmd"];
+pub const MARKDOWN_EXTENSIONS: &[&str] = &["md", "markdown", "mdown", "mkdn", "mdx", "mmd", "qmd", "rmd"];
 pub const TYPST_EXTENSIONS: &[&str] = &["typ"];
+pub const ORG_EXTENSIONS: &[&str] = &["org"];
+pub const RST_EXTENSIONS: &[&str] = &["rst", "rest"];
+pub const ASCIIDOC_EXTENSIONS: &[&str] = &["adoc", "asciidoc"];
+pub const LATEX_EXTENSIONS: &[&str] = &["tex", "ltx"];
+pub const NOTEBOOK_EXTENSIONS: &[&str] = &["ipynb"];
 pub const GO_EXTENSIONS: &[&str] = &["go"];
 pub const FORTH_EXTENSIONS: &[&str] = &["forth", "fth"];
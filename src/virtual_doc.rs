@@ -1,6 +1,11 @@
+use crate::attributes::BlockAttributes;
+use crate::chunks::{reference, ChunkGraph};
+use crate::config::{LanguageAliases, Wrapper};
+use crate::fence::{find_fence_at_line, Fence, LineSource};
+use crate::format::DocumentFormat;
 use tracing::debug;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CodeBlock {
     pub lang: String,
     pub markdown_start: usize,    // Line with opening ```
@@ -10,80 +15,270 @@ pub struct CodeBlock {
     pub virtual_start: usize,
     pub virtual_end: usize,
     pub content: String,
+    pub indent: usize,            // Column offset of the content (list/blockquote nesting)
+    pub prefixes: Vec<usize>,     // Stripped prefix width of each content line
+    pub expansion_indent: usize,  // Indentation added in front of each line by a chunk reference
+    pub sources: Vec<LineSource>, // Where each content line sits, if not at `content_start + offset`
+    pub attributes: BlockAttributes,
+    pub options: Vec<(String, String)>, // Cell options or Org header arguments, kept out of `content`
 }
 
-#[derive(Debug)]
+impl CodeBlock {
+    /// Width of the prefix stripped from a content line, by offset from `content_start`
+    ///
+    /// Lines past the recorded ones (e.g. an end position just after the block) use the fence indent.
+    pub fn prefix_width(&self, offset: usize) -> usize {
+        self.prefixes.get(offset).copied().unwrap_or(self.indent)
+    }
+
+    /// Line of the document holding a content line, by offset from `content_start`
+    pub fn markdown_line(&self, offset: usize) -> usize {
+        self.sources.get(offset).map_or(self.content_start + offset, |source| source.line)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct VirtualDocument {
     pub content: String,
     pub blocks: Vec<CodeBlock>,
 }
 
-pub fn build_virtual_document(markdown: &str, target_lang: &str) -> VirtualDocument {
-    debug!("[VirtualDoc] Building virtual document for language: '{}'", target_lang);
-    let lines: Vec<&str> = markdown.lines().collect();
-    let mut blocks = Vec::new();
-    let mut virtual_content = String::new();
-    let mut virtual_line = 0;
-    let mut in_code_block = false;
-    let mut current_block_lang = String::new();
-    let mut block_start = 0;
-    let mut block_content = String::new();
-
-    for (idx, line) in lines.iter().enumerate() {
-        if !in_code_block {
-            if let Some(pos) = line.find("```") {
-                let lang_start = pos + 3;
-                if lang_start <= line.len() {
-                    let lang_part = &line[lang_start..];
-                    let lang = lang_part.split_whitespace().next().unwrap_or("").to_string();
-                    debug!("[VirtualDoc] Found code block with language: '{}'", lang);
-                    in_code_block = true;
-                    current_block_lang = lang;
-                    block_start = idx;
-                    block_content.clear();
-                }
+impl VirtualDocument {
+    /// Surround the blocks with a wrapper's prelude and epilogue
+    ///
+    /// Blocks are shifted past the prelude. The injected lines belong to no block,
+    /// so positions in them never map back to the markdown document.
+    pub fn wrap(mut self, wrapper: &Wrapper) -> Self {
+        if self.blocks.is_empty() {
+            return self;
+        }
+
+        let mut content = String::new();
+        for text in [wrapper.prelude.as_str(), &self.content, wrapper.epilogue.as_str()] {
+            content.push_str(text);
+            if !text.is_empty() && !text.ends_with('\n') {
+                content.push('\n');
             }
-        } else if line.contains("```") {
-            debug!("[VirtualDoc] End of block '{}', checking if matches target '{}'", current_block_lang, target_lang);
-            if current_block_lang == target_lang {
-                // Add blank line separator before this block (except for first block)
-                if !blocks.is_empty() {
-                    virtual_content.push('\n');
-                    virtual_line += 1;
-                }
+        }
 
-                let virtual_start = virtual_line;
-                let block_lines: Vec<&str> = block_content.trim_end().lines().collect();
-                for (i, content_line) in block_lines.iter().enumerate() {
-                    if i > 0 {
-                        virtual_content.push('\n');
-                        virtual_line += 1;
-                    }
-                    virtual_content.push_str(content_line);
-                }
-                // Always push newline at end of block
-                if !block_content.is_empty() {
-                    virtual_content.push('\n');
-                    virtual_line += 1;
+        let prelude_lines = wrapper.prelude.lines().count();
+        for block in &mut self.blocks {
+            block.virtual_start += prelude_lines;
+            block.virtual_end += prelude_lines;
+        }
+        self.content = content;
+        self
+    }
+}
+
+/// Strip the rustdoc marker from a hidden line, returning the code and the stripped width
+///
+/// `# code` and a lone `#` are hidden from readers but still compiled, and `##` escapes
+/// a literal `#`. Leading whitespace goes with the marker, as in rustdoc.
+fn strip_hidden_marker(line: &str) -> (&str, usize) {
+    let code = line.trim_start();
+    let indent = line.len() - code.len();
+    if code.starts_with("##") {
+        (&code[1..], indent + 1)
+    } else if let Some(hidden) = code.strip_prefix("# ") {
+        (hidden, indent + 2)
+    } else if code.trim_end() == "#" {
+        ("", line.len())
+    } else {
+        (line, 0)
+    }
+}
+
+/// Blocks of one (language, session) group that take part in its virtual document
+///
+/// Blocks are grouped by the language their name resolves to, so `rs` and `rust`
+/// blocks end up in one document. `fences` are all blocks of the document, scanned
+/// once and shared by every group.
+pub(crate) fn group_fences(
+    fences: &[Fence],
+    aliases: &LanguageAliases,
+    target_lang: &str,
+    session: Option<&str>,
+) -> Vec<Fence> {
+    fences
+        .iter()
+        .filter(|fence| {
+            debug!("[VirtualDoc] Found code block with language: '{}'", fence.lang());
+            if aliases.resolve(fence.lang()) != target_lang || fence.session().as_deref() != session {
+                return false;
+            }
+            let attributes = fence.attributes();
+            if attributes.excluded() {
+                debug!("[VirtualDoc] Skipping block at line {} ({:?})", fence.start_line, attributes);
+            }
+            !attributes.excluded()
+        })
+        .cloned()
+        .collect()
+}
+
+/// A fence with only its code lines, the way the language sees them
+///
+/// Rustdoc hidden lines are code too; their marker is stripped like a line prefix.
+pub(crate) fn code_lines(fence: &Fence, lang: &str) -> Fence {
+    let mut code = fence.clone();
+    if lang == "rust" {
+        for (offset, (line, prefix)) in code.lines.iter_mut().zip(code.prefixes.iter_mut()).enumerate() {
+            let (stripped, marker) = strip_hidden_marker(line);
+            if marker > 0 {
+                *line = stripped.to_string();
+                *prefix += marker;
+                if let Some(source) = code.sources.get_mut(offset) {
+                    source.strip_code_prefix(marker);
                 }
+            }
+        }
+    }
+    code
+}
+
+/// Writes blocks into a virtual document, expanding chunk references in place
+struct Expander<'a> {
+    lang: &'a str,
+    /// Code of each block, from `code_lines`
+    fences: &'a [Fence],
+    graph: &'a ChunkGraph,
+    content: String,
+    line: usize,
+    blocks: Vec<CodeBlock>,
+    /// Chunks being expanded, to stop at cycles
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Expander<'a> {
+    /// Write the lines of a block from `first` on, with references replaced by their chunks
+    fn emit(&mut self, block: usize, first: usize, indent: &str) {
+        let fences = self.fences;
+        let lines = &fences[block].lines;
+
+        // Trailing blank lines carry no code and are not sent to the child
+        let line_count = lines
+            .iter()
+            .rposition(|line| !line.trim().is_empty())
+            .map_or(0, |last| last + 1)
+            .max(first);
+
+        let mut run_start = first;
+        for (offset, line) in lines.iter().enumerate().take(line_count).skip(first) {
+            let (ref_indent, name) = match reference(line) {
+                Some(reference) => reference,
+                None => continue,
+            };
+            self.push_segment(block, run_start, offset, indent, false);
+            run_start = offset + 1;
 
-                blocks.push(CodeBlock {
-                    lang: current_block_lang.clone(),
-                    markdown_start: block_start,
-                    markdown_end: idx,
-                    content_start: block_start + 1,  // First line after opening ```
-                    content_end: idx - 1,             // Last line before closing ```
-                    virtual_start,
-                    virtual_end: virtual_line,
-                    content: block_content.clone(),
-                });
+            // Cycles are reported as diagnostics on the markdown; the expansion stops there
+            if self.stack.contains(&name) {
+                continue;
+            }
+            let nested = format!("{}{}", indent, &line[..ref_indent]);
+            self.stack.push(name);
+            for &definition in self.graph.definitions(name) {
+                self.emit(definition, 1, &nested);
             }
-            in_code_block = false;
-        } else if in_code_block {
-            block_content.push_str(line);
-            block_content.push('\n');
+            self.stack.pop();
+        }
+        self.push_segment(block, run_start, line_count, indent, true);
+    }
+
+    /// Write lines `start..end` of a block as one contiguous segment
+    ///
+    /// The last segment of a block also covers its trailing blank lines, so that it
+    /// spans the block up to the closing fence.
+    fn push_segment(&mut self, block: usize, start: usize, end: usize, indent: &str, last: bool) {
+        if start == end && !last {
+            return;
+        }
+        let fence = &self.fences[block];
+        let (lines, prefixes) = (&fence.lines, &fence.prefixes);
+
+        let virtual_start = self.line;
+        for line in &lines[start..end] {
+            self.content.push_str(indent);
+            self.content.push_str(line);
+            self.content.push('\n');
+            self.line += 1;
         }
+
+        let covered = if last { lines.len() } else { end };
+        let mut content = lines[start..covered].join("\n");
+        if covered > start {
+            content.push('\n');
+        }
+
+        self.blocks.push(CodeBlock {
+            lang: self.lang.to_string(),
+            markdown_start: fence.start_line,
+            markdown_end: fence.end_line,
+            content_start: fence.content_line(start),  // First line of the segment
+            content_end: if last {
+                fence.content_end()  // Last line before closing fence
+            } else {
+                fence.content_line(end - 1)
+            },
+            virtual_start,
+            virtual_end: self.line,
+            content,
+            indent: fence.indent,
+            prefixes: prefixes[start.min(prefixes.len())..covered.min(prefixes.len())].to_vec(),
+            expansion_indent: indent.len(),
+            sources: fence.sources[start.min(fence.sources.len())..covered.min(fence.sources.len())].to_vec(),
+            attributes: fence.attributes(),
+            options: fence.options.clone(),
+        });
+    }
+}
+
+/// Build the virtual document of a language's default session in a markdown document
+pub fn build_virtual_document(markdown: &str, target_lang: &str) -> VirtualDocument {
+    let fences = DocumentFormat::Markdown.scan(markdown);
+    build_session_document(&fences, &LanguageAliases::default(), target_lang, None)
+}
+
+/// Build the virtual document for one (language, session) group of blocks
+///
+/// Each session is an independent document, so the same name may be defined in
+/// several sessions without clashing. Noweb chunk references (`<<name>>`) are
+/// replaced by the chunk's code, so a block may end up split into several
+/// `CodeBlock` segments, and a chunk used twice appears twice.
+pub fn build_session_document(
+    fences: &[Fence],
+    aliases: &LanguageAliases,
+    target_lang: &str,
+    session: Option<&str>,
+) -> VirtualDocument {
+    debug!("[VirtualDoc] Building virtual document for language: '{}', session: {:?}", target_lang, session);
+    let fences: Vec<Fence> = group_fences(fences, aliases, target_lang, session)
+        .iter()
+        .map(|fence| code_lines(fence, target_lang))
+        .collect();
+    let graph = ChunkGraph::new(fences.iter().map(|fence| fence.lines.as_slice()));
+
+    let mut expander = Expander {
+        lang: target_lang,
+        fences: &fences,
+        graph: &graph,
+        content: String::new(),
+        line: 0,
+        blocks: Vec::new(),
+        stack: Vec::new(),
+    };
+    for block in (0..fences.len()).filter(|&block| graph.is_root(block)) {
+        // Add blank line separator before this block (except for first block)
+        if !expander.blocks.is_empty() {
+            expander.content.push('\n');
+            expander.line += 1;
+        }
+        // A chunk emitted on its own starts after its `<<name>>=` line
+        let first = usize::from(graph.defines(block).is_some());
+        expander.emit(block, first, "");
     }
+    let Expander { content: virtual_content, blocks, .. } = expander;
 
     debug!("[VirtualDoc] Collected {} blocks, content length: {}", blocks.len(), virtual_content.len());
     if virtual_content.is_empty() {
@@ -100,44 +295,39 @@ pub fn find_code_block_at_line(
     markdown: &str,
     line: usize,
 ) -> Option<(String, usize, usize)> {
-    let lines: Vec<&str> = markdown.lines().collect();
-    let mut in_code_block = false;
-    let mut block_lang = String::new();
-    let mut block_start = 0;
-    let mut fence_backtick_count = 0;
-
-    for (idx, current_line) in lines.iter().enumerate() {
-        // Count leading backticks
-        let backtick_count = current_line.chars().take_while(|&c| c == '`').count();
-
-        if backtick_count < 3 {
-            continue; // Not a fence
-        }
-
-        if !in_code_block {
-            // Starting a code block
-            let lang_start = backtick_count;
-            let lang = if lang_start < current_line.len() {
-                let lang_part = &current_line[lang_start..];
-                lang_part.split_whitespace().next().unwrap_or("").to_string()
-            } else {
-                String::new()
-            };
+    find_fence_at_line(markdown, DocumentFormat::Markdown, line)
+        .map(|fence| (fence.lang().to_string(), fence.start_line, fence.end_line))
+}
 
-            in_code_block = true;
-            block_lang = lang;
-            block_start = idx;
-            fence_backtick_count = backtick_count;
-        } else if backtick_count >= fence_backtick_count {
-            // Closing a code block (must have at least as many backticks)
-            if line >= block_start && line <= idx {
-                return Some((block_lang.clone(), block_start, idx));
-            }
-            in_code_block = false;
+/// Collect the distinct languages of all code blocks, in order of first appearance
+///
+/// Languages are reported by the name their blocks' names resolve to.
+pub fn collect_languages(fences: &[Fence], aliases: &LanguageAliases) -> Vec<String> {
+    let mut langs: Vec<String> = Vec::new();
+    for fence in fences {
+        let lang = aliases.resolve(fence.lang());
+        if !lang.is_empty() && !langs.iter().any(|l| l == lang) {
+            langs.push(lang.to_string());
         }
     }
+    langs
+}
 
-    None
+/// Collect the sessions of a language's blocks, in order of first appearance
+///
+/// `None` stands for the default session of blocks without one.
+pub fn collect_sessions(fences: &[Fence], aliases: &LanguageAliases, target_lang: &str) -> Vec<Option<String>> {
+    let mut sessions: Vec<Option<String>> = Vec::new();
+    for fence in fences {
+        if aliases.resolve(fence.lang()) != target_lang || fence.attributes().excluded() {
+            continue;
+        }
+        let session = fence.session();
+        if !sessions.contains(&session) {
+            sessions.push(session);
+        }
+    }
+    sessions
 }
 
 #[cfg(test)]
@@ -160,4 +350,164 @@ mod tests {
         let (lang, _start, _end) = result.unwrap();
         assert_eq!(lang, "forth");
     }
+
+    #[test]
+    fn test_block_attributes() {
+        let markdown = "```rust {ignore}\nfn broken( {}\n```\n\n```rust {hidden file=src/lib.rs}\nfn a() {}\n```\n\n```{.rust .no-lsp}\nfn b() {}\n```\n";
+        assert!(build_virtual_document(markdown, "rust").blocks.is_empty());
+        let vdoc = build_session_document(&DocumentFormat::Markdown.scan(markdown), &LanguageAliases::default(), "rust", Some("src/lib.rs"));
+
+        // Only the hidden block is kept; ignored and no-lsp blocks are left out
+        assert_eq!(vdoc.blocks.len(), 1);
+        assert_eq!(vdoc.content, "fn a() {}\n");
+        assert!(vdoc.blocks[0].attributes.hidden);
+        assert_eq!(vdoc.blocks[0].attributes.file.as_deref(), Some("src/lib.rs"));
+        assert_eq!(vdoc.blocks[0].content_start, 5);
+
+        // The pandoc-style block still reports its language
+        assert_eq!(find_code_block_at_line(markdown, 9).unwrap().0, "rust");
+    }
+
+    #[test]
+    fn test_sessions_are_separate_documents() {
+        let markdown = "```rust\nfn main() { wrong() }\n```\n\n```rust {session=right}\nfn main() { right() }\n```\n\n```rust\nfn wrong() {}\n```\n";
+        assert_eq!(collect_sessions(&DocumentFormat::Markdown.scan(markdown), &LanguageAliases::default(), "rust"), vec![None, Some("right".to_string())]);
+
+        let default = build_virtual_document(markdown, "rust");
+        assert_eq!(default.content, "fn main() { wrong() }\n\nfn wrong() {}\n");
+        assert_eq!(default.blocks.len(), 2);
+
+        let right = build_session_document(&DocumentFormat::Markdown.scan(markdown), &LanguageAliases::default(), "rust", Some("right"));
+        assert_eq!(right.content, "fn main() { right() }\n");
+        assert_eq!(right.blocks[0].content_start, 5);
+        assert_eq!(right.blocks[0].virtual_start, 0);
+    }
+
+    #[test]
+    fn test_wrapper_lines_do_not_map_back() {
+        use crate::position::PositionMapper;
+
+        let markdown = "# Go\n\n```go\nfmt.Println(1)\n```\n";
+        let wrapper = Wrapper {
+            prelude: "package main\nimport \"fmt\"\nfunc main() {".to_string(),
+            epilogue: "}".to_string(),
+        };
+        let vdoc = build_virtual_document(markdown, "go").wrap(&wrapper);
+        assert_eq!(vdoc.content, "package main\nimport \"fmt\"\nfunc main() {\nfmt.Println(1)\n}\n");
+        assert_eq!((vdoc.blocks[0].virtual_start, vdoc.blocks[0].virtual_end), (3, 4));
+
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        assert_eq!(mapper.markdown_to_virtual(3, 4), Some((3, 4)));
+        assert_eq!(mapper.virtual_to_markdown(3, 4), Some((3, 4)));
+        assert_eq!(mapper.virtual_to_markdown(1, 0), None);
+        assert_eq!(mapper.virtual_to_markdown(4, 0), None);
+
+        // Languages without blocks stay empty
+        assert!(build_virtual_document(markdown, "rust").wrap(&wrapper).content.is_empty());
+    }
+
+    #[test]
+    fn test_rustdoc_hidden_lines() {
+        use crate::position::PositionMapper;
+
+        let markdown = "```rust\n# use std::fmt;\n#\n  # fn main() {\nlet x = 1;\n## not hidden\n#[derive(Debug)]\n# }\n```\n";
+        let vdoc = build_virtual_document(markdown, "rust");
+        assert_eq!(vdoc.content, "use std::fmt;\n\nfn main() {\nlet x = 1;\n# not hidden\n#[derive(Debug)]\n}\n");
+
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        // `fmt` in `# use std::fmt;` and `main` in `  # fn main() {`
+        assert_eq!(mapper.markdown_to_virtual(1, 11), Some((0, 9)));
+        assert_eq!(mapper.virtual_to_markdown(0, 9), Some((1, 11)));
+        assert_eq!(mapper.virtual_to_markdown(2, 3), Some((3, 7)));
+        assert_eq!(mapper.virtual_to_markdown(4, 0), Some((5, 1)));
+
+        // Other languages keep `#` lines as they are
+        let python = build_virtual_document("```python\n# comment\n```\n", "python");
+        assert_eq!(python.content, "# comment\n");
+    }
+
+    #[test]
+    fn test_typst_raw_call_mapping() {
+        use crate::position::PositionMapper;
+
+        let typst = "Voilà #raw(lang: \"python\", \"s = \\\"é\\\"\\nprint(s)\")\n";
+        let vdoc = build_session_document(&DocumentFormat::Typst.scan(typst), &LanguageAliases::default(), "python", None);
+        assert_eq!(vdoc.content, "s = \"é\"\nprint(s)\n");
+
+        // Both code lines sit on line 0, after non-ASCII text and escapes
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        assert_eq!(mapper.markdown_to_virtual(0, 39), Some((1, 0)));
+        assert_eq!(mapper.virtual_to_markdown(1, 5), Some((0, 44)));
+        assert_eq!(mapper.virtual_to_markdown(0, 6), Some((0, 35)));
+        assert_eq!(mapper.markdown_to_virtual(0, 35), Some((0, 6)));
+        // Inside the `\"` escape
+        assert_eq!(mapper.markdown_to_virtual(0, 33), Some((0, 4)));
+    }
+
+    #[test]
+    fn test_quarto_cell_options() {
+        use crate::position::PositionMapper;
+
+        let qmd = "```{python}\n#| label: fig-plot\n#| echo: false\nimport os\nprint(os.sep)\n```\n\n```{r echo=FALSE}\n#| eval: false\nx <- 1\n```\n";
+        let vdoc = build_virtual_document(qmd, "python");
+        assert_eq!(vdoc.content, "import os\nprint(os.sep)\n");
+        let block = &vdoc.blocks[0];
+        assert_eq!(block.options, vec![("label".to_string(), "fig-plot".to_string()), ("echo".to_string(), "false".to_string())]);
+        assert_eq!((block.content_start, block.content_end), (3, 4));
+
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        assert_eq!(mapper.markdown_to_virtual(4, 6), Some((1, 6)));
+        assert_eq!(mapper.virtual_to_markdown(0, 0), Some((3, 0)));
+
+        // `eval: false` chunks are never run, but still edited: they stay in, with their options
+        let vdoc = build_virtual_document(qmd, "r");
+        assert_eq!(vdoc.content, "x <- 1\n");
+        assert_eq!(vdoc.blocks[0].options, vec![("echo".to_string(), "FALSE".to_string()), ("eval".to_string(), "false".to_string())]);
+    }
+
+    #[test]
+    fn test_noweb_chunks_are_expanded() {
+        use crate::position::PositionMapper;
+
+        let markdown = "```rust\nfn main() {\n    <<parse args>>\n    run(args);\n}\n```\n\nParsing:\n\n```rust\n<<parse args>>=\nlet args = parse();\n```\n";
+        let vdoc = build_virtual_document(markdown, "rust");
+        assert_eq!(vdoc.content, "fn main() {\n    let args = parse();\n    run(args);\n}\n");
+
+        // main before the reference, the chunk, main after the reference
+        let spans: Vec<_> = vdoc.blocks.iter().map(|b| (b.content_start, b.virtual_start, b.virtual_end)).collect();
+        assert_eq!(spans, vec![(1, 0, 1), (11, 1, 2), (3, 2, 4)]);
+
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        // `parse` in the chunk maps through the reference's indentation
+        assert_eq!(mapper.markdown_to_virtual(11, 11), Some((1, 15)));
+        assert_eq!(mapper.virtual_to_markdown(1, 15), Some((11, 11)));
+        assert_eq!(mapper.virtual_to_markdown(2, 4), Some((3, 4)));
+        // The reference and definition lines are not code
+        assert_eq!(mapper.markdown_to_virtual(2, 4), None);
+        assert_eq!(mapper.markdown_to_virtual(10, 0), None);
+    }
+
+    #[test]
+    fn test_noweb_cycles_and_unreferenced_chunks() {
+        let markdown = "```python\n<<a>>=\nx = 1\n<<a>>\n```\n\n```python\n<<*>>=\n<<a>>\n```\n";
+        let vdoc = build_virtual_document(markdown, "python");
+        // The root chunk is emitted on its own; the cyclic reference expands once
+        assert_eq!(vdoc.content, "x = 1\n");
+    }
+
+    #[test]
+    fn test_aliases_share_a_document() {
+        use crate::config::Config;
+
+        let markdown = "```rs\nfn a() {}\n```\n\n```rust\nfn b() { a() }\n```\n\n```py\nx = 1\n```\n";
+        let aliases = Config::default().language_aliases();
+        assert_eq!(collect_languages(&DocumentFormat::Markdown.scan(markdown), &aliases), vec!["rust", "python"]);
+
+        let vdoc = build_session_document(&DocumentFormat::Markdown.scan(markdown), &aliases, "rust", None);
+        assert_eq!(vdoc.content, "fn a() {}\n\nfn b() { a() }\n");
+        assert!(vdoc.blocks.iter().all(|block| block.lang == "rust"));
+
+        // Without the table, each name is a language of its own
+        assert_eq!(build_virtual_document(markdown, "rust").blocks.len(), 1);
+    }
 }
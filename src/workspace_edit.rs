@@ -0,0 +1,258 @@
+use crate::position::PositionMapper;
+use tower_lsp::lsp_types::{
+    DocumentChangeOperation, DocumentChanges, OneOf, OptionalVersionedTextDocumentIdentifier,
+    Range, ResourceOp, TextDocumentEdit, TextEdit, Url, WorkspaceEdit,
+};
+
+/// Map a virtual document edit range to markdown, rejecting ranges outside a single block
+///
+/// Inside a block virtual and markdown lines advance together; a range spanning two
+/// blocks covers their fences and the prose between them in markdown, so its line
+/// span no longer matches.
+fn map_edit_range(mapper: &PositionMapper, range: Range) -> Result<Range, String> {
+    let outside = || {
+        format!(
+            "Edit at line {} of the virtual document falls outside the code blocks",
+            range.start.line + 1
+        )
+    };
+
+    let mapped = mapper.virtual_range_to_markdown(range).ok_or_else(outside)?;
+    if mapped.end.line.checked_sub(mapped.start.line) != range.end.line.checked_sub(range.start.line) {
+        return Err(outside());
+    }
+    Ok(mapped)
+}
+
+/// Drop repeated edits: a chunk expanded twice gets the same edit at both expansions
+fn dedup<T: PartialEq>(items: Vec<T>) -> Vec<T> {
+    let mut unique = Vec::with_capacity(items.len());
+    for item in items {
+        if !unique.contains(&item) {
+            unique.push(item);
+        }
+    }
+    unique
+}
+
+/// Map text edits on the virtual document to markdown coordinates
+fn map_text_edits(mapper: &PositionMapper, edits: Vec<TextEdit>) -> Result<Vec<TextEdit>, String> {
+    edits
+        .into_iter()
+        .map(|edit| {
+            Ok(TextEdit {
+                range: map_edit_range(mapper, edit.range)?,
+                new_text: edit.new_text,
+            })
+        })
+        .collect::<Result<_, String>>()
+        .map(dedup)
+}
+
+/// Map one `documentChanges` text edit, retargeting it at the markdown document
+fn map_document_edit(
+    mut edit: TextDocumentEdit,
+    mapper: &PositionMapper,
+    virtual_uri: &Url,
+    markdown_uri: &Url,
+    markdown_version: i32,
+) -> Result<TextDocumentEdit, String> {
+    if &edit.text_document.uri != virtual_uri {
+        return Ok(edit);
+    }
+
+    edit.text_document = OptionalVersionedTextDocumentIdentifier {
+        uri: markdown_uri.clone(),
+        version: Some(markdown_version),
+    };
+    let edits = edit
+        .edits
+        .into_iter()
+        .map(|edit| match edit {
+            OneOf::Left(edit) => Ok(OneOf::Left(TextEdit {
+                range: map_edit_range(mapper, edit.range)?,
+                new_text: edit.new_text,
+            })),
+            OneOf::Right(mut annotated) => {
+                annotated.text_edit.range = map_edit_range(mapper, annotated.text_edit.range)?;
+                Ok(OneOf::Right(annotated))
+            }
+        })
+        .collect::<Result<_, String>>()?;
+    edit.edits = dedup(edits);
+    Ok(edit)
+}
+
+/// Reject file operations on the virtual document, which only exists as a mirror of markdown
+fn check_resource_op(op: &ResourceOp, virtual_uri: &Url) -> Result<(), String> {
+    let touches_virtual = match op {
+        ResourceOp::Create(create) => &create.uri == virtual_uri,
+        ResourceOp::Rename(rename) => &rename.old_uri == virtual_uri || &rename.new_uri == virtual_uri,
+        ResourceOp::Delete(delete) => &delete.uri == virtual_uri,
+    };
+    if touches_virtual {
+        return Err("Cannot create, rename or delete the generated virtual document".to_string());
+    }
+    Ok(())
+}
+
+/// Map a `WorkspaceEdit` returned by a child LSP back to the markdown document
+///
+/// Edits on the virtual document move to the markdown URI at the matching lines and
+/// columns; edits on other files are kept as-is. Fails if any edit on the virtual
+/// document lands outside a code block, since applying it would corrupt the prose.
+pub fn map_workspace_edit(
+    edit: WorkspaceEdit,
+    mapper: &PositionMapper,
+    virtual_uri: &Url,
+    markdown_uri: &Url,
+    markdown_version: i32,
+) -> Result<WorkspaceEdit, String> {
+    let changes = match edit.changes {
+        Some(changes) => {
+            let mut mapped = std::collections::HashMap::new();
+            for (uri, edits) in changes {
+                if &uri == virtual_uri {
+                    let edits = map_text_edits(mapper, edits)?;
+                    mapped.entry(markdown_uri.clone()).or_insert_with(Vec::new).extend(edits);
+                } else {
+                    mapped.entry(uri).or_insert_with(Vec::new).extend(edits);
+                }
+            }
+            Some(mapped)
+        }
+        None => None,
+    };
+
+    let document_changes = match edit.document_changes {
+        Some(DocumentChanges::Edits(edits)) => Some(DocumentChanges::Edits(
+            edits
+                .into_iter()
+                .map(|edit| map_document_edit(edit, mapper, virtual_uri, markdown_uri, markdown_version))
+                .collect::<Result<_, String>>()?,
+        )),
+        Some(DocumentChanges::Operations(operations)) => Some(DocumentChanges::Operations(
+            operations
+                .into_iter()
+                .map(|operation| match operation {
+                    DocumentChangeOperation::Edit(edit) => Ok(DocumentChangeOperation::Edit(
+                        map_document_edit(edit, mapper, virtual_uri, markdown_uri, markdown_version)?,
+                    )),
+                    DocumentChangeOperation::Op(op) => {
+                        check_resource_op(&op, virtual_uri)?;
+                        Ok(DocumentChangeOperation::Op(op))
+                    }
+                })
+                .collect::<Result<_, String>>()?,
+        )),
+        None => None,
+    };
+
+    Ok(WorkspaceEdit {
+        changes,
+        document_changes,
+        change_annotations: edit.change_annotations,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_doc::build_virtual_document;
+    use std::collections::HashMap;
+    use tower_lsp::lsp_types::{AnnotatedTextEdit, Position};
+
+    const BOOK: &str = "# Words\n\n```forth\n: square dup * ;\n```\n\nUse it:\n\n```forth\n5 square .\n```\n";
+
+    fn edit(line: u32, start: u32, end: u32) -> TextEdit {
+        TextEdit {
+            range: Range {
+                start: Position { line, character: start },
+                end: Position { line, character: end },
+            },
+            new_text: "sq".to_string(),
+        }
+    }
+
+    fn uris() -> (Url, Url, Url) {
+        (
+            Url::parse("file:///project/target/literate/book.forth").unwrap(),
+            Url::parse("file:///project/book.md").unwrap(),
+            Url::parse("file:///project/lib.forth").unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_map_changes() {
+        let vdoc = build_virtual_document(BOOK, "forth");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let (virtual_uri, markdown_uri, library_uri) = uris();
+
+        let mut changes = HashMap::new();
+        changes.insert(virtual_uri.clone(), vec![edit(0, 2, 8), edit(2, 2, 8)]);
+        changes.insert(library_uri.clone(), vec![edit(4, 0, 6)]);
+        let workspace_edit = WorkspaceEdit { changes: Some(changes), ..Default::default() };
+
+        let mapped = map_workspace_edit(workspace_edit, &mapper, &virtual_uri, &markdown_uri, 3).unwrap();
+        let changes = mapped.changes.unwrap();
+        assert_eq!(changes[&markdown_uri], vec![edit(3, 2, 8), edit(9, 2, 8)]);
+        assert_eq!(changes[&library_uri], vec![edit(4, 0, 6)]);
+        assert!(!changes.contains_key(&virtual_uri));
+    }
+
+    #[test]
+    fn test_map_document_changes() {
+        let vdoc = build_virtual_document(BOOK, "forth");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let (virtual_uri, markdown_uri, _) = uris();
+
+        let annotated = AnnotatedTextEdit { text_edit: edit(2, 2, 8), annotation_id: "rename".to_string() };
+        let workspace_edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(vec![TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier { uri: virtual_uri.clone(), version: Some(7) },
+                edits: vec![OneOf::Left(edit(0, 2, 8)), OneOf::Right(annotated)],
+            }])),
+            ..Default::default()
+        };
+
+        let mapped = map_workspace_edit(workspace_edit, &mapper, &virtual_uri, &markdown_uri, 3).unwrap();
+        let edits = match mapped.document_changes.unwrap() {
+            DocumentChanges::Edits(edits) => edits,
+            DocumentChanges::Operations(_) => panic!("expected edits"),
+        };
+        assert_eq!(edits[0].text_document.uri, markdown_uri);
+        assert_eq!(edits[0].text_document.version, Some(3));
+        assert_eq!(edits[0].edits[0], OneOf::Left(edit(3, 2, 8)));
+        match &edits[0].edits[1] {
+            OneOf::Right(annotated) => assert_eq!(annotated.text_edit, edit(9, 2, 8)),
+            OneOf::Left(_) => panic!("annotation lost"),
+        }
+    }
+
+    #[test]
+    fn test_reject_edits_outside_blocks() {
+        let vdoc = build_virtual_document(BOOK, "forth");
+        let mapper = PositionMapper::new(&vdoc.blocks);
+        let (virtual_uri, markdown_uri, _) = uris();
+
+        // The separator line between the blocks
+        let mut changes = HashMap::new();
+        changes.insert(virtual_uri.clone(), vec![edit(1, 0, 0)]);
+        let workspace_edit = WorkspaceEdit { changes: Some(changes), ..Default::default() };
+        let err = map_workspace_edit(workspace_edit, &mapper, &virtual_uri, &markdown_uri, 1).unwrap_err();
+        assert!(err.contains("outside the code blocks"));
+
+        // A range spanning both blocks would swallow the prose between them
+        let spanning = TextEdit {
+            range: Range {
+                start: Position { line: 0, character: 2 },
+                end: Position { line: 2, character: 1 },
+            },
+            new_text: String::new(),
+        };
+        let mut changes = HashMap::new();
+        changes.insert(virtual_uri.clone(), vec![spanning]);
+        let workspace_edit = WorkspaceEdit { changes: Some(changes), ..Default::default() };
+        assert!(map_workspace_edit(workspace_edit, &mapper, &virtual_uri, &markdown_uri, 1).is_err());
+    }
+}
@@ -45,10 +45,8 @@ fn test_goto_definition() {
     // Spawn a thread to read and print server stderr
     let _stderr_handle = std::thread::spawn(move || {
         let reader = BufReader::new(stderr);
-        for line in reader.lines() {
-            if let Ok(l) = line {
-                eprintln!("[SERVER] {}", l);
-            }
+        for l in reader.lines().map_while(Result::ok) {
+            eprintln!("[SERVER] {}", l);
         }
     });
 
@@ -184,7 +182,7 @@ fn test_goto_definition() {
 
     // Verify that virtual documents were written to disk after the request
     // Check where the file was written based on .literate.toml config
-    let possible_dirs = vec!["./src", "./code"];
+    let possible_dirs = ["./src", "./code"];
     let forth_file = possible_dirs
         .iter()
         .map(|dir| std::path::PathBuf::from(dir).join("example.forth"))
@@ -248,4 +246,5 @@ fn test_goto_definition() {
     // Clean up
     drop(writer);
     let _ = server.kill();
+    let _ = server.wait();
 }
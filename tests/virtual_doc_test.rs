@@ -120,6 +120,7 @@ fn test_position_mapping() {
             virtual_start: 0,
             virtual_end: 1,
             content: ": square ( n -- n ) dup * ;    \\ ok\n".to_string(),
+            ..Default::default()
         },
         literate_lsp::virtual_doc::CodeBlock {
             lang: "forth".to_string(),
@@ -130,6 +131,7 @@ fn test_position_mapping() {
             virtual_start: 2,
             virtual_end: 3,
             content: "5 square .                     \\ 25 ok\n".to_string(),
+            ..Default::default()
         },
     ];
 
@@ -184,3 +186,47 @@ fn test_example_md_parsing() {
         "Virtual doc should contain 'dup'"
     );
 }
+
+#[test]
+fn test_nested_fences_extraction_and_mapping() {
+    let markdown = r#"# Steps
+
+1. Define the helper:
+
+   ```python
+   def square(n):
+       return n * n
+   ```
+
+> ~~~python
+> print(square(5))
+> ~~~
+
+````markdown
+```python
+not_python_code()
+```
+````
+"#;
+
+    let vdoc = build_virtual_document(markdown, "python");
+    assert_eq!(vdoc.blocks.len(), 2, "Blocks inside the markdown example are not extracted");
+    assert_eq!(
+        vdoc.content,
+        "def square(n):\n    return n * n\n\nprint(square(5))\n",
+        "Indentation and quote markers should be stripped"
+    );
+
+    let mapper = PositionMapper::new(&vdoc.blocks);
+
+    // `square` on line 5 starts at column 7 in markdown, column 4 in the virtual doc
+    assert_eq!(mapper.markdown_to_virtual(5, 7), Some((0, 4)));
+    assert_eq!(mapper.virtual_to_markdown(0, 4), Some((5, 7)));
+
+    // `print` on line 10 follows the "> " prefix
+    assert_eq!(mapper.virtual_to_markdown(3, 0), Some((10, 2)));
+
+    // The whole four-backtick block is markdown, regardless of the inner fence
+    let (lang, start, end) = find_code_block_at_line(markdown, 15).unwrap();
+    assert_eq!((lang.as_str(), start, end), ("markdown", 13, 17));
+}